@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+#[cfg(feature = "browserid")]
+use fxa_client::AuthorizationStatus;
 use fxa_client::Profile;
 use fxa_client::{OAuthInfo, SyncKeys};
 use fxa_str_free;
@@ -50,9 +52,9 @@ impl From<OAuthInfo> for OAuthInfoC {
     fn from(info: OAuthInfo) -> Self {
         let scopes = info.scopes.join(" ");
         OAuthInfoC {
-            access_token: string_to_c_char(info.access_token),
+            access_token: string_to_c_char(&*info.access_token),
             keys: match info.keys {
-                Some(keys) => string_to_c_char(keys),
+                Some(keys) => string_to_c_char(&*keys),
                 None => std::ptr::null_mut(),
             },
             scope: string_to_c_char(scopes),
@@ -89,3 +91,20 @@ impl From<Profile> for ProfileC {
         }
     }
 }
+
+#[cfg(feature = "browserid")]
+#[repr(C)]
+pub struct AuthorizationStatusC {
+    pub active: bool,
+    pub verified: bool,
+}
+
+#[cfg(feature = "browserid")]
+impl From<AuthorizationStatus> for AuthorizationStatusC {
+    fn from(status: AuthorizationStatus) -> Self {
+        AuthorizationStatusC {
+            active: status.active,
+            verified: status.verified,
+        }
+    }
+}
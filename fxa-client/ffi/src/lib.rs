@@ -14,7 +14,11 @@ use std::ptr;
 use ctypes::*;
 use fxa_client::errors::Error as InternalError;
 use fxa_client::errors::ErrorKind as InternalErrorKind;
-use fxa_client::{Config, FirefoxAccount, PersistCallback, WebChannelResponse};
+#[cfg(feature = "browserid")]
+use fxa_client::{AttachedClient, Email};
+use fxa_client::{
+    Config, EventCallback, FirefoxAccount, MetricsCallback, PersistCallback, WebChannelResponse,
+};
 use libc::c_char;
 use util::*;
 
@@ -54,6 +58,8 @@ impl From<InternalError> for ExternError {
         match err.kind() {
             InternalErrorKind::RemoteError { code: 401, .. }
             | InternalErrorKind::NotMarried
+            | InternalErrorKind::NoSessionToken
+            | InternalErrorKind::UnknownAccount
             | InternalErrorKind::NoCachedToken(_) => ExternError {
                 code: ErrorCode::AuthenticationError,
                 message: string_to_c_char(err.to_string()),
@@ -330,6 +336,105 @@ pub unsafe extern "C" fn fxa_unregister_persist_callback(
     });
 }
 
+/// Registers a callback that gets called with a JSON description of every
+/// account event (e.g. `{"type":"AuthorizationLost"}`).
+#[no_mangle]
+pub unsafe extern "C" fn fxa_register_event_callback(
+    fxa: *mut FirefoxAccount,
+    callback: extern "C" fn(json: *const c_char),
+    error: *mut ExternError,
+) {
+    AssertUnwindSafe(callback);
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.register_event_callback(EventCallback::new(move |event| {
+            let json = match event.to_json() {
+                Ok(json) => json,
+                Err(_) => return,
+            };
+            let s = string_to_c_char(json);
+            callback(s);
+            drop(CString::from_raw(s));
+        }));
+        Ok(()) // call_with_result needs a result
+    });
+}
+
+/// Unregisters a previous registered event callback
+#[no_mangle]
+pub unsafe extern "C" fn fxa_unregister_event_callback(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.unregister_event_callback();
+        Ok(()) // call_with_result needs a result
+    });
+}
+
+/// Registers a callback that gets called with a JSON description of every
+/// metrics event (e.g. `{"event":"OAuthFlowCompleted","flow_id":"..."}`).
+#[no_mangle]
+pub unsafe extern "C" fn fxa_register_metrics_callback(
+    fxa: *mut FirefoxAccount,
+    callback: extern "C" fn(json: *const c_char),
+    error: *mut ExternError,
+) {
+    AssertUnwindSafe(callback);
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.register_metrics_callback(MetricsCallback::new(move |event| {
+            let json = match event.to_json() {
+                Ok(json) => json,
+                Err(_) => return,
+            };
+            let s = string_to_c_char(json);
+            callback(s);
+            drop(CString::from_raw(s));
+        }));
+        Ok(()) // call_with_result needs a result
+    });
+}
+
+/// Unregisters a previous registered metrics callback
+#[no_mangle]
+pub unsafe extern "C" fn fxa_unregister_metrics_callback(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        fxa.unregister_metrics_callback();
+        Ok(()) // call_with_result needs a result
+    });
+}
+
+/// Checks with the server whether the session is still valid. If it isn't, the
+/// account needs to be re-authenticated and the event callback gets notified.
+///
+/// # Safety
+///
+/// A destructor [fxa_authorization_status_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_check_authorization_status(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut AuthorizationStatusC {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let status: AuthorizationStatusC = fxa.check_authorization_status()?.into();
+        Ok(status)
+    })
+}
+
 /// Fetches the profile associated with a Firefox Account.
 ///
 /// The profile might get cached in-memory and the caller might get served a cached version.
@@ -413,6 +518,329 @@ pub unsafe extern "C" fn fxa_get_sync_keys(
     })
 }
 
+/// Creates a new account recovery key. Requires to be in a `Married` state,
+/// and the current `password` to fetch the account keys.
+///
+/// The returned hex-encoded recovery key must be shown to the user, as it is not
+/// stored anywhere and is needed by [fxa_reset_password_with_recovery_key].
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_create_recovery_key(
+    fxa: *mut FirefoxAccount,
+    password: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let password = c_char_to_string(password);
+        fxa.create_recovery_key(password)
+    })
+}
+
+/// Emails a password reset verification code to `email`.
+///
+/// Returns a password forgot token to be passed along with that code to
+/// [fxa_verify_password_reset_code].
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_send_password_reset_code(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        fxa.send_password_reset_code(email)
+    })
+}
+
+/// Verifies a password reset code and returns an account reset token.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_verify_password_reset_code(
+    fxa: *mut FirefoxAccount,
+    password_forgot_token: *const c_char,
+    code: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let password_forgot_token = c_char_to_string(password_forgot_token);
+        let code = c_char_to_string(code);
+        fxa.verify_password_reset_code(password_forgot_token, code)
+    })
+}
+
+/// Resets the account password, restoring the account keys with `recovery_key`.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_reset_password_with_recovery_key(
+    fxa: *mut FirefoxAccount,
+    account_reset_token: *const c_char,
+    recovery_key: *const c_char,
+    new_password: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let account_reset_token = c_char_to_string(account_reset_token);
+        let recovery_key = c_char_to_string(recovery_key);
+        let new_password = c_char_to_string(new_password);
+        fxa.reset_password_with_recovery_key(account_reset_token, recovery_key, new_password)
+    });
+}
+
+/// Changes the account password, keeping the account keys.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_change_password(
+    fxa: *mut FirefoxAccount,
+    old_password: *const c_char,
+    new_password: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let old_password = c_char_to_string(old_password);
+        let new_password = c_char_to_string(new_password);
+        fxa.change_password(old_password, new_password)
+    });
+}
+
+/// Handles a message received from the content server over the fxaccounts WebChannel.
+///
+/// Returns the message to post back to the content server, or null if the command
+/// doesn't expect a reply.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_handle_web_channel_message(
+    fxa: *mut FirefoxAccount,
+    json: *const c_char,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_result_by_value(error, ptr::null_mut(), || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let json = c_char_to_string(json);
+        Ok(match fxa.handle_web_channel_message(json)? {
+            Some(reply) => string_to_c_char(reply),
+            None => ptr::null_mut(),
+        })
+    })
+}
+
+/// Lists the sessions, OAuth clients and devices attached to the account, as a JSON array.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_attached_clients(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let clients = fxa.get_attached_clients()?;
+        AttachedClient::list_to_json(&clients)
+    })
+}
+
+/// Destroys the session identified by `session_token_id`.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_destroy_session(
+    fxa: *mut FirefoxAccount,
+    session_token_id: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let session_token_id = c_char_to_string(session_token_id);
+        fxa.destroy_session(session_token_id)
+    });
+}
+
+/// Revokes the tokens of the OAuth client identified by `client_id`.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_revoke_oauth_client(
+    fxa: *mut FirefoxAccount,
+    client_id: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let client_id = c_char_to_string(client_id);
+        fxa.revoke_oauth_client(client_id)
+    });
+}
+
+/// Deletes the device identified by `device_id`.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_delete_device(
+    fxa: *mut FirefoxAccount,
+    device_id: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let device_id = c_char_to_string(device_id);
+        fxa.delete_device(device_id)
+    });
+}
+
+/// Lists the emails of the account, as a JSON array.
+///
+/// # Safety
+///
+/// A destructor [fxa_str_free] is provided for releasing the memory for this
+/// pointer type.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_get_emails(
+    fxa: *mut FirefoxAccount,
+    error: *mut ExternError,
+) -> *mut c_char {
+    call_with_string_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let emails = fxa.get_emails()?;
+        Email::list_to_json(&emails)
+    })
+}
+
+/// Adds a secondary email to the account and sends it a verification code.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_add_secondary_email(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        fxa.add_secondary_email(email)
+    });
+}
+
+/// Sends a new verification code to `email`.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_resend_email_verification(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        fxa.resend_email_verification(email)
+    });
+}
+
+/// Verifies a secondary email with the code that was sent to it.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_verify_secondary_email(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    code: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        let code = c_char_to_string(code);
+        fxa.verify_secondary_email(email, code)
+    });
+}
+
+/// Makes a verified secondary email the primary email.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_set_primary_email(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        fxa.set_primary_email(email)
+    });
+}
+
+/// Removes a secondary email from the account.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_delete_secondary_email(
+    fxa: *mut FirefoxAccount,
+    email: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let email = c_char_to_string(email);
+        fxa.delete_secondary_email(email)
+    });
+}
+
+/// Deletes the account after checking `password`, and forgets everything about it.
+/// The [FirefoxAccount] can then be freed or used to sign in to another account.
+#[cfg(feature = "browserid")]
+#[no_mangle]
+pub unsafe extern "C" fn fxa_destroy_account(
+    fxa: *mut FirefoxAccount,
+    password: *const c_char,
+    error: *mut ExternError,
+) {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let password = c_char_to_string(password);
+        fxa.destroy_account(password)
+    });
+}
+
 /// Request a OAuth token by starting a new OAuth flow.
 ///
 /// This function returns a URL string that the caller should open in a webview.
@@ -469,6 +897,28 @@ pub unsafe extern "C" fn fxa_complete_oauth_flow(
     })
 }
 
+/// Same as [fxa_complete_oauth_flow], but takes the whole URL the user got redirected
+/// to and checks it for errors before extracting `code` and `state` from it.
+///
+/// # Safety
+///
+/// A destructor [fxa_oauth_info_free] is provided for releasing the memory for this
+/// pointer type.
+#[no_mangle]
+pub unsafe extern "C" fn fxa_complete_oauth_flow_from_redirect(
+    fxa: *mut FirefoxAccount,
+    redirect_url: *const c_char,
+    error: *mut ExternError,
+) -> *mut OAuthInfoC {
+    call_with_result(error, || {
+        assert!(!fxa.is_null());
+        let fxa = &mut *fxa;
+        let redirect_url = c_char_to_string(redirect_url);
+        let info = fxa.complete_oauth_flow_from_redirect(redirect_url)?;
+        Ok(info.into())
+    })
+}
+
 /// Try to get a previously obtained cached token.
 ///
 /// If the token is expired, the system will try to refresh it automatically using
@@ -526,3 +976,5 @@ define_destructor!(fxa_config_free, Config);
 define_destructor!(fxa_oauth_info_free, OAuthInfoC);
 define_destructor!(fxa_profile_free, ProfileC);
 define_destructor!(fxa_sync_keys_free, SyncKeysC);
+#[cfg(feature = "browserid")]
+define_destructor!(fxa_authorization_status_free, AuthorizationStatusC);
@@ -74,6 +74,12 @@ pub enum ErrorKind {
     #[fail(display = "No cached token for scope {}", _0)]
     NoCachedToken(&'static str),
 
+    #[fail(display = "No session token is available for the current operation")]
+    NoSessionToken,
+
+    #[fail(display = "The account uid and email are not known")]
+    UnknownAccount,
+
     #[fail(display = "Unrecoverable server error")]
     UnrecoverableServerError,
 
@@ -83,6 +89,27 @@ pub enum ErrorKind {
     #[fail(display = "Empty names")]
     EmptyOAuthScopeNames,
 
+    #[fail(display = "Unsupported code challenge method {}", _0)]
+    UnsupportedCodeChallengeMethod(String),
+
+    #[fail(display = "Invalid keys_jwk")]
+    InvalidKeysJwk,
+
+    #[fail(display = "A force_auth flow requires an email")]
+    ForceAuthWithoutEmail,
+
+    #[fail(display = "Invalid metrics parameter {}", _0)]
+    InvalidMetricsParameter(String),
+
+    #[fail(display = "The redirect URL does not match our redirect URI")]
+    RedirectUriMismatch,
+
+    #[fail(display = "Missing {} parameter in the redirect URL", _0)]
+    MissingRedirectParameter(&'static str),
+
+    #[fail(display = "The authorization request failed: {}", _0)]
+    OAuthRedirectError(String),
+
     #[fail(display = "Key {} had wrong length, got {}, expected {}", _0, _1, _2)]
     BadKeyLength(&'static str, usize, usize),
 
@@ -95,6 +122,9 @@ pub enum ErrorKind {
     #[fail(display = "JWT signature validation failed")]
     JWTSignatureValidationFailed,
 
+    #[fail(display = "Malformed BrowserID certificate")]
+    MalformedCertificate,
+
     #[fail(display = "ECDH key generation failed")]
     KeyGenerationFailed,
 
@@ -110,12 +140,24 @@ pub enum ErrorKind {
     #[fail(display = "AEAD open failure")]
     AEADOpenFailure,
 
+    #[fail(display = "AEAD seal failure")]
+    AEADSealFailure,
+
+    #[fail(display = "Invalid recovery data")]
+    InvalidRecoveryData,
+
     #[fail(display = "Random number generation failure")]
     RngFailure,
 
     #[fail(display = "HMAC verification failed")]
     HmacVerifyFail,
 
+    #[fail(display = "Unexpected HTTP status {}", _0)]
+    UnexpectedStatus(u16),
+
+    #[fail(display = "The event loop running our requests has stopped")]
+    EventLoopGone,
+
     #[fail(
         display = "Remote server error: '{}' '{}' '{}' '{}' '{}'", code, errno, error, message, info
     )]
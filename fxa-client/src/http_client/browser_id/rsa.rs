@@ -18,6 +18,8 @@ use serde_json;
 use super::BrowserIDKeyPair;
 use errors::*;
 
+/// OpenSSL clears the private key components when the key is freed, and
+/// `Debug` never prints them.
 pub struct RSABrowserIDKeyPair {
     key: PKey<Private>,
 }
@@ -111,7 +113,9 @@ impl Serialize for RSABrowserIDKeyPair {
             .map_err(|err| ser::Error::custom(err.to_string()))?;
         state.serialize_field("n", &format!("{}", n))?;
         state.serialize_field("e", &format!("{}", e))?;
-        state.serialize_field("d", &format!("{}", d))?;
+        // The private components go straight from OpenSSL's buffers, so we
+        // don't leave copies of them in `String`s that never get zeroed.
+        state.serialize_field("d", &**d)?;
         if let (Some(p), Some(q)) = (rsa.p(), rsa.q()) {
             let p = p
                 .to_dec_str()
@@ -119,8 +123,8 @@ impl Serialize for RSABrowserIDKeyPair {
             let q = q
                 .to_dec_str()
                 .map_err(|err| ser::Error::custom(err.to_string()))?;
-            state.serialize_field("p", &format!("{}", p))?;
-            state.serialize_field("q", &format!("{}", q))?;
+            state.serialize_field("p", &**p)?;
+            state.serialize_field("q", &**q)?;
         }
         if let (Some(dmp1), Some(dmq1), Some(iqmp)) = (rsa.dmp1(), rsa.dmq1(), rsa.iqmp()) {
             let dmp1 = dmp1
@@ -132,9 +136,9 @@ impl Serialize for RSABrowserIDKeyPair {
             let iqmp = iqmp
                 .to_dec_str()
                 .map_err(|err| ser::Error::custom(err.to_string()))?;
-            state.serialize_field("dmp1", &format!("{}", dmp1))?;
-            state.serialize_field("dmq1", &format!("{}", dmq1))?;
-            state.serialize_field("iqmp", &format!("{}", iqmp))?;
+            state.serialize_field("dmp1", &**dmp1)?;
+            state.serialize_field("dmq1", &**dmq1)?;
+            state.serialize_field("iqmp", &**iqmp)?;
         }
         state.end()
     }
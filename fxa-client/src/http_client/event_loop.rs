@@ -0,0 +1,101 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Every request, for every account, runs on reqwest's async client on one
+//! shared event loop, so the number of threads doesn't grow with the number
+//! of accounts. The blocking API simply waits on the futures returned here.
+
+use std::cell::RefCell;
+use std::mem;
+use std::sync::mpsc;
+use std::thread;
+
+use futures::sync::oneshot;
+use futures::{future, Future, Stream};
+use reqwest::header::Headers;
+use reqwest::unstable::async::{Client, Decoder, Request};
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use serde_json;
+use tokio_core::reactor::{Core, Remote};
+
+use errors::*;
+
+/// A request running on the event loop. It can be waited on, or driven, from
+/// any thread.
+pub type FxaFuture<T> = Box<Future<Item = T, Error = Error> + Send>;
+
+/// A response, with its body read in full.
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body).map_err(|e| e.into())
+    }
+}
+
+lazy_static! {
+    static ref EVENT_LOOP: Remote = start_event_loop();
+}
+
+thread_local! {
+    // Only ever set on the event loop thread, so that requests share
+    // connections.
+    static CLIENT: RefCell<Option<Client>> = RefCell::new(None);
+}
+
+fn start_event_loop() -> Remote {
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("fxa-client".to_string())
+        .spawn(move || {
+            let mut core = Core::new().expect("Failed to create the event loop");
+            tx.send(core.remote())
+                .expect("Nobody is waiting for the event loop");
+            core.run(future::empty::<(), ()>())
+                .expect("The event loop stopped");
+        })
+        .expect("Failed to start the event loop thread");
+    rx.recv().expect("The event loop thread died")
+}
+
+pub fn execute(request: Request) -> FxaFuture<HttpResponse> {
+    let (tx, rx) = oneshot::channel();
+    EVENT_LOOP.spawn(move |handle| {
+        let client = CLIENT.with(|client| {
+            client
+                .borrow_mut()
+                .get_or_insert_with(|| Client::new(handle))
+                .clone()
+        });
+        client
+            .execute(request)
+            .and_then(|mut response| {
+                let status = response.status();
+                let headers = response.headers().clone();
+                let body = mem::replace(response.body_mut(), Decoder::empty());
+                body.fold(Vec::new(), |mut body, chunk| {
+                    body.extend_from_slice(&chunk);
+                    Ok::<_, ::reqwest::Error>(body)
+                }).map(move |body| HttpResponse {
+                    status,
+                    headers,
+                    body,
+                })
+            })
+            .then(move |result| {
+                // Whoever made the request may have given up on it already.
+                let _ = tx.send(result);
+                Ok::<(), ()>(())
+            })
+    });
+    Box::new(rx.then(|result| match result {
+        Ok(result) => result.map_err(|e| e.into()),
+        Err(_) => Err(ErrorKind::EventLoopGone.into()),
+    }))
+}
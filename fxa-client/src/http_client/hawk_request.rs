@@ -4,7 +4,8 @@
 
 use hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
 use hex;
-use reqwest::{header, Client, Method, Request};
+use reqwest::unstable::async::Request;
+use reqwest::{header, Method};
 use serde_json;
 use url::Url;
 
@@ -58,14 +59,14 @@ impl<'a> HAWKRequestBuilder<'a> {
             hawk_header = format!("Hawk {}", header);
         }
 
-        let mut request_builder = Client::new().request(self.method, self.url);
-        request_builder.header(header::Authorization(hawk_header));
+        let mut request = Request::new(self.method, self.url);
+        request.headers_mut().set(header::Authorization(hawk_header));
 
         if let Some(body) = self.body {
-            request_builder.header(header::ContentType::json());
-            request_builder.body(body);
+            request.headers_mut().set(header::ContentType::json());
+            *request.body_mut() = Some(body.into());
         }
 
-        request_builder.build().map_err(|e| e.into())
+        Ok(request)
     }
 }
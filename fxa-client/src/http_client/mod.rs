@@ -2,12 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use futures::{future, Future};
 use hex;
-use reqwest;
-use reqwest::{header, Client as ReqwestClient, Method, Request, Response, StatusCode};
-use ring::{digest, hkdf, hmac};
+use reqwest::unstable::async::Request;
+use reqwest::{header, Method, StatusCode};
+use ring::{digest, hkdf, hmac, pbkdf2};
+use serde::de::DeserializeOwned;
 use serde_json;
-use std;
+use url::Url;
 use util::Xorable;
 
 #[cfg(feature = "browserid")]
@@ -18,15 +20,22 @@ use self::browser_id::{jwt_utils, BrowserIDKeyPair};
 use self::hawk_request::HAWKRequestBuilder;
 use config::Config;
 use errors::*;
+use secret::{SecretBytes, SecretString};
+#[cfg(feature = "browserid")]
+use AuthorizationParameters;
+
+pub use self::event_loop::{FxaFuture, HttpResponse};
 
 #[cfg(feature = "browserid")]
 pub mod browser_id;
+mod event_loop;
 #[cfg(feature = "browserid")]
 mod hawk_request;
 
 const HKDF_SALT: [u8; 32] = [0b0; 32];
 const KEY_LENGTH: usize = 32;
 const SIGN_DURATION_MS: u64 = 24 * 60 * 60 * 1000;
+const QUICK_STRETCH_ITERATIONS: u32 = 1000;
 
 pub struct Client<'a> {
     config: &'a Config,
@@ -43,22 +52,64 @@ impl<'a> Client<'a> {
             .to_vec()
     }
 
-    #[allow(dead_code)]
     fn kwe(name: &str, email: &str) -> Vec<u8> {
         format!("identity.mozilla.com/picl/v1/{}:{}", name, email)
             .as_bytes()
             .to_vec()
     }
 
+    /// Stretches the user's password with PBKDF2, as done by the onepw protocol.
+    /// Note that `email` must be the email the account was created with.
+    pub fn quick_stretch_pwd(email: &str, pwd: &str) -> SecretBytes {
+        let salt = Client::kwe("quickStretch", email);
+        let mut out = vec![0u8; KEY_LENGTH];
+        pbkdf2::derive(
+            &digest::SHA256,
+            QUICK_STRETCH_ITERATIONS,
+            &salt,
+            pwd.as_bytes(),
+            &mut out,
+        );
+        SecretBytes::new(out)
+    }
+
+    pub fn derive_auth_pwd(stretched_pwd: &[u8]) -> SecretString {
+        let salt = [0u8; 0];
+        let context_info = Client::kw("authPW");
+        let derived = SecretBytes::new(Client::derive_hkdf_sha256_key(
+            stretched_pwd,
+            &salt,
+            &context_info,
+            KEY_LENGTH,
+        ));
+        SecretString::new(hex::encode(&derived))
+    }
+
+    pub fn derive_unwrap_kb(stretched_pwd: &[u8]) -> SecretBytes {
+        let salt = [0u8; 0];
+        let context_info = Client::kw("unwrapBkey");
+        SecretBytes::new(Client::derive_hkdf_sha256_key(
+            stretched_pwd,
+            &salt,
+            &context_info,
+            KEY_LENGTH,
+        ))
+    }
+
     #[cfg(feature = "browserid")]
     pub fn key_pair(len: u32) -> Result<RSABrowserIDKeyPair> {
         RSABrowserIDKeyPair::generate_random(len)
     }
 
-    pub fn derive_sync_key(kb: &[u8]) -> Vec<u8> {
+    pub fn derive_sync_key(kb: &[u8]) -> SecretBytes {
         let salt = [0u8; 0];
         let context_info = Client::kw("oldsync");
-        Client::derive_hkdf_sha256_key(&kb, &salt, &context_info, KEY_LENGTH * 2)
+        SecretBytes::new(Client::derive_hkdf_sha256_key(
+            &kb,
+            &salt,
+            &context_info,
+            KEY_LENGTH * 2,
+        ))
     }
 
     pub fn compute_client_state(kb: &[u8]) -> String {
@@ -71,30 +122,26 @@ impl<'a> Client<'a> {
 
     #[cfg(feature = "browserid")]
     pub fn login(&self, email: &str, auth_pwd: &str, get_keys: bool) -> Result<LoginResponse> {
-        let url = self.config.auth_url_path("v1/account/login")?;
+        let mut url = self.config.auth_url_path("v1/account/login")?;
+        url.query_pairs_mut()
+            .append_pair("keys", &get_keys.to_string());
         let parameters = json!({
           "email": email,
           "authPW": auth_pwd
         });
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::Post, url)
-            .query(&[("keys", get_keys)])
-            .body(parameters.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let request = Client::json_request(Method::Post, url, &parameters);
+        Client::make_request(request).wait()?.json()
     }
 
     pub fn account_status(&self, uid: &String) -> Result<AccountStatusResponse> {
-        let url = self.config.auth_url_path("v1/account/status")?;
-        let client = ReqwestClient::new();
-        let request = client.get(url).query(&[("uid", uid)]).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        let mut url = self.config.auth_url_path("v1/account/status")?;
+        url.query_pairs_mut().append_pair("uid", uid);
+        let request = Request::new(Method::Get, url);
+        Client::make_request(request).wait()?.json()
     }
 
     #[cfg(feature = "browserid")]
-    pub fn keys(&self, key_fetch_token: &[u8]) -> Result<KeysResponse> {
-        let url = self.config.auth_url_path("v1/account/keys")?;
+    pub fn keys(&self, key_fetch_token: &[u8]) -> FxaFuture<KeysResponse> {
         let context_info = Client::kw("keyFetchToken");
         let key = Client::derive_hkdf_sha256_key(
             &key_fetch_token,
@@ -102,76 +149,339 @@ impl<'a> Client<'a> {
             &context_info,
             KEY_LENGTH * 3,
         );
-        let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
-        let request = HAWKRequestBuilder::new(Method::Get, url, &key).build()?;
-        let json: serde_json::Value = Client::make_request(request)?.json()?;
-        let bundle = match json["bundle"].as_str() {
-            Some(bundle) => bundle,
-            None => panic!("Invalid JSON"),
-        };
-        let data = hex::decode(bundle)?;
-        if data.len() != 3 * KEY_LENGTH {
-            return Err(ErrorKind::BadKeyLength("bundle", 3 * KEY_LENGTH, data.len()).into());
+        let request = self
+            .config
+            .auth_url_path("v1/account/keys")
+            .and_then(|url| HAWKRequestBuilder::new(Method::Get, url, &key).build());
+        Box::new(future::result(request).and_then(Client::make_request).and_then(move |resp| {
+            let key_request_key = &key[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
+            let json: serde_json::Value = resp.json()?;
+            let bundle = match json["bundle"].as_str() {
+                Some(bundle) => bundle,
+                None => panic!("Invalid JSON"),
+            };
+            let data = hex::decode(bundle)?;
+            if data.len() != 3 * KEY_LENGTH {
+                return Err(ErrorKind::BadKeyLength("bundle", 3 * KEY_LENGTH, data.len()).into());
+            }
+            let ciphertext = &data[0..(KEY_LENGTH * 2)];
+            let mac_code = &data[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
+            let context_info = Client::kw("account/keys");
+            let bytes = Client::derive_hkdf_sha256_key(
+                key_request_key,
+                &HKDF_SALT,
+                &context_info,
+                KEY_LENGTH * 3,
+            );
+            let hmac_key = &bytes[0..KEY_LENGTH];
+            let xor_key = &bytes[KEY_LENGTH..(KEY_LENGTH * 3)];
+
+            let v_key = hmac::VerificationKey::new(&digest::SHA256, hmac_key.as_ref());
+            hmac::verify(&v_key, ciphertext, mac_code).map_err(|_| ErrorKind::HmacVerifyFail)?;
+
+            let xored_bytes = ciphertext.xored_with(xor_key)?;
+            let wrap_kb = xored_bytes[KEY_LENGTH..(KEY_LENGTH * 2)].to_vec();
+            Ok(KeysResponse { wrap_kb })
+        }))
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn recovery_email_status(
+        &self,
+        session_token: &[u8],
+    ) -> Result<RecoveryEmailStatusResponse> {
+        self.make_session_token_get("v1/recovery_email/status", session_token)
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn session_status(&self, session_token: &[u8]) -> Result<SessionStatusResponse> {
+        self.make_session_token_get("v1/session/status", session_token)
+    }
+
+    // Sending our session token is required by the server if it has one for us,
+    // on top of the password which proves the user is actually there.
+    #[cfg(feature = "browserid")]
+    pub fn destroy_account(
+        &self,
+        email: &str,
+        auth_pwd: &str,
+        session_token: Option<&[u8]>,
+    ) -> Result<()> {
+        let parameters = json!({
+          "email": email,
+          "authPW": auth_pwd
+        });
+        if let Some(session_token) = session_token {
+            self.make_session_token_post("v1/account/destroy", session_token, parameters)?;
+            return Ok(());
         }
-        let ciphertext = &data[0..(KEY_LENGTH * 2)];
-        let mac_code = &data[(KEY_LENGTH * 2)..(KEY_LENGTH * 3)];
-        let context_info = Client::kw("account/keys");
-        let bytes = Client::derive_hkdf_sha256_key(
-            key_request_key,
-            &HKDF_SALT,
-            &context_info,
-            KEY_LENGTH * 3,
-        );
-        let hmac_key = &bytes[0..KEY_LENGTH];
-        let xor_key = &bytes[KEY_LENGTH..(KEY_LENGTH * 3)];
+        let url = self.config.auth_url_path("v1/account/destroy")?;
+        let request = Client::json_request(Method::Post, url, &parameters);
+        Client::make_request(request).wait()?;
+        Ok(())
+    }
 
-        let v_key = hmac::VerificationKey::new(&digest::SHA256, hmac_key.as_ref());
-        hmac::verify(&v_key, ciphertext, mac_code).map_err(|_| ErrorKind::HmacVerifyFail)?;
+    #[cfg(feature = "browserid")]
+    pub fn attached_clients(&self, session_token: &[u8]) -> Result<Vec<AttachedClientResponse>> {
+        self.make_session_token_get("v1/account/attached_clients", session_token)
+    }
 
-        let xored_bytes = ciphertext.xored_with(xor_key)?;
-        let wrap_kb = xored_bytes[KEY_LENGTH..(KEY_LENGTH * 2)].to_vec();
-        Ok(KeysResponse { wrap_kb })
+    #[cfg(feature = "browserid")]
+    pub fn destroy_session(&self, session_token: &[u8], session_token_id: &str) -> Result<()> {
+        let parameters = json!({
+          "customSessionToken": session_token_id
+        });
+        self.make_session_token_post("v1/session/destroy", session_token, parameters)?;
+        Ok(())
     }
 
     #[cfg(feature = "browserid")]
-    pub fn recovery_email_status(
+    pub fn destroy_attached_oauth_client(
         &self,
         session_token: &[u8],
-    ) -> Result<RecoveryEmailStatusResponse> {
-        let url = self.config.auth_url_path("v1/recovery_email/status")?;
-        let key = Client::derive_key_from_session_token(session_token)?;
+        client_id: &str,
+    ) -> Result<()> {
+        let parameters = json!({
+          "clientId": client_id
+        });
+        self.make_session_token_post(
+            "v1/account/attached_client/destroy",
+            session_token,
+            parameters,
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn destroy_device(&self, session_token: &[u8], device_id: &str) -> Result<()> {
+        let parameters = json!({
+          "id": device_id
+        });
+        self.make_session_token_post("v1/account/device/destroy", session_token, parameters)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn recovery_emails(&self, session_token: &[u8]) -> Result<Vec<EmailResponse>> {
+        self.make_session_token_get("v1/recovery_emails", session_token)
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn create_secondary_email(&self, session_token: &[u8], email: &str) -> Result<()> {
+        self.make_email_request(
+            "v1/recovery_email",
+            session_token,
+            json!({ "email": email }),
+        )
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn resend_email_code(&self, session_token: &[u8], email: &str) -> Result<()> {
+        self.make_email_request(
+            "v1/recovery_email/resend_code",
+            session_token,
+            json!({ "email": email }),
+        )
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn verify_secondary_email(
+        &self,
+        session_token: &[u8],
+        email: &str,
+        code: &str,
+    ) -> Result<()> {
+        self.make_email_request(
+            "v1/recovery_email/secondary/verify_code",
+            session_token,
+            json!({ "email": email, "code": code }),
+        )
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn set_primary_email(&self, session_token: &[u8], email: &str) -> Result<()> {
+        self.make_email_request(
+            "v1/recovery_email/set_primary",
+            session_token,
+            json!({ "email": email }),
+        )
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn destroy_secondary_email(&self, session_token: &[u8], email: &str) -> Result<()> {
+        self.make_email_request(
+            "v1/recovery_email/destroy",
+            session_token,
+            json!({ "email": email }),
+        )
+    }
+
+    // The recovery_email endpoints all reply with an empty object.
+    #[cfg(feature = "browserid")]
+    fn make_email_request(
+        &self,
+        path: &str,
+        session_token: &[u8],
+        parameters: serde_json::Value,
+    ) -> Result<()> {
+        self.make_session_token_post(path, session_token, parameters)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn create_recovery_key(
+        &self,
+        session_token: &[u8],
+        recovery_key_id: &str,
+        recovery_data: &str,
+    ) -> Result<()> {
+        let parameters = json!({
+          "recoveryKeyId": recovery_key_id,
+          "recoveryData": recovery_data
+        });
+        self.make_session_token_post("v1/recoveryKey", session_token, parameters)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn get_recovery_key(
+        &self,
+        account_reset_token: &[u8],
+        recovery_key_id: &str,
+    ) -> Result<RecoveryKeyResponse> {
+        let url = self
+            .config
+            .auth_url_path(&format!("v1/recoveryKey/{}", recovery_key_id))?;
+        let key = Client::derive_key_from_token(account_reset_token, "accountResetToken")?;
         let request = HAWKRequestBuilder::new(Method::Get, url, &key).build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        Client::make_request(request).wait()?.json()
+    }
+
+    pub fn password_forgot_send_code(&self, email: &str) -> Result<PasswordForgotSendCodeResponse> {
+        let url = self.config.auth_url_path("v1/password/forgot/send_code")?;
+        let parameters = json!({
+          "email": email
+        });
+        let request = Client::json_request(Method::Post, url, &parameters);
+        Client::make_request(request).wait()?.json()
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn password_forgot_verify_code(
+        &self,
+        password_forgot_token: &[u8],
+        code: &str,
+    ) -> Result<PasswordForgotVerifyCodeResponse> {
+        let url = self.config.auth_url_path("v1/password/forgot/verify_code")?;
+        let parameters = json!({
+          "code": code
+        });
+        let key = Client::derive_key_from_token(password_forgot_token, "passwordForgotToken")?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(parameters)
+            .build()?;
+        Client::make_request(request).wait()?.json()
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn account_reset(
+        &self,
+        account_reset_token: &[u8],
+        auth_pwd: &str,
+        wrap_kb: &[u8],
+        recovery_key_id: Option<&str>,
+    ) -> Result<AccountResetResponse> {
+        let url = self.config.auth_url_path("v1/account/reset?keys=true")?;
+        let mut parameters = json!({
+          "authPW": auth_pwd,
+          "wrapKb": hex::encode(wrap_kb),
+          "sessionToken": true
+        });
+        if let Some(recovery_key_id) = recovery_key_id {
+            parameters["recoveryKeyId"] = json!(recovery_key_id);
+        }
+        let key = Client::derive_key_from_token(account_reset_token, "accountResetToken")?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(parameters)
+            .build()?;
+        Client::make_request(request).wait()?.json()
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn password_change_start(
+        &self,
+        email: &str,
+        old_auth_pwd: &str,
+    ) -> Result<PasswordChangeStartResponse> {
+        let url = self.config.auth_url_path("v1/password/change/start")?;
+        let parameters = json!({
+          "email": email,
+          "oldAuthPW": old_auth_pwd
+        });
+        let request = Client::json_request(Method::Post, url, &parameters);
+        Client::make_request(request).wait()?.json()
+    }
+
+    // Passing our current session token makes the server keep it around (and
+    // hand us a fresh one), instead of destroying it with every other session.
+    #[cfg(feature = "browserid")]
+    pub fn password_change_finish(
+        &self,
+        password_change_token: &[u8],
+        auth_pwd: &str,
+        wrap_kb: &[u8],
+        session_token: &[u8],
+    ) -> Result<PasswordChangeFinishResponse> {
+        let url = self
+            .config
+            .auth_url_path("v1/password/change/finish?keys=true")?;
+        let parameters = json!({
+          "authPW": auth_pwd,
+          "wrapKb": hex::encode(wrap_kb),
+          "sessionToken": Client::session_token_id(session_token)?
+        });
+        let key = Client::derive_key_from_token(password_change_token, "passwordChangeToken")?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(parameters)
+            .build()?;
+        Client::make_request(request).wait()?.json()
     }
 
     pub fn profile(
         &self,
         profile_access_token: &str,
         etag: Option<String>,
-    ) -> Result<Option<ResponseAndETag<ProfileResponse>>> {
-        let url = self.config.userinfo_endpoint()?;
-        let client = ReqwestClient::new();
-        let mut builder = client.request(Method::Get, url);
-        builder.header(header::Authorization(header::Bearer {
-            token: profile_access_token.to_string(),
-        }));
-        if let Some(etag) = etag {
-            builder.header(header::IfNoneMatch::Items(vec![header::EntityTag::strong(
-                etag,
-            )]));
-        }
-        let request = builder.build()?;
-        let mut resp = Client::make_request(request)?;
-        if resp.status() == StatusCode::NotModified {
-            return Ok(None);
-        }
-        Ok(Some(ResponseAndETag {
-            etag: resp
-                .headers()
-                .get::<header::ETag>()
-                .map(|etag| etag.tag().to_string()),
-            response: resp.json()?,
-        }))
+    ) -> FxaFuture<Option<ResponseAndETag<ProfileResponse>>> {
+        let request = self.config.userinfo_endpoint().map(|url| {
+            let mut request = Request::new(Method::Get, url);
+            request.headers_mut().set(header::Authorization(header::Bearer {
+                token: profile_access_token.to_string(),
+            }));
+            if let Some(etag) = etag {
+                request
+                    .headers_mut()
+                    .set(header::IfNoneMatch::Items(vec![header::EntityTag::strong(
+                        etag,
+                    )]));
+            }
+            request
+        });
+        Box::new(
+            future::result(request)
+                .and_then(Client::make_request)
+                .and_then(|resp| {
+                    if resp.status == StatusCode::NotModified {
+                        return Ok(None);
+                    }
+                    Ok(Some(ResponseAndETag {
+                        etag: resp
+                            .headers
+                            .get::<header::ETag>()
+                            .map(|etag| etag.tag().to_string()),
+                        response: resp.json()?,
+                    }))
+                }),
+        )
     }
 
     #[cfg(feature = "browserid")]
@@ -179,24 +489,82 @@ impl<'a> Client<'a> {
         &self,
         client_id: &str,
         session_token: &[u8],
+        clock_skew: i64,
         scopes: &[&str],
-    ) -> Result<OAuthTokenResponse> {
-        let audience = self.get_oauth_audience()?;
-        let key_pair = Client::key_pair(1024)?;
-        let certificate = self.sign(session_token, &key_pair)?.certificate;
-        let assertion = jwt_utils::create_assertion(&key_pair, &certificate, &audience)?;
+    ) -> FxaFuture<OAuthTokenResponse> {
         let parameters = json!({
-          "assertion": assertion,
           "client_id": client_id,
           "response_type": "token",
           "scope": scopes.join(" ")
         });
-        let key = Client::derive_key_from_session_token(session_token)?;
-        let url = self.config.authorization_endpoint()?;
-        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
-            .body(parameters)
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        self.make_oauth_authorization_request(session_token, clock_skew, parameters)
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn oauth_authorization_code_with_session_token(
+        &self,
+        session_token: &[u8],
+        clock_skew: i64,
+        params: &AuthorizationParameters,
+    ) -> Result<AuthorizationResponse> {
+        let mut parameters = json!({
+          "client_id": params.client_id,
+          "redirect_uri": params.redirect_uri,
+          "response_type": "code",
+          "scope": params.scopes.join(" "),
+          "state": params.state,
+          "access_type": params.access_type,
+          "code_challenge": params.code_challenge,
+          "code_challenge_method": params.code_challenge_method
+        });
+        if let Some(ref keys_jwk) = params.keys_jwk {
+            parameters["keys_jwk"] = json!(keys_jwk);
+        }
+        self.make_oauth_authorization_request(session_token, clock_skew, parameters)
+            .wait()
+    }
+
+    #[cfg(feature = "browserid")]
+    fn oauth_assertion(&self, session_token: &[u8], clock_skew: i64) -> FxaFuture<String> {
+        let (audience, key_pair) = match self
+            .get_oauth_audience()
+            .and_then(|audience| Ok((audience, Client::key_pair(1024)?)))
+        {
+            Ok(prepared) => prepared,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let certificate = self.sign(session_token, &key_pair);
+        Box::new(certificate.and_then(move |resp| {
+            jwt_utils::create_assertion(&key_pair, &resp.certificate, &audience, clock_skew)
+        }))
+    }
+
+    // Signs an assertion for the OAuth server first, then sends it along with
+    // `parameters`.
+    #[cfg(feature = "browserid")]
+    fn make_oauth_authorization_request<T>(
+        &self,
+        session_token: &[u8],
+        clock_skew: i64,
+        mut parameters: serde_json::Value,
+    ) -> FxaFuture<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let prepared = Client::derive_key_from_session_token(session_token)
+            .and_then(|key| Ok((key, self.config.authorization_endpoint()?)));
+        Box::new(
+            self.oauth_assertion(session_token, clock_skew)
+                .and_then(move |assertion| {
+                    parameters["assertion"] = json!(assertion);
+                    let request = prepared.and_then(|(key, url)| {
+                        HAWKRequestBuilder::new(Method::Post, url, &key)
+                            .body(parameters)
+                            .build()
+                    });
+                    Client::make_json_request(request)
+                }),
+        )
     }
 
     pub fn oauth_token_with_code(
@@ -204,7 +572,7 @@ impl<'a> Client<'a> {
         code: &str,
         code_verifier: &str,
         client_id: &str,
-    ) -> Result<OAuthTokenResponse> {
+    ) -> FxaFuture<OAuthTokenResponse> {
         let body = json!({
             "code": code,
             "client_id": client_id,
@@ -218,7 +586,7 @@ impl<'a> Client<'a> {
         client_id: &str,
         refresh_token: &str,
         scopes: &[&str],
-    ) -> Result<OAuthTokenResponse> {
+    ) -> FxaFuture<OAuthTokenResponse> {
         let body = json!({
             "grant_type": "refresh_token",
             "client_id": client_id,
@@ -228,30 +596,28 @@ impl<'a> Client<'a> {
         self.make_oauth_token_request(body)
     }
 
-    fn make_oauth_token_request(&self, body: serde_json::Value) -> Result<OAuthTokenResponse> {
-        let url = self.config.token_endpoint()?;
-        let client = ReqwestClient::new();
-        let request = client
-            .request(Method::Post, url)
-            .header(header::ContentType::json())
-            .body(body.to_string())
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+    fn make_oauth_token_request(&self, body: serde_json::Value) -> FxaFuture<OAuthTokenResponse> {
+        let request = self
+            .config
+            .token_endpoint()
+            .map(|url| Client::json_request(Method::Post, url, &body));
+        Client::make_json_request(request)
     }
 
     #[cfg(feature = "browserid")]
-    pub fn sign(&self, session_token: &[u8], key_pair: &BrowserIDKeyPair) -> Result<SignResponse> {
-        let public_key_json = key_pair.to_json(false)?;
-        let parameters = json!({
-          "publicKey": public_key_json,
-          "duration": SIGN_DURATION_MS
+    pub fn sign(&self, session_token: &[u8], key_pair: &BrowserIDKeyPair) -> FxaFuture<SignResponse> {
+        let request = key_pair.to_json(false).and_then(|public_key_json| {
+            let parameters = json!({
+              "publicKey": public_key_json,
+              "duration": SIGN_DURATION_MS
+            });
+            let key = Client::derive_key_from_session_token(session_token)?;
+            let url = self.config.auth_url_path("v1/certificate/sign")?;
+            HAWKRequestBuilder::new(Method::Post, url, &key)
+                .body(parameters)
+                .build()
         });
-        let key = Client::derive_key_from_session_token(session_token)?;
-        let url = self.config.auth_url_path("v1/certificate/sign")?;
-        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
-            .body(parameters)
-            .build()?;
-        Client::make_request(request)?.json().map_err(|e| e.into())
+        Client::make_json_request(request)
     }
 
     fn get_oauth_audience(&self) -> Result<String> {
@@ -265,33 +631,87 @@ impl<'a> Client<'a> {
         }
     }
 
+    #[cfg(feature = "browserid")]
+    fn make_session_token_get<T>(&self, path: &str, session_token: &[u8]) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let url = self.config.auth_url_path(path)?;
+        let key = Client::derive_key_from_session_token(session_token)?;
+        let request = HAWKRequestBuilder::new(Method::Get, url, &key).build()?;
+        Client::make_request(request).wait()?.json()
+    }
+
+    #[cfg(feature = "browserid")]
+    fn make_session_token_post(
+        &self,
+        path: &str,
+        session_token: &[u8],
+        body: serde_json::Value,
+    ) -> Result<HttpResponse> {
+        let url = self.config.auth_url_path(path)?;
+        let key = Client::derive_key_from_session_token(session_token)?;
+        let request = HAWKRequestBuilder::new(Method::Post, url, &key)
+            .body(body)
+            .build()?;
+        Client::make_request(request).wait()
+    }
+
+    /// The id the server knows a session token by, e.g. in `attached_clients`.
+    #[cfg(feature = "browserid")]
+    pub fn session_token_id(session_token: &[u8]) -> Result<String> {
+        let key = Client::derive_key_from_session_token(session_token)?;
+        Ok(hex::encode(&key[..KEY_LENGTH]))
+    }
+
     fn derive_key_from_session_token(session_token: &[u8]) -> Result<Vec<u8>> {
-        let context_info = Client::kw("sessionToken");
+        Client::derive_key_from_token(session_token, "sessionToken")
+    }
+
+    // Derives the HAWK credentials (token id and request HMAC key) of a onepw token.
+    fn derive_key_from_token(token: &[u8], name: &str) -> Result<Vec<u8>> {
+        let context_info = Client::kw(name);
         Ok(Client::derive_hkdf_sha256_key(
-            session_token,
+            token,
             &HKDF_SALT,
             &context_info,
             KEY_LENGTH * 2,
         ))
     }
 
-    fn derive_hkdf_sha256_key(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Vec<u8> {
+    pub fn derive_hkdf_sha256_key(ikm: &[u8], salt: &[u8], info: &[u8], len: usize) -> Vec<u8> {
         let salt = hmac::SigningKey::new(&digest::SHA256, salt);
         let mut out = vec![0u8; len];
         hkdf::extract_and_expand(&salt, ikm, info, &mut out);
         out.to_vec()
     }
 
-    fn make_request(request: Request) -> Result<Response> {
-        let client = ReqwestClient::new();
-        let mut resp = client.execute(request)?;
-        let status = resp.status();
+    fn json_request(method: Method, url: Url, body: &serde_json::Value) -> Request {
+        let mut request = Request::new(method, url);
+        request.headers_mut().set(header::ContentType::json());
+        *request.body_mut() = Some(body.to_string().into());
+        request
+    }
+
+    // Sends a request, if we managed to build it, and parses its response.
+    fn make_json_request<T>(request: Result<Request>) -> FxaFuture<T>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        Box::new(
+            future::result(request)
+                .and_then(Client::make_request)
+                .and_then(|resp| resp.json()),
+        )
+    }
 
-        if status.is_success() || status == StatusCode::NotModified {
-            Ok(resp)
-        } else {
-            let json: std::result::Result<serde_json::Value, reqwest::Error> = resp.json();
-            match json {
+    fn make_request(request: Request) -> FxaFuture<HttpResponse> {
+        Box::new(event_loop::execute(request).and_then(|resp| {
+            let status = resp.status;
+            if status.is_success() || status == StatusCode::NotModified {
+                return Ok(resp);
+            }
+            match resp.json::<serde_json::Value>() {
                 Ok(json) => Err(ErrorKind::RemoteError {
                     code: json["code"].as_u64().unwrap_or(0),
                     errno: json["errno"].as_u64().unwrap_or(0),
@@ -299,9 +719,9 @@ impl<'a> Client<'a> {
                     message: json["message"].as_str().unwrap_or("").to_string(),
                     info: json["info"].as_str().unwrap_or("").to_string(),
                 }.into()),
-                Err(_) => Err(resp.error_for_status().unwrap_err().into()),
+                Err(_) => Err(ErrorKind::UnexpectedStatus(status.as_u16()).into()),
             }
-        }
+        }))
     }
 }
 
@@ -324,6 +744,98 @@ pub struct RecoveryEmailStatusResponse {
     pub verified: bool,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmailResponse {
+    pub email: String,
+    #[serde(rename = "isPrimary")]
+    pub is_primary: bool,
+    pub verified: bool,
+}
+
+impl EmailResponse {
+    pub fn list_to_json(emails: &[EmailResponse]) -> Result<String> {
+        serde_json::to_string(emails).map_err(|e| e.into())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RecoveryKeyResponse {
+    #[serde(rename = "recoveryData")]
+    pub recovery_data: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForgotSendCodeResponse {
+    #[serde(rename = "passwordForgotToken")]
+    pub password_forgot_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordForgotVerifyCodeResponse {
+    #[serde(rename = "accountResetToken")]
+    pub account_reset_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct AccountResetResponse {
+    pub uid: String,
+    #[serde(rename = "sessionToken")]
+    pub session_token: String,
+    #[serde(rename = "keyFetchToken")]
+    pub key_fetch_token: String,
+    pub verified: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeStartResponse {
+    #[serde(rename = "keyFetchToken")]
+    pub key_fetch_token: String,
+    #[serde(rename = "passwordChangeToken")]
+    pub password_change_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChangeFinishResponse {
+    pub uid: String,
+    #[serde(rename = "sessionToken")]
+    pub session_token: String,
+    #[serde(rename = "keyFetchToken")]
+    pub key_fetch_token: String,
+    pub verified: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AttachedClientResponse {
+    #[serde(rename = "clientId")]
+    pub client_id: Option<String>,
+    #[serde(rename = "deviceId")]
+    pub device_id: Option<String>,
+    #[serde(rename = "sessionTokenId")]
+    pub session_token_id: Option<String>,
+    #[serde(rename = "isCurrentSession", default)]
+    pub is_current_session: bool,
+    #[serde(rename = "deviceType")]
+    pub device_type: Option<String>,
+    pub name: Option<String>,
+    pub scope: Option<Vec<String>>,
+    #[serde(rename = "createdTime")]
+    pub created_time: Option<u64>,
+    #[serde(rename = "lastAccessTime")]
+    pub last_access_time: Option<u64>,
+}
+
+impl AttachedClientResponse {
+    pub fn list_to_json(clients: &[AttachedClientResponse]) -> Result<String> {
+        serde_json::to_string(clients).map_err(|e| e.into())
+    }
+}
+
+#[derive(Deserialize)]
+pub struct SessionStatusResponse {
+    pub uid: String,
+    pub state: String,
+}
+
 #[derive(Deserialize)]
 pub struct AccountStatusResponse {
     pub exists: bool,
@@ -338,6 +850,13 @@ pub struct OAuthTokenResponse {
     pub access_token: String,
 }
 
+#[derive(Deserialize)]
+pub struct AuthorizationResponse {
+    pub code: String,
+    pub state: String,
+    pub redirect: String,
+}
+
 #[derive(Deserialize)]
 pub struct SignResponse {
     #[serde(rename = "cert")]
@@ -369,21 +888,14 @@ pub struct ProfileResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use ring::{digest, pbkdf2};
 
-    fn quick_strech_pwd(email: &str, pwd: &str) -> Vec<u8> {
-        let salt = Client::kwe("quickStretch", email);
-        let mut out = [0u8; 32];
-        pbkdf2::derive(&digest::SHA256, 1000, &salt, pwd.as_bytes(), &mut out);
-        out.to_vec()
+    fn quick_strech_pwd(email: &str, pwd: &str) -> SecretBytes {
+        Client::quick_stretch_pwd(email, pwd)
     }
 
-    fn auth_pwd(email: &str, pwd: &str) -> String {
+    fn auth_pwd(email: &str, pwd: &str) -> SecretString {
         let streched = quick_strech_pwd(email, pwd);
-        let salt = [0u8; 0];
-        let context = Client::kw("authPW");
-        let derived = Client::derive_hkdf_sha256_key(&streched, &salt, &context, 32);
-        hex::encode(derived)
+        Client::derive_auth_pwd(&streched)
     }
 
     #[test]
@@ -403,11 +915,22 @@ mod tests {
         let pwd = "pässwörd";
         let auth_pwd = auth_pwd(email, pwd);
         assert_eq!(
-            auth_pwd,
+            &*auth_pwd,
             "247b675ffb4c46310bc87e26d712153abe5e1c90ef00a4784594f97ef54f2375"
         );
     }
 
+    #[test]
+    fn test_unwrap_kb() {
+        let email = "andré@example.org";
+        let pwd = "pässwörd";
+        let unwrap_kb = hex::encode(Client::derive_unwrap_kb(&quick_strech_pwd(email, pwd)));
+        assert_eq!(
+            unwrap_kb,
+            "de6a2648b78284fcb9ffa81ba95803309cfba7af583c01a8a1a63e567234dd28"
+        );
+    }
+
     // #[test]
     // fn live_account_test() {
     //     let email = "testfxarustclient@restmail.net";
@@ -422,7 +945,7 @@ mod tests {
     //     let session_token = hex::decode(resp.session_token).unwrap();
 
     //     let resp = client
-    //         .oauth_token_with_session_token("5882386c6d801776", &session_token, &["profile"])
+    //         .oauth_token_with_session_token("5882386c6d801776", &session_token, 0, &["profile"])
     //         .unwrap();
     //     println!("OAuth Token obtained: {}", &resp.access_token);
     // }
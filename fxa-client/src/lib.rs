@@ -4,9 +4,11 @@
 
 extern crate base64;
 extern crate byteorder;
+extern crate clear_on_drop;
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
+extern crate futures;
 #[cfg(feature = "browserid")]
 extern crate hawk;
 extern crate hex;
@@ -24,6 +26,7 @@ extern crate serde;
 extern crate serde_derive;
 #[macro_use]
 extern crate serde_json;
+extern crate tokio_core;
 extern crate untrusted;
 extern crate url;
 
@@ -37,26 +40,56 @@ use self::login_sm::LoginState::*;
 #[cfg(feature = "browserid")]
 use self::login_sm::*;
 use errors::*;
+use futures::future::{self, Either};
+#[cfg(feature = "browserid")]
+use futures::future::Loop;
+use futures::Future;
 #[cfg(feature = "browserid")]
 use http_client::browser_id::jwt_utils;
-use http_client::{Client, OAuthTokenResponse, ProfileResponse};
+use http_client::{Client, FxaFuture, OAuthTokenResponse, ProfileResponse};
+#[cfg(feature = "browserid")]
+use http_client::AuthorizationResponse;
+#[cfg(feature = "browserid")]
+use recovery_key::RecoveryKey;
 use ring::digest;
 use ring::rand::{SecureRandom, SystemRandom};
 use scoped_keys::ScopedKeysFlow;
+#[cfg(feature = "browserid")]
+use secret::SecretBytes;
+use secret::SecretString;
 use url::Url;
 use util::now;
+#[cfg(feature = "browserid")]
+use util::Xorable;
+#[cfg(feature = "browserid")]
+use web_channel::WebChannelCommand;
 
 mod config;
 pub mod errors;
 mod http_client;
 #[cfg(feature = "browserid")]
 mod login_sm;
+mod metrics;
 mod oauth;
+#[cfg(feature = "browserid")]
+mod recovery_key;
 mod scoped_keys;
+pub mod secret;
 mod util;
+#[cfg(feature = "browserid")]
+mod web_channel;
 
 pub use config::Config;
+#[cfg(feature = "browserid")]
+pub use http_client::browser_id::jwt_utils::CertificateInfo;
+#[cfg(feature = "browserid")]
+pub use http_client::AttachedClientResponse as AttachedClient;
+#[cfg(feature = "browserid")]
+pub use http_client::AuthorizationResponse as AuthorizationCode;
+#[cfg(feature = "browserid")]
+pub use http_client::EmailResponse as Email;
 pub use http_client::ProfileResponse as Profile;
+pub use metrics::{MetricsCallback, MetricsEvent};
 
 // If a cached token has less than `OAUTH_MIN_TIME_LEFT` seconds left to live,
 // it will be considered already expired.
@@ -68,13 +101,17 @@ lazy_static! {
     static ref RNG: SystemRandom = SystemRandom::new();
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct StateV1 {
     client_id: String,
     redirect_uri: String,
     config: Config,
     #[cfg(feature = "browserid")]
     login_state: LoginState,
+    // The client state (`xcs`) of the last kB we saw, to notice when it changes.
+    #[cfg(feature = "browserid")]
+    #[serde(default)]
+    key_fingerprint: Option<String>,
     oauth_cache: HashMap<String, OAuthInfo>,
 }
 
@@ -84,17 +121,25 @@ enum State {
     V1(StateV1),
 }
 
+// Serializes exactly like `State`, but borrows the state so we don't have to
+// copy the secrets in it.
+#[derive(Serialize)]
+#[serde(tag = "schema_version")]
+enum StateRef<'a> {
+    V1(&'a StateV1),
+}
+
 #[derive(Deserialize)]
 pub struct WebChannelResponse {
     uid: String,
     email: String,
     verified: bool,
     #[serde(rename = "sessionToken")]
-    session_token: String,
+    session_token: SecretString,
     #[serde(rename = "keyFetchToken")]
-    key_fetch_token: String,
+    key_fetch_token: SecretString,
     #[serde(rename = "unwrapBKey")]
-    unwrap_kb: String,
+    unwrap_kb: SecretString,
 }
 
 impl WebChannelResponse {
@@ -113,6 +158,8 @@ pub struct FirefoxAccount {
     state: StateV1,
     flow_store: HashMap<String, OAuthFlow>,
     persist_callback: Option<PersistCallback>,
+    event_callback: Option<EventCallback>,
+    metrics_callback: Option<MetricsCallback>,
     profile_cache: Option<CachedResponse<ProfileResponse>>,
 }
 
@@ -137,12 +184,70 @@ impl PersistCallback {
     }
 }
 
+/// Something that happened to the account which the embedding application
+/// may want to react to.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum AccountEvent {
+    /// The account credentials are no longer valid (e.g. the session was
+    /// destroyed from another device): the user needs to sign in again.
+    AuthorizationLost,
+    /// kB changed (e.g. the password was reset without a recovery key), and
+    /// so did the sync key: data encrypted with the previous one is lost and
+    /// sync engines need to reset their local state.
+    KeysChanged,
+}
+
+impl AccountEvent {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.into())
+    }
+}
+
+pub struct EventCallback {
+    callback_fn: Box<Fn(&AccountEvent) + Send + RefUnwindSafe>,
+}
+
+impl EventCallback {
+    pub fn new<F>(callback_fn: F) -> EventCallback
+    where
+        F: Fn(&AccountEvent) + 'static + Send + RefUnwindSafe,
+    {
+        EventCallback {
+            callback_fn: Box::new(callback_fn),
+        }
+    }
+
+    pub fn call(&self, event: &AccountEvent) {
+        (*self.callback_fn)(event);
+    }
+}
+
+/// What the auth server told us about our session in `check_authorization_status`.
+#[cfg(feature = "browserid")]
+pub struct AuthorizationStatus {
+    pub active: bool,
+    pub verified: bool,
+}
+
+#[cfg(feature = "browserid")]
+impl AuthorizationStatus {
+    fn inactive() -> AuthorizationStatus {
+        AuthorizationStatus {
+            active: false,
+            verified: false,
+        }
+    }
+}
+
 impl FirefoxAccount {
     fn from_state(state: StateV1) -> FirefoxAccount {
         FirefoxAccount {
             state,
             flow_store: HashMap::new(),
             persist_callback: None,
+            event_callback: None,
+            metrics_callback: None,
             profile_cache: None,
         }
     }
@@ -154,6 +259,8 @@ impl FirefoxAccount {
             config,
             #[cfg(feature = "browserid")]
             login_state: Unknown,
+            #[cfg(feature = "browserid")]
+            key_fingerprint: None,
             oauth_cache: HashMap::new(),
         })
     }
@@ -167,9 +274,22 @@ impl FirefoxAccount {
         redirect_uri: &str,
         credentials: WebChannelResponse,
     ) -> Result<FirefoxAccount> {
-        let session_token = hex::decode(credentials.session_token)?;
-        let key_fetch_token = hex::decode(credentials.key_fetch_token)?;
-        let unwrap_kb = hex::decode(credentials.unwrap_kb)?;
+        let login_state = FirefoxAccount::login_state_from_credentials(credentials)?;
+        Ok(FirefoxAccount::from_state(StateV1 {
+            client_id: client_id.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            config,
+            login_state,
+            key_fingerprint: None,
+            oauth_cache: HashMap::new(),
+        }))
+    }
+
+    #[cfg(feature = "browserid")]
+    fn login_state_from_credentials(credentials: WebChannelResponse) -> Result<LoginState> {
+        let session_token = hex::decode(&*credentials.session_token)?;
+        let key_fetch_token = hex::decode(&*credentials.key_fetch_token)?;
+        let unwrap_kb = SecretBytes::new(hex::decode(&*credentials.unwrap_kb)?);
         let login_state_data = ReadyForKeysState::new(
             credentials.uid,
             credentials.email,
@@ -177,19 +297,19 @@ impl FirefoxAccount {
             key_fetch_token,
             unwrap_kb,
         );
-        let login_state = if credentials.verified {
+        Ok(FirefoxAccount::engaged_state(
+            login_state_data,
+            credentials.verified,
+        ))
+    }
+
+    #[cfg(feature = "browserid")]
+    fn engaged_state(login_state_data: ReadyForKeysState, verified: bool) -> LoginState {
+        if verified {
             EngagedAfterVerified(login_state_data)
         } else {
             EngagedBeforeVerified(login_state_data)
-        };
-
-        Ok(FirefoxAccount::from_state(StateV1 {
-            client_id: client_id.to_string(),
-            redirect_uri: redirect_uri.to_string(),
-            config,
-            login_state,
-            oauth_cache: HashMap::new(),
-        }))
+        }
     }
 
     pub fn from_json(data: &str) -> Result<FirefoxAccount> {
@@ -200,7 +320,7 @@ impl FirefoxAccount {
     }
 
     pub fn to_json(&self) -> Result<String> {
-        let state = State::V1(self.state.clone());
+        let state = StateRef::V1(&self.state);
         serde_json::to_string(&state).map_err(|e| e.into())
     }
 
@@ -215,10 +335,113 @@ impl FirefoxAccount {
 
     #[cfg(feature = "browserid")]
     pub fn advance(&mut self) {
-        let client = Client::new(&self.state.config);
-        let state_machine = LoginStateMachine::new(client);
-        let state = mem::replace(&mut self.state.login_state, Unknown);
-        self.state.login_state = state_machine.advance(state);
+        // The state machine never fails, it stays put on network errors.
+        let _ = self.advance_async().wait();
+    }
+
+    /// Same as `advance`, without blocking the calling thread on the server.
+    /// The login state is only replaced once each response is in, so
+    /// dropping the future doesn't lose it.
+    #[cfg(feature = "browserid")]
+    pub fn advance_async<'a>(&'a mut self) -> impl Future<Item = (), Error = Error> + 'a {
+        let was_separated = self.needs_reauth();
+        let from = self.state.login_state.name();
+        let config = self.state.config.clone();
+        future::loop_fn(self, move |fxa| {
+            let fetched =
+                LoginStateMachine::new(Client::new(&config)).fetch(&fxa.state.login_state);
+            fetched.map(move |fetched| {
+                if let Some(e) = fetched.error() {
+                    fxa.record_remote_error(e);
+                }
+                let state = mem::replace(&mut fxa.state.login_state, Unknown);
+                let discriminant = mem::discriminant(&state);
+                fxa.state.login_state = LoginStateMachine::advance_one(state, fetched);
+                if mem::discriminant(&fxa.state.login_state) == discriminant {
+                    Loop::Break(fxa)
+                } else {
+                    Loop::Continue(fxa)
+                }
+            })
+        }).map(move |fxa| {
+            let to = fxa.state.login_state.name();
+            if from != to {
+                fxa.maybe_call_metrics_callback(&MetricsEvent::LoginStateChanged { from, to });
+            }
+            if !was_separated && fxa.needs_reauth() {
+                fxa.maybe_call_event_callback(&AccountEvent::AuthorizationLost);
+            }
+            fxa.check_key_fingerprint();
+        })
+    }
+
+    // Remembers the fingerprint of the current kB, firing
+    // `AccountEvent::KeysChanged` if it differs from the previous one.
+    #[cfg(feature = "browserid")]
+    fn check_key_fingerprint(&mut self) {
+        let xcs = match self.state.login_state.xcs() {
+            Some(xcs) => xcs.to_string(),
+            None => return,
+        };
+        match self.state.key_fingerprint {
+            Some(ref previous) if *previous == xcs => return,
+            Some(_) => {
+                info!("kB changed, the sync key needs to be rotated.");
+                self.maybe_call_event_callback(&AccountEvent::KeysChanged);
+            }
+            None => {}
+        }
+        self.state.key_fingerprint = Some(xcs);
+        self.maybe_call_persist_callback();
+    }
+
+    /// Whether we know who the user is but lost their credentials, in which
+    /// case they need to sign in again.
+    #[cfg(feature = "browserid")]
+    pub fn needs_reauth(&self) -> bool {
+        match self.state.login_state {
+            Separated(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Asks the auth server whether our session token is still valid, and
+    /// whether it's verified.
+    ///
+    /// If the session was destroyed (e.g. the password was changed or the
+    /// device disconnected elsewhere) the account moves to the `Separated`
+    /// state and an `AccountEvent::AuthorizationLost` event is fired.
+    #[cfg(feature = "browserid")]
+    pub fn check_authorization_status(&mut self) -> Result<AuthorizationStatus> {
+        let session_token = {
+            let login_state = &self.state.login_state;
+            if login_state.base().is_none() {
+                return Err(ErrorKind::UnknownAccount.into());
+            }
+            if let Separated(_) = *login_state {
+                return Ok(AuthorizationStatus::inactive());
+            }
+            SecretBytes::from(self.session_token()?)
+        };
+        let session_status = Client::new(&self.state.config)
+            .session_status(&session_token)
+            .map_err(|e| self.record_error(e));
+        let session_status = match session_status {
+            Ok(session_status) => session_status,
+            Err(e) => match e.kind() {
+                ErrorKind::RemoteError { code: 401, .. } => {
+                    info!("Session token is no longer valid. Transitioning to Separated.");
+                    self.separate();
+                    self.maybe_call_event_callback(&AccountEvent::AuthorizationLost);
+                    return Ok(AuthorizationStatus::inactive());
+                }
+                _ => return Err(e),
+            },
+        };
+        Ok(AuthorizationStatus {
+            active: true,
+            verified: session_status.state == "verified",
+        })
     }
 
     fn oauth_cache_store(&mut self, info: &OAuthInfo) {
@@ -251,57 +474,124 @@ impl FirefoxAccount {
         None
     }
 
+    /// Marks the cached token for `scopes` as expired, e.g. because a server
+    /// rejected it, so that the next `get_oauth_token` call gets a new one.
+    pub fn invalidate_oauth_token(&mut self, scopes: &[&str]) {
+        let scope_key = match self.oauth_cache_find(scopes) {
+            Some(info) => info.scopes.join(" "),
+            None => return,
+        };
+        if let Some(info) = self.state.oauth_cache.get_mut(&scope_key) {
+            info.expires_at = 0;
+        }
+        self.maybe_call_persist_callback();
+    }
+
     pub fn get_oauth_token(&mut self, scopes: &[&str]) -> Result<Option<OAuthInfo>> {
-        let mut refresh_token = None;
-        if let Some(cached_oauth_info) = self.oauth_cache_find(scopes) {
-            if cached_oauth_info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT {
-                return Ok(Some(cached_oauth_info.clone()));
-            }
-            refresh_token = cached_oauth_info.refresh_token.clone();
+        self.get_oauth_token_async(scopes).wait()
+    }
+
+    /// Same as `get_oauth_token`, without blocking the calling thread on the
+    /// server.
+    pub fn get_oauth_token_async<'a>(
+        &'a mut self,
+        scopes: &[&str],
+    ) -> impl Future<Item = Option<OAuthInfo>, Error = Error> + 'a {
+        self.fetch_oauth_token(scopes).map(|(_, oauth_info)| oauth_info)
+    }
+
+    // Hands the account back along with the token, so that callers can chain
+    // more requests.
+    fn fetch_oauth_token<'a>(
+        &'a mut self,
+        scopes: &[&str],
+    ) -> impl Future<Item = (&'a mut FirefoxAccount, Option<OAuthInfo>), Error = Error> + 'a {
+        let previous_oauth_info = self.oauth_cache_find(scopes).cloned();
+        let is_fresh = previous_oauth_info.as_ref().map_or(false, |info| {
+            info.expires_at > util::now_secs() + OAUTH_MIN_TIME_LEFT
+        });
+        if is_fresh {
+            return Either::A(future::ok((self, previous_oauth_info)));
         }
-        // This is a bit awkward, borrow checker weirdness.
-        let resp;
-        {
-            if let Some(refresh_token) = refresh_token {
-                let client = Client::new(&self.state.config);
-                resp = client.oauth_token_with_refresh_token(
-                    &self.state.client_id,
-                    &refresh_token,
-                    &scopes,
-                )?;
-            } else {
-                #[cfg(feature = "browserid")]
-                {
-                    if let Some(session_token) =
-                        FirefoxAccount::session_token_from_state(&self.state.login_state)
-                    {
-                        let client = Client::new(&self.state.config);
-                        resp = client.oauth_token_with_session_token(
-                            &self.state.client_id,
-                            session_token,
-                            &scopes,
-                        )?;
-                    } else {
-                        return Ok(None);
-                    }
-                }
-                #[cfg(not(feature = "browserid"))]
-                {
-                    return Ok(None);
+        let request = {
+            let refresh_token = previous_oauth_info
+                .as_ref()
+                .and_then(|info| info.refresh_token.as_ref());
+            match refresh_token {
+                Some(refresh_token) => {
+                    let client = Client::new(&self.state.config);
+                    let resp = client.oauth_token_with_refresh_token(
+                        &self.state.client_id,
+                        &refresh_token,
+                        &scopes,
+                    );
+                    Some((resp, "refresh_token"))
                 }
+                None => self
+                    .oauth_token_with_session_token(scopes)
+                    .map(|resp| (resp, "session_token")),
             }
-        }
-        Ok(Some(self.handle_oauth_token_response(resp, None)?))
+        };
+        let (resp, grant) = match request {
+            Some(request) => request,
+            None => return Either::A(future::ok((self, None))),
+        };
+        Either::B(resp.then(
+            move |resp| -> Result<(&'a mut FirefoxAccount, Option<OAuthInfo>)> {
+                let resp = resp.map_err(|e| self.record_error(e))?;
+                let oauth_info =
+                    self.handle_oauth_token_response(resp, None, previous_oauth_info)?;
+                self.maybe_call_metrics_callback(&MetricsEvent::TokenRefreshed {
+                    scopes: oauth_info.scopes.clone(),
+                    grant,
+                });
+                Ok((self, Some(oauth_info)))
+            },
+        ))
+    }
+
+    #[cfg(feature = "browserid")]
+    fn oauth_token_with_session_token(
+        &self,
+        scopes: &[&str],
+    ) -> Option<FxaFuture<OAuthTokenResponse>> {
+        let session_token = FirefoxAccount::session_token_from_state(&self.state.login_state)?;
+        let client = Client::new(&self.state.config);
+        Some(client.oauth_token_with_session_token(
+            &self.state.client_id,
+            session_token,
+            self.clock_skew(),
+            &scopes,
+        ))
+    }
+
+    #[cfg(not(feature = "browserid"))]
+    fn oauth_token_with_session_token(
+        &self,
+        _scopes: &[&str],
+    ) -> Option<FxaFuture<OAuthTokenResponse>> {
+        None
     }
 
     pub fn begin_oauth_flow(&mut self, scopes: &[&str], wants_keys: bool) -> Result<String> {
+        self.begin_oauth_flow_with_options(scopes, wants_keys, &OAuthFlowOptions::default())
+    }
+
+    /// Same as `begin_oauth_flow`, with control over the authorization page
+    /// shown to the user (see `OAuthFlowOptions`).
+    pub fn begin_oauth_flow_with_options(
+        &mut self,
+        scopes: &[&str],
+        wants_keys: bool,
+        options: &OAuthFlowOptions,
+    ) -> Result<String> {
         let state = FirefoxAccount::random_base64_url_string(16)?;
         let code_verifier = FirefoxAccount::random_base64_url_string(43)?;
         let code_challenge = digest::digest(&digest::SHA256, &code_verifier.as_bytes());
         let code_challenge = base64::encode_config(&code_challenge, base64::URL_SAFE_NO_PAD);
         let mut url = self.state.config.authorization_endpoint()?;
+        options.append_to_url(&mut url)?;
         url.query_pairs_mut()
-            .append_pair("action", "email")
             .append_pair("client_id", &self.state.client_id)
             .append_pair("redirect_uri", &self.state.redirect_uri)
             .append_pair("scope", &scopes.join(" "))
@@ -320,38 +610,106 @@ impl FirefoxAccount {
             }
             false => None,
         };
+        let flow_id_metric = options.metrics.iter().find(|&&(ref n, _)| n == "flow_id");
+        let flow_id = match flow_id_metric {
+            Some(&(_, ref flow_id)) => flow_id.clone(),
+            None => hex::encode(&FirefoxAccount::random_bytes(32)?),
+        };
+        self.maybe_call_metrics_callback(&MetricsEvent::OAuthFlowBegun {
+            flow_id: flow_id.clone(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            wants_keys,
+        });
         self.flow_store.insert(
             state.clone(), // Since state is supposed to be unique, we use it to key our flows.
             OAuthFlow {
                 scoped_keys_flow,
                 code_verifier,
+                flow_id,
             },
         );
         Ok(url.to_string())
     }
 
     pub fn complete_oauth_flow(&mut self, code: &str, state: &str) -> Result<OAuthInfo> {
-        let resp;
-        // Needs non-lexical borrow checking.
-        {
-            let flow = match self.flow_store.get(state) {
-                Some(flow) => flow,
-                None => return Err(ErrorKind::UnknownOAuthState.into()),
+        self.complete_oauth_flow_async(code, state).wait()
+    }
+
+    /// Same as `complete_oauth_flow`, without blocking the calling thread on
+    /// the server.
+    pub fn complete_oauth_flow_async<'a>(
+        &'a mut self,
+        code: &str,
+        state: &str,
+    ) -> impl Future<Item = OAuthInfo, Error = Error> + 'a {
+        let (flow_id, resp) = match self.flow_store.get(state) {
+            Some(flow) => {
+                let client = Client::new(&self.state.config);
+                let resp = client.oauth_token_with_code(
+                    &code,
+                    &flow.code_verifier,
+                    &self.state.client_id,
+                );
+                (flow.flow_id.clone(), resp)
+            }
+            None => return Either::A(future::err(ErrorKind::UnknownOAuthState.into())),
+        };
+        let state = state.to_string();
+        Either::B(resp.then(move |resp| {
+            let result = self.exchange_oauth_code(resp, &state);
+            let event = match result {
+                Ok(_) => MetricsEvent::OAuthFlowCompleted { flow_id },
+                Err(ref e) => MetricsEvent::OAuthFlowFailed {
+                    flow_id,
+                    errno: MetricsEvent::errno(e),
+                    reason: e.to_string(),
+                },
             };
-            let client = Client::new(&self.state.config);
-            resp = client.oauth_token_with_code(&code, &flow.code_verifier, &self.state.client_id)?;
-        }
+            self.maybe_call_metrics_callback(&event);
+            result
+        }))
+    }
+
+    fn exchange_oauth_code(
+        &mut self,
+        resp: Result<OAuthTokenResponse>,
+        state: &str,
+    ) -> Result<OAuthInfo> {
+        let resp = resp.map_err(|e| self.record_error(e))?;
         let oauth_flow = match self.flow_store.remove(state) {
             Some(oauth_flow) => oauth_flow,
             None => return Err(ErrorKind::UnknownOAuthState.into()),
         };
-        self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow)
+        self.handle_oauth_token_response(resp, oauth_flow.scoped_keys_flow, None)
     }
 
+    /// Finishes an OAuth flow given the full URL the user got redirected to,
+    /// which is checked against our redirect URI and for errors reported by
+    /// the authorization server.
+    pub fn complete_oauth_flow_from_redirect(&mut self, redirect_url: &str) -> Result<OAuthInfo> {
+        let (code, state) = match parse_oauth_redirect(&self.state.redirect_uri, redirect_url)? {
+            OAuthRedirect::Code { code, state } => (code, state),
+            OAuthRedirect::Error { error, state } => {
+                if let Some(flow) = state.and_then(|state| self.flow_store.remove(&state)) {
+                    self.maybe_call_metrics_callback(&MetricsEvent::OAuthFlowFailed {
+                        flow_id: flow.flow_id,
+                        errno: None,
+                        reason: error.clone(),
+                    });
+                }
+                return Err(ErrorKind::OAuthRedirectError(error).into());
+            }
+        };
+        self.complete_oauth_flow(&code, &state)
+    }
+
+    // `previous_oauth_info` is the expired token being replaced: refreshed tokens
+    // come without keys nor refresh token, so we keep using the previous ones.
     fn handle_oauth_token_response(
         &mut self,
         resp: OAuthTokenResponse,
         scoped_keys_flow: Option<ScopedKeysFlow>,
+        previous_oauth_info: Option<OAuthInfo>,
     ) -> Result<OAuthInfo> {
         let granted_scopes = resp.scope.split(" ").map(|s| s.to_string()).collect();
         // This assumes that if the server returns keys_jwe, the jwk argument is Some.
@@ -360,14 +718,17 @@ impl FirefoxAccount {
                 let scoped_keys_flow = scoped_keys_flow.expect(
                     "Insane state! If we are getting back a JWE this means we should have a JWK private key.",
                 );
-                Some(scoped_keys_flow.decrypt_keys_jwe(&jwe)?)
+                Some(scoped_keys_flow.decrypt_keys_jwe(&jwe)?.into())
             }
             None => {
                 if scoped_keys_flow.is_some() {
                     error!("Expected to get keys back alongside the token but the server didn't send them.");
                     return Err(ErrorKind::TokenWithoutKeys.into());
                 } else {
-                    None
+                    previous_oauth_info
+                        .as_ref()
+                        .and_then(|info| info.keys.as_ref())
+                        .map(|keys| SecretString::from(&**keys))
                 }
             }
         };
@@ -376,9 +737,12 @@ impl FirefoxAccount {
             .expect("Something is very wrong.");
         let expires_at = since_epoch.as_secs() + resp.expires_in;
         let oauth_info = OAuthInfo {
-            access_token: resp.access_token,
+            access_token: resp.access_token.into(),
             keys,
-            refresh_token: resp.refresh_token,
+            refresh_token: resp
+                .refresh_token
+                .map(SecretString::from)
+                .or_else(|| previous_oauth_info.and_then(|info| info.refresh_token)),
             expires_at,
             scopes: granted_scopes,
         };
@@ -388,9 +752,74 @@ impl FirefoxAccount {
     }
 
     fn random_base64_url_string(len: usize) -> Result<String> {
+        let out = FirefoxAccount::random_bytes(len)?;
+        Ok(base64::encode_config(&out, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn random_bytes(len: usize) -> Result<Vec<u8>> {
         let mut out = vec![0u8; len];
         RNG.fill(&mut out).map_err(|_| ErrorKind::RngFailure)?;
-        Ok(base64::encode_config(&out, base64::URL_SAFE_NO_PAD))
+        Ok(out)
+    }
+
+    /// Use the session token to obtain an OAuth authorization code for another
+    /// relying party (`params.client_id`), e.g. to log the user into a different
+    /// Mozilla service.
+    ///
+    /// The PKCE code challenge and the optional `keys_jwk` are generated by the
+    /// relying party, which will later exchange the code for tokens (and keys).
+    #[cfg(feature = "browserid")]
+    pub fn authorize_code_using_session_token(
+        &self,
+        params: AuthorizationParameters,
+    ) -> Result<AuthorizationResponse> {
+        oauth::Scope::from_string(&params.scopes.join(" "))?;
+        if params.code_challenge_method != "S256" {
+            return Err(
+                ErrorKind::UnsupportedCodeChallengeMethod(params.code_challenge_method).into(),
+            );
+        }
+        if let Some(ref keys_jwk) = params.keys_jwk {
+            scoped_keys::validate_keys_jwk(keys_jwk)?;
+        }
+        let client = Client::new(&self.state.config);
+        let resp = client
+            .oauth_authorization_code_with_session_token(
+                self.session_token()?,
+                self.clock_skew(),
+                &params,
+            )
+            .map_err(|e| self.record_error(e))?;
+        if resp.state != params.state {
+            error!("The server returned an authorization code for a different state.");
+            return Err(ErrorKind::UnknownOAuthState.into());
+        }
+        Ok(resp)
+    }
+
+    #[cfg(feature = "browserid")]
+    fn session_token(&self) -> Result<&[u8]> {
+        FirefoxAccount::session_token_from_state(&self.state.login_state)
+            .ok_or_else(|| ErrorKind::NoSessionToken.into())
+    }
+
+    // The difference between the server clock and ours, as measured when we
+    // got our certificate. We assume our clock is right until then.
+    #[cfg(feature = "browserid")]
+    fn clock_skew(&self) -> i64 {
+        match self.state.login_state {
+            Married(ref married) => married.clock_skew(),
+            _ => 0,
+        }
+    }
+
+    // Drops the session token, keeping what we know about the account so the
+    // user can sign in again.
+    #[cfg(feature = "browserid")]
+    fn separate(&mut self) {
+        let state = mem::replace(&mut self.state.login_state, Unknown);
+        self.state.login_state = state.to_separated();
+        self.maybe_call_persist_callback();
     }
 
     #[cfg(feature = "browserid")]
@@ -420,41 +849,71 @@ impl FirefoxAccount {
             key_pair,
             &certificate,
             audience,
+            married.clock_skew(),
         )?)
     }
 
-    pub fn get_profile(&mut self, ignore_cache: bool) -> Result<ProfileResponse> {
-        let profile_access_token = match self.get_oauth_token(&["profile"])? {
-            Some(token) => token.access_token,
-            None => return Err(ErrorKind::NoCachedToken("profile").into()),
-        };
-        let mut etag = None;
-        if let Some(ref cached_profile) = self.profile_cache {
-            if !ignore_cache && now() < cached_profile.cached_at + PROFILE_FRESHNESS_THRESHOLD {
-                return Ok(cached_profile.response.clone());
-            }
-            etag = Some(cached_profile.etag.clone());
+    /// Inspects the BrowserID certificate currently used to generate assertions.
+    /// Requires to be in a `Married` state.
+    #[cfg(feature = "browserid")]
+    pub fn get_certificate_info(&mut self) -> Result<CertificateInfo> {
+        match self.to_married() {
+            Some(married) => married.certificate_info(),
+            None => Err(ErrorKind::NotMarried.into()),
         }
-        let client = Client::new(&self.state.config);
-        match client.profile(&profile_access_token, etag)? {
-            Some(response_and_etag) => {
-                if let Some(etag) = response_and_etag.etag {
-                    self.profile_cache = Some(CachedResponse {
-                        response: response_and_etag.response.clone(),
-                        cached_at: now(),
-                        etag,
-                    });
-                }
-                Ok(response_and_etag.response)
-            }
-            None => match self.profile_cache {
-                Some(ref cached_profile) => Ok(cached_profile.response.clone()),
-                None => {
-                    error!("Insane state! We got a 304 without having a cached response.");
-                    Err(ErrorKind::UnrecoverableServerError.into())
+    }
+
+    pub fn get_profile(&mut self, ignore_cache: bool) -> Result<ProfileResponse> {
+        self.get_profile_async(ignore_cache).wait()
+    }
+
+    /// Same as `get_profile`, without blocking the calling thread on the
+    /// server.
+    pub fn get_profile_async<'a>(
+        &'a mut self,
+        ignore_cache: bool,
+    ) -> impl Future<Item = ProfileResponse, Error = Error> + 'a {
+        self.fetch_oauth_token(&["profile"])
+            .and_then(move |(fxa, oauth_info)| {
+                let profile_access_token = match oauth_info {
+                    Some(oauth_info) => oauth_info.access_token,
+                    None => {
+                        return Either::A(future::err(ErrorKind::NoCachedToken("profile").into()))
+                    }
+                };
+                let mut etag = None;
+                if let Some(ref cached_profile) = fxa.profile_cache {
+                    if !ignore_cache
+                        && now() < cached_profile.cached_at + PROFILE_FRESHNESS_THRESHOLD
+                    {
+                        return Either::A(future::ok(cached_profile.response.clone()));
+                    }
+                    etag = Some(cached_profile.etag.clone());
                 }
-            },
-        }
+                let resp = Client::new(&fxa.state.config).profile(&profile_access_token, etag);
+                Either::B(resp.then(move |resp| -> Result<ProfileResponse> {
+                    let response = resp.map_err(|e| fxa.record_error(e))?;
+                    match response {
+                        Some(response_and_etag) => {
+                            if let Some(etag) = response_and_etag.etag {
+                                fxa.profile_cache = Some(CachedResponse {
+                                    response: response_and_etag.response.clone(),
+                                    cached_at: now(),
+                                    etag,
+                                });
+                            }
+                            Ok(response_and_etag.response)
+                        }
+                        None => match fxa.profile_cache {
+                            Some(ref cached_profile) => Ok(cached_profile.response.clone()),
+                            None => {
+                                error!("Insane state! We got a 304 without having a cached response.");
+                                Err(ErrorKind::UnrecoverableServerError.into())
+                            }
+                        },
+                    }
+                }))
+            })
     }
 
     #[cfg(feature = "browserid")]
@@ -467,6 +926,417 @@ impl FirefoxAccount {
         Ok((sync_key, married.xcs().to_string()))
     }
 
+    /// Generates a new account recovery key, wraps kB with it and registers the
+    /// result with the auth server. Requires to be in a `Married` state.
+    ///
+    /// We don't keep kB around, so it is fetched again using the current
+    /// `password`, the same way `change_password` does, and zeroed as soon as
+    /// it has been wrapped.
+    ///
+    /// The returned (hex-encoded) recovery key is not stored anywhere: it must be
+    /// handed to the user, who will need it to restore kB after a password reset.
+    #[cfg(feature = "browserid")]
+    pub fn create_recovery_key(&mut self, password: &str) -> Result<String> {
+        let (uid, email, session_token) = {
+            let married = match self.to_married() {
+                Some(married) => married,
+                None => return Err(ErrorKind::NotMarried.into()),
+            };
+            (
+                married.base().uid().to_string(),
+                married.base().email().to_string(),
+                SecretBytes::from(married.session_token()),
+            )
+        };
+        let stretched_pwd = Client::quick_stretch_pwd(&email, password);
+        let recovery_key = RecoveryKey::generate(&*RNG)?;
+        let client = Client::new(&self.state.config);
+        let bundle = {
+            // Starting a password change hands us a key fetch token, and we
+            // simply never finish it.
+            let start = client
+                .password_change_start(&email, &Client::derive_auth_pwd(&stretched_pwd))
+                .map_err(|e| self.record_error(e))?;
+            let key_fetch_token = SecretBytes::new(hex::decode(start.key_fetch_token)?);
+            let kb = SecretBytes::from(
+                client
+                    .keys(&key_fetch_token)
+                    .wait()
+                    .map_err(|e| self.record_error(e))?
+                    .wrap_kb
+                    .xored_with(&Client::derive_unwrap_kb(&stretched_pwd))?,
+            );
+            recovery_key.wrap_kb(&uid, &kb, &*RNG)?
+            // `kb` is zeroed when it goes out of scope here.
+        };
+        client
+            .create_recovery_key(&session_token, &bundle.recovery_key_id, &bundle.recovery_data)
+            .map_err(|e| self.record_error(e))?;
+        Ok(recovery_key.to_hex())
+    }
+
+    /// Starts a password reset by emailing a verification code to the user.
+    /// Returns the (hex-encoded) password forgot token to be passed along with
+    /// that code to `verify_password_reset_code`.
+    pub fn send_password_reset_code(&self, email: &str) -> Result<String> {
+        let client = Client::new(&self.state.config);
+        Ok(client
+            .password_forgot_send_code(email)
+            .map_err(|e| self.record_error(e))?
+            .password_forgot_token)
+    }
+
+    /// Exchanges the verification code emailed by `send_password_reset_code`
+    /// for a (hex-encoded) account reset token.
+    #[cfg(feature = "browserid")]
+    pub fn verify_password_reset_code(
+        &self,
+        password_forgot_token: &str,
+        code: &str,
+    ) -> Result<String> {
+        let password_forgot_token = hex::decode(password_forgot_token)?;
+        let client = Client::new(&self.state.config);
+        Ok(client
+            .password_forgot_verify_code(&password_forgot_token, code)
+            .map_err(|e| self.record_error(e))?
+            .account_reset_token)
+    }
+
+    /// Resets the account password and restores kB using an account recovery key
+    /// created earlier with `create_recovery_key`.
+    ///
+    /// Since kB is unchanged, so are the Sync keys derived from it once the
+    /// state machine gets us back to `Married`. Note that the server deletes the
+    /// recovery key once it has been used.
+    #[cfg(feature = "browserid")]
+    pub fn reset_password_with_recovery_key(
+        &mut self,
+        account_reset_token: &str,
+        recovery_key: &str,
+        new_password: &str,
+    ) -> Result<()> {
+        let (uid, email) = match self.state.login_state.base() {
+            Some(base) => (base.uid().to_string(), base.email().to_string()),
+            None => return Err(ErrorKind::UnknownAccount.into()),
+        };
+        let account_reset_token = hex::decode(account_reset_token)?;
+        let recovery_key = RecoveryKey::from_hex(recovery_key)?;
+        let recovery_key_id = recovery_key.key_id(&uid)?;
+        let stretched_pwd = Client::quick_stretch_pwd(&email, new_password);
+        let unwrap_kb = Client::derive_unwrap_kb(&stretched_pwd);
+        let resp;
+        {
+            let client = Client::new(&self.state.config);
+            let recovery_data = client
+                .get_recovery_key(&account_reset_token, &recovery_key_id)
+                .map_err(|e| self.record_error(e))?
+                .recovery_data;
+            let kb = SecretBytes::from(recovery_key.unwrap_kb(&uid, &recovery_data)?);
+            let wrap_kb = kb.xored_with(&unwrap_kb)?;
+            resp = client
+                .account_reset(
+                    &account_reset_token,
+                    &Client::derive_auth_pwd(&stretched_pwd),
+                    &wrap_kb,
+                    Some(&recovery_key_id),
+                )
+                .map_err(|e| self.record_error(e))?;
+        }
+        let login_state_data = ReadyForKeysState::new(
+            resp.uid,
+            email,
+            hex::decode(resp.session_token)?,
+            hex::decode(resp.key_fetch_token)?,
+            unwrap_kb,
+        );
+        self.state.login_state = FirefoxAccount::engaged_state(login_state_data, resp.verified);
+        // Resetting the password revokes every OAuth token we had.
+        self.state.oauth_cache.clear();
+        self.profile_cache = None;
+        self.advance();
+        self.maybe_call_persist_callback();
+        Ok(())
+    }
+
+    /// Changes the account password without losing kB (and therefore the Sync
+    /// data encrypted with it).
+    ///
+    /// kB is unwrapped with the old password and re-wrapped with the new one.
+    /// The server keeps our session alive and hands us new tokens, which we
+    /// use to update the login state in place before fetching the keys again.
+    #[cfg(feature = "browserid")]
+    pub fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<()> {
+        let (email, session_token) = {
+            let login_state = &self.state.login_state;
+            let email = match login_state.base() {
+                Some(base) => base.email().to_string(),
+                None => return Err(ErrorKind::UnknownAccount.into()),
+            };
+            (email, SecretBytes::from(self.session_token()?))
+        };
+        let old_stretched_pwd = Client::quick_stretch_pwd(&email, old_password);
+        let new_stretched_pwd = Client::quick_stretch_pwd(&email, new_password);
+        let new_unwrap_kb = Client::derive_unwrap_kb(&new_stretched_pwd);
+        let resp;
+        {
+            let client = Client::new(&self.state.config);
+            let start = client
+                .password_change_start(&email, &Client::derive_auth_pwd(&old_stretched_pwd))
+                .map_err(|e| self.record_error(e))?;
+            let key_fetch_token = hex::decode(start.key_fetch_token)?;
+            let password_change_token = hex::decode(start.password_change_token)?;
+            let kb = SecretBytes::from(
+                client
+                    .keys(&key_fetch_token)
+                    .wait()
+                    .map_err(|e| self.record_error(e))?
+                    .wrap_kb
+                    .xored_with(&Client::derive_unwrap_kb(&old_stretched_pwd))?,
+            );
+            let wrap_kb = kb.xored_with(&new_unwrap_kb)?;
+            resp = client
+                .password_change_finish(
+                    &password_change_token,
+                    &Client::derive_auth_pwd(&new_stretched_pwd),
+                    &wrap_kb,
+                    &session_token,
+                )
+                .map_err(|e| self.record_error(e))?;
+        }
+        let login_state_data = ReadyForKeysState::new(
+            resp.uid,
+            email,
+            hex::decode(resp.session_token)?,
+            hex::decode(resp.key_fetch_token)?,
+            new_unwrap_kb,
+        );
+        self.state.login_state = FirefoxAccount::engaged_state(login_state_data, resp.verified);
+        self.advance();
+        self.maybe_call_persist_callback();
+        Ok(())
+    }
+
+    /// Handles a message sent by the content server over the fxaccounts
+    /// WebChannel (the `detail` of a `WebChannelMessageToChrome` event), so the
+    /// content server can be embedded to sign in, change the password, etc.
+    ///
+    /// Returns the message to post back to the content server, if the command
+    /// expects a reply.
+    #[cfg(feature = "browserid")]
+    pub fn handle_web_channel_message(&mut self, json: &str) -> Result<Option<String>> {
+        let (header, command) = web_channel::parse_message(json)?;
+        let command = match command {
+            Some(command) => command,
+            None => {
+                debug!("Ignoring WebChannel command {}.", header.command());
+                return Ok(None);
+            }
+        };
+        match command {
+            WebChannelCommand::CanLinkAccount { email } => {
+                let ok = match self.state.login_state.base() {
+                    Some(base) => base.email().to_lowercase() == email.to_lowercase(),
+                    None => true,
+                };
+                return Ok(Some(header.reply(json!({ "ok": ok }))));
+            }
+            WebChannelCommand::Login(credentials) => {
+                if !self.is_current_account(&credentials.uid) {
+                    // The OAuth tokens and keys we have belong to someone else.
+                    self.state.oauth_cache.clear();
+                    self.state.key_fingerprint = None;
+                }
+                self.state.login_state = FirefoxAccount::login_state_from_credentials(credentials)?;
+                self.profile_cache = None;
+                self.advance();
+            }
+            WebChannelCommand::ChangePassword(credentials) => {
+                if !self.is_current_account(&credentials.uid) {
+                    warn!("Password changed for another account, ignoring.");
+                    return Ok(None);
+                }
+                self.state.login_state = FirefoxAccount::login_state_from_credentials(credentials)?;
+                self.advance();
+            }
+            WebChannelCommand::DeleteAccount { uid } | WebChannelCommand::Logout { uid } => {
+                if !self.is_current_account(&uid) {
+                    return Ok(None);
+                }
+                self.state.login_state = Unknown;
+                self.state.key_fingerprint = None;
+                self.state.oauth_cache.clear();
+                self.profile_cache = None;
+            }
+            WebChannelCommand::ProfileChange { uid } => {
+                if uid.map_or(true, |uid| self.is_current_account(&uid)) {
+                    self.profile_cache = None;
+                }
+                return Ok(None);
+            }
+        }
+        self.maybe_call_persist_callback();
+        Ok(None)
+    }
+
+    #[cfg(feature = "browserid")]
+    fn is_current_account(&self, uid: &str) -> bool {
+        match self.state.login_state.base() {
+            Some(base) => base.uid() == uid,
+            None => false,
+        }
+    }
+
+    /// Lists everything connected to the account: web sessions, OAuth clients and
+    /// devices, merged by the server when they belong to the same client.
+    #[cfg(feature = "browserid")]
+    pub fn get_attached_clients(&self) -> Result<Vec<AttachedClient>> {
+        let client = Client::new(&self.state.config);
+        client
+            .attached_clients(self.session_token()?)
+            .map_err(|e| self.record_error(e))
+    }
+
+    /// Destroys a session of the account, given its `session_token_id` as
+    /// listed by `get_attached_clients`. Destroying our own session moves the
+    /// account to the `Separated` state.
+    #[cfg(feature = "browserid")]
+    pub fn destroy_session(&mut self, session_token_id: &str) -> Result<()> {
+        let is_current_session = {
+            let session_token = self.session_token()?;
+            let client = Client::new(&self.state.config);
+            client
+                .destroy_session(session_token, session_token_id)
+                .map_err(|e| self.record_error(e))?;
+            Client::session_token_id(session_token)? == session_token_id
+        };
+        if is_current_session {
+            self.separate();
+        }
+        Ok(())
+    }
+
+    /// Revokes every token held by the OAuth client `client_id`.
+    #[cfg(feature = "browserid")]
+    pub fn revoke_oauth_client(&self, client_id: &str) -> Result<()> {
+        let client = Client::new(&self.state.config);
+        client
+            .destroy_attached_oauth_client(self.session_token()?, client_id)
+            .map_err(|e| self.record_error(e))
+    }
+
+    /// Deletes a device record, which also destroys its session. Deleting our
+    /// own device moves the account to the `Separated` state.
+    #[cfg(feature = "browserid")]
+    pub fn delete_device(&mut self, device_id: &str) -> Result<()> {
+        let is_current_device = {
+            let session_token = self.session_token()?;
+            let client = Client::new(&self.state.config);
+            // We don't keep our device id around, so ask the server which
+            // device our session belongs to.
+            let is_current_device = client
+                .attached_clients(session_token)
+                .map_err(|e| self.record_error(e))?
+                .iter()
+                .any(|attached| {
+                    let id = attached.device_id.as_ref().map(|id| id.as_str());
+                    attached.is_current_session && id == Some(device_id)
+                });
+            client
+                .destroy_device(session_token, device_id)
+                .map_err(|e| self.record_error(e))?;
+            is_current_device
+        };
+        if is_current_device {
+            self.separate();
+        }
+        Ok(())
+    }
+
+    /// Lists the primary and secondary emails of the account.
+    #[cfg(feature = "browserid")]
+    pub fn get_emails(&self) -> Result<Vec<Email>> {
+        let client = Client::new(&self.state.config);
+        client
+            .recovery_emails(self.session_token()?)
+            .map_err(|e| self.record_error(e))
+    }
+
+    /// Adds a secondary email to the account. The server emails a verification
+    /// code to it, to be passed to `verify_secondary_email`.
+    #[cfg(feature = "browserid")]
+    pub fn add_secondary_email(&self, email: &str) -> Result<()> {
+        let client = Client::new(&self.state.config);
+        client
+            .create_secondary_email(self.session_token()?, email)
+            .map_err(|e| self.record_error(e))
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn resend_email_verification(&self, email: &str) -> Result<()> {
+        let client = Client::new(&self.state.config);
+        client
+            .resend_email_code(self.session_token()?, email)
+            .map_err(|e| self.record_error(e))
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn verify_secondary_email(&self, email: &str, code: &str) -> Result<()> {
+        let client = Client::new(&self.state.config);
+        client
+            .verify_secondary_email(self.session_token()?, email, code)
+            .map_err(|e| self.record_error(e))
+    }
+
+    /// Makes a verified secondary email the primary email of the account.
+    ///
+    /// Note that the email we keep in the login state is left untouched, since
+    /// the password is still stretched with the email the account was created with.
+    #[cfg(feature = "browserid")]
+    pub fn set_primary_email(&mut self, email: &str) -> Result<()> {
+        {
+            let client = Client::new(&self.state.config);
+            client
+                .set_primary_email(self.session_token()?, email)
+                .map_err(|e| self.record_error(e))?;
+        }
+        self.profile_cache = None;
+        Ok(())
+    }
+
+    #[cfg(feature = "browserid")]
+    pub fn delete_secondary_email(&self, email: &str) -> Result<()> {
+        let client = Client::new(&self.state.config);
+        client
+            .destroy_secondary_email(self.session_token()?, email)
+            .map_err(|e| self.record_error(e))
+    }
+
+    /// Permanently deletes the account after checking the user's `password`,
+    /// then tears down everything we kept locally about it (tokens, keys,
+    /// cached profile).
+    #[cfg(feature = "browserid")]
+    pub fn destroy_account(&mut self, password: &str) -> Result<()> {
+        {
+            let email = match self.state.login_state.base() {
+                Some(base) => base.email(),
+                None => return Err(ErrorKind::UnknownAccount.into()),
+            };
+            let stretched_pwd = Client::quick_stretch_pwd(email, password);
+            let session_token = FirefoxAccount::session_token_from_state(&self.state.login_state);
+            let client = Client::new(&self.state.config);
+            client
+                .destroy_account(email, &Client::derive_auth_pwd(&stretched_pwd), session_token)
+                .map_err(|e| self.record_error(e))?;
+        }
+        self.state.login_state = Unknown;
+        self.state.key_fingerprint = None;
+        self.state.oauth_cache.clear();
+        self.flow_store.clear();
+        self.profile_cache = None;
+        self.maybe_call_persist_callback();
+        Ok(())
+    }
+
     pub fn get_token_server_endpoint_url(&self) -> Result<Url> {
         self.state.config.token_server_endpoint_url()
     }
@@ -499,6 +1369,47 @@ impl FirefoxAccount {
         self.persist_callback = None;
     }
 
+    pub fn register_event_callback(&mut self, event_callback: EventCallback) {
+        self.event_callback = Some(event_callback);
+    }
+
+    pub fn unregister_event_callback(&mut self) {
+        self.event_callback = None;
+    }
+
+    pub fn register_metrics_callback(&mut self, metrics_callback: MetricsCallback) {
+        self.metrics_callback = Some(metrics_callback);
+    }
+
+    pub fn unregister_metrics_callback(&mut self) {
+        self.metrics_callback = None;
+    }
+
+    fn maybe_call_metrics_callback(&self, event: &MetricsEvent) {
+        if let Some(ref cb) = self.metrics_callback {
+            cb.call(event);
+        }
+    }
+
+    // Meant to be used as `.map_err(|e| self.record_error(e))` on server calls.
+    fn record_error(&self, error: Error) -> Error {
+        self.record_remote_error(&error);
+        error
+    }
+
+    fn record_remote_error(&self, error: &Error) {
+        if let ErrorKind::RemoteError { code, errno, .. } = *error.kind() {
+            self.maybe_call_metrics_callback(&MetricsEvent::RemoteError { code, errno });
+        }
+    }
+
+    #[cfg(feature = "browserid")]
+    fn maybe_call_event_callback(&self, event: &AccountEvent) {
+        if let Some(ref cb) = self.event_callback {
+            cb.call(event);
+        }
+    }
+
     fn maybe_call_persist_callback(&self) {
         if let Some(ref cb) = self.persist_callback {
             let json = match self.to_json() {
@@ -541,12 +1452,152 @@ mod tests {
         assert_eq!(fxa1_json, fxa2_json);
     }
 
+    #[test]
+    fn test_account_event_to_json() {
+        assert_eq!(
+            AccountEvent::AuthorizationLost.to_json().unwrap(),
+            r#"{"type":"AuthorizationLost"}"#
+        );
+        assert_eq!(
+            AccountEvent::KeysChanged.to_json().unwrap(),
+            r#"{"type":"KeysChanged"}"#
+        );
+    }
+
+    #[cfg(feature = "browserid")]
+    #[test]
+    fn test_keys_changed_event() {
+        use std::sync::{Arc, Mutex};
+
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        fxa.register_event_callback(EventCallback::new(move |event| {
+            recorded.lock().unwrap().push(event.clone());
+        }));
+        let cohabiting = |kb: &[u8]| {
+            CohabitingBeforeKeyPair(TokenAndKeysState::new(
+                BaseState::new("uid".to_string(), "foo@bar.com".to_string()),
+                vec![0u8; 32].into(),
+                kb.into(),
+            ))
+        };
+
+        // The first kB we see is only remembered.
+        fxa.state.login_state = cohabiting(&[1u8; 32]);
+        fxa.check_key_fingerprint();
+        assert!(events.lock().unwrap().is_empty());
+
+        // Fetching the same kB again, e.g. after signing in again, is fine.
+        fxa.state.login_state = cohabiting(&[1u8; 32]);
+        fxa.check_key_fingerprint();
+        assert!(events.lock().unwrap().is_empty());
+
+        fxa.state.login_state = cohabiting(&[2u8; 32]);
+        fxa.check_key_fingerprint();
+        assert_eq!(*events.lock().unwrap(), vec![AccountEvent::KeysChanged]);
+    }
+
+    #[test]
+    fn test_oauth_flow_options() {
+        let mut url = Url::parse("https://accounts.firefox.com/authorization").unwrap();
+        let options = OAuthFlowOptions {
+            action: OAuthAction::ForceAuth,
+            email: Some("foo@bar.com".to_string()),
+            max_age: Some(0),
+            metrics: vec![("utm_source".to_string(), "app".to_string())],
+            ..Default::default()
+        };
+        options.append_to_url(&mut url).unwrap();
+        assert_eq!(
+            url.query(),
+            Some("action=force_auth&email=foo%40bar.com&max_age=0&utm_source=app")
+        );
+
+        let options = OAuthFlowOptions {
+            action: OAuthAction::ForceAuth,
+            ..Default::default()
+        };
+        assert!(options.append_to_url(&mut url).is_err());
+
+        let options = OAuthFlowOptions {
+            metrics: vec![("client_id".to_string(), "evil".to_string())],
+            ..Default::default()
+        };
+        assert!(options.append_to_url(&mut url).is_err());
+    }
+
+    #[test]
+    fn test_oauth_flow_metrics() {
+        use std::sync::{Arc, Mutex};
+
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        fxa.register_metrics_callback(MetricsCallback::new(move |event| {
+            recorded.lock().unwrap().push(event.clone());
+        }));
+        let options = OAuthFlowOptions {
+            metrics: vec![("flow_id".to_string(), "abcd".to_string())],
+            ..Default::default()
+        };
+        let url = fxa
+            .begin_oauth_flow_with_options(&["profile"], false, &options)
+            .unwrap();
+        let url = Url::parse(&url).unwrap();
+        let (_, state) = url.query_pairs().find(|&(ref k, _)| k == "state").unwrap();
+        let redirect = format!("https://foo.bar/?error=access_denied&state={}", state);
+        assert!(fxa.complete_oauth_flow_from_redirect(&redirect).is_err());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                MetricsEvent::OAuthFlowBegun {
+                    flow_id: "abcd".to_string(),
+                    scopes: vec!["profile".to_string()],
+                    wants_keys: false,
+                },
+                MetricsEvent::OAuthFlowFailed {
+                    flow_id: "abcd".to_string(),
+                    errno: None,
+                    reason: "access_denied".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_oauth_redirect() {
+        let redirect_uri = "https://foo.bar/oauth/success";
+        let url = "https://foo.bar/oauth/success?code=abc&state=123";
+        match parse_oauth_redirect(redirect_uri, url).unwrap() {
+            OAuthRedirect::Code { code, state } => {
+                assert_eq!(code, "abc");
+                assert_eq!(state, "123");
+            }
+            _ => panic!("Expected a code"),
+        }
+        let url = "https://foo.bar/oauth/success?error=denied&state=123";
+        match parse_oauth_redirect(redirect_uri, url).unwrap() {
+            OAuthRedirect::Error { error, state } => {
+                assert_eq!(error, "denied");
+                assert_eq!(state, Some("123".to_string()));
+            }
+            _ => panic!("Expected an error"),
+        }
+        let url = "https://foo.bar/oauth/success?code=abc";
+        assert!(parse_oauth_redirect(redirect_uri, url).is_err());
+        let url = "https://evil.com/oauth/success?code=abc&state=123";
+        assert!(parse_oauth_redirect(redirect_uri, url).is_err());
+    }
+
     #[test]
     fn test_oauth_cache_store_and_find() {
         let mut fxa =
             FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
         let oauth_info = OAuthInfo {
-            access_token: "abcdef".to_string(),
+            access_token: "abcdef".into(),
             keys: None,
             refresh_token: None,
             expires_at: 1,
@@ -558,18 +1609,203 @@ mod tests {
         fxa.oauth_cache_store(&oauth_info);
         fxa.oauth_cache_find(&["profile"]).unwrap();
     }
+
+    #[test]
+    fn test_cached_oauth_token_async() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: None,
+            refresh_token: None,
+            expires_at: u64::max_value(),
+            scopes: vec!["profile".to_string()],
+        });
+        let oauth_info = fxa
+            .get_oauth_token_async(&["profile"])
+            .wait()
+            .unwrap()
+            .unwrap();
+        assert_eq!(&*oauth_info.access_token, "abcdef");
+    }
+
+    #[test]
+    fn test_invalidate_oauth_token() {
+        let mut fxa =
+            FirefoxAccount::new(Config::stable_dev().unwrap(), "12345678", "https://foo.bar");
+        fxa.oauth_cache_store(&OAuthInfo {
+            access_token: "abcdef".into(),
+            keys: None,
+            refresh_token: None,
+            expires_at: u64::max_value(),
+            scopes: vec!["profile".to_string()],
+        });
+        assert!(fxa.get_oauth_token(&["profile"]).unwrap().is_some());
+        fxa.invalidate_oauth_token(&["profile"]);
+        // Without a refresh token or a session, we can't get a new one.
+        assert!(fxa.get_oauth_token(&["profile"]).unwrap().is_none());
+    }
 }
 
 pub struct OAuthFlow {
     pub scoped_keys_flow: Option<ScopedKeysFlow>,
     pub code_verifier: String,
+    pub flow_id: String,
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+/// The page the authorization flow starts on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OAuthAction {
+    /// Let the content server decide between sign-in and sign-up from the email.
+    Email,
+    SignIn,
+    SignUp,
+    /// Sign in to the account of `OAuthFlowOptions::email`, and no other.
+    ForceAuth,
+}
+
+impl OAuthAction {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            OAuthAction::Email => "email",
+            OAuthAction::SignIn => "signin",
+            OAuthAction::SignUp => "signup",
+            OAuthAction::ForceAuth => "force_auth",
+        }
+    }
+}
+
+/// Optional parameters of the authorization URL built by
+/// `FirefoxAccount::begin_oauth_flow_with_options`.
+#[derive(Clone, Debug)]
+pub struct OAuthFlowOptions {
+    pub action: OAuthAction,
+    pub email: Option<String>,
+    pub login_hint: Option<String>,
+    pub entrypoint: Option<String>,
+    pub prompt: Option<String>,
+    pub max_age: Option<u64>,
+    /// UTM (`utm_*`) and flow metrics (`flow_id`, `flow_begin_time`...) parameters.
+    pub metrics: Vec<(String, String)>,
+}
+
+impl Default for OAuthFlowOptions {
+    fn default() -> OAuthFlowOptions {
+        OAuthFlowOptions {
+            action: OAuthAction::Email,
+            email: None,
+            login_hint: None,
+            entrypoint: None,
+            prompt: None,
+            max_age: None,
+            metrics: vec![],
+        }
+    }
+}
+
+impl OAuthFlowOptions {
+    fn append_to_url(&self, url: &mut Url) -> Result<()> {
+        if self.action == OAuthAction::ForceAuth && self.email.is_none() {
+            return Err(ErrorKind::ForceAuthWithoutEmail.into());
+        }
+        for &(ref name, _) in &self.metrics {
+            if !name.starts_with("utm_") && !name.starts_with("flow_") {
+                return Err(ErrorKind::InvalidMetricsParameter(name.clone()).into());
+            }
+        }
+        let mut query = url.query_pairs_mut();
+        query.append_pair("action", self.action.as_str());
+        if let Some(ref email) = self.email {
+            query.append_pair("email", email);
+        }
+        if let Some(ref login_hint) = self.login_hint {
+            query.append_pair("login_hint", login_hint);
+        }
+        if let Some(ref entrypoint) = self.entrypoint {
+            query.append_pair("entrypoint", entrypoint);
+        }
+        if let Some(ref prompt) = self.prompt {
+            query.append_pair("prompt", prompt);
+        }
+        if let Some(max_age) = self.max_age {
+            query.append_pair("max_age", &max_age.to_string());
+        }
+        for &(ref name, ref value) in &self.metrics {
+            query.append_pair(name, value);
+        }
+        Ok(())
+    }
+}
+
+enum OAuthRedirect {
+    Code {
+        code: String,
+        state: String,
+    },
+    Error {
+        error: String,
+        state: Option<String>,
+    },
+}
+
+fn parse_oauth_redirect(redirect_uri: &str, redirect_url: &str) -> Result<OAuthRedirect> {
+    let expected = Url::parse(redirect_uri)?;
+    let url = Url::parse(redirect_url)?;
+    if url.origin() != expected.origin() || url.path() != expected.path() {
+        return Err(ErrorKind::RedirectUriMismatch.into());
+    }
+    let mut code = None;
+    let mut state = None;
+    let mut error = None;
+    for (name, value) in url.query_pairs() {
+        match name.as_ref() {
+            "code" => code = Some(value.into_owned()),
+            "state" => state = Some(value.into_owned()),
+            "error" => error = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    if let Some(error) = error {
+        return Ok(OAuthRedirect::Error { error, state });
+    }
+    let code = code.ok_or_else(|| ErrorKind::MissingRedirectParameter("code"))?;
+    let state = state.ok_or_else(|| ErrorKind::MissingRedirectParameter("state"))?;
+    Ok(OAuthRedirect::Code { code, state })
+}
+
+/// Parameters of an authorization code request made on behalf of another
+/// relying party using `FirefoxAccount::authorize_code_using_session_token`.
+pub struct AuthorizationParameters {
+    pub client_id: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub state: String,
+    pub access_type: String,
+    pub code_challenge: String,
+    pub code_challenge_method: String,
+    pub keys_jwk: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct OAuthInfo {
-    pub access_token: String,
-    pub keys: Option<String>,
-    pub refresh_token: Option<String>,
+    pub access_token: SecretString,
+    pub keys: Option<SecretString>,
+    pub refresh_token: Option<SecretString>,
     pub expires_at: u64, // seconds since epoch
     pub scopes: Vec<String>,
 }
+
+// We hand out copies of the tokens we cache, so `OAuthInfo` copies its
+// secrets explicitly.
+impl Clone for OAuthInfo {
+    fn clone(&self) -> OAuthInfo {
+        let copy = |secret: &SecretString| SecretString::from(&**secret);
+        OAuthInfo {
+            access_token: copy(&self.access_token),
+            keys: self.keys.as_ref().map(&copy),
+            refresh_token: self.refresh_token.as_ref().map(&copy),
+            expires_at: self.expires_at,
+            scopes: self.scopes.clone(),
+        }
+    }
+}
@@ -2,41 +2,69 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use std;
+use futures::{future, Future};
 
 use errors::*;
+use http_client::browser_id::jwt_utils::{self, CertificateInfo};
 use http_client::browser_id::rsa::RSABrowserIDKeyPair;
 use http_client::*;
 use login_sm::LoginState::*;
+use secret::SecretBytes;
 use util::{now, Xorable};
 
 pub struct LoginStateMachine<'a> {
     client: Client<'a>,
 }
 
+/// What the server told us about a state, if we had to ask it anything to
+/// move on from it.
+pub enum Fetched {
+    Nothing,
+    Keys(Result<KeysResponse>),
+    Certificate(Result<SignResponse>),
+}
+
+impl Fetched {
+    pub fn error(&self) -> Option<&Error> {
+        match self {
+            Fetched::Keys(Err(e)) => Some(e),
+            Fetched::Certificate(Err(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl<'a> LoginStateMachine<'a> {
     pub fn new(client: Client<'a>) -> LoginStateMachine {
         LoginStateMachine { client }
     }
 
-    pub fn advance(&self, from: LoginState) -> LoginState {
-        let mut cur_state = from;
-        loop {
-            let cur_state_discriminant = std::mem::discriminant(&cur_state);
-            let new_state = self.advance_one(cur_state);
-            let new_state_discriminant = std::mem::discriminant(&new_state);
-            cur_state = new_state;
-            if cur_state_discriminant == new_state_discriminant {
-                break;
+    /// Starts the request `advance_one` needs to move on from `state`. This
+    /// doesn't take the state, so it stays in place while the request is in
+    /// flight.
+    pub fn fetch(&self, state: &LoginState) -> FxaFuture<Fetched> {
+        match state {
+            CohabitingAfterKeyPair(state) => {
+                debug!("Signing public key.");
+                let resp = self
+                    .client
+                    .sign(&state.token_and_keys.session_token, &state.key_pair);
+                Box::new(resp.then(|resp| Ok(Fetched::Certificate(resp))))
+            }
+            EngagedBeforeVerified(state) | EngagedAfterVerified(state) => {
+                debug!("Fetching keys.");
+                let resp = self.client.keys(&state.key_fetch_token);
+                Box::new(resp.then(|resp| Ok(Fetched::Keys(resp))))
             }
+            _ => Box::new(future::ok(Fetched::Nothing)),
         }
-        cur_state
     }
 
-    fn advance_one(&self, from: LoginState) -> LoginState {
+    /// Moves on from `from`, given what `fetch` got for it.
+    pub fn advance_one(from: LoginState, fetched: Fetched) -> LoginState {
         info!("advancing from state {:?}", from);
-        match from {
-            Married(state) => {
+        match (from, fetched) {
+            (Married(state), _) => {
                 let now = now();
                 debug!("Checking key pair and certificate freshness.");
                 if now > state.token_keys_and_key_pair.key_pair_expires_at {
@@ -50,7 +78,7 @@ impl<'a> LoginStateMachine<'a> {
                     Married(state) // same
                 }
             }
-            CohabitingBeforeKeyPair(state) => {
+            (CohabitingBeforeKeyPair(state), _) => {
                 debug!("Generating key pair.");
                 let key_pair = match Client::key_pair(2048) {
                     Ok(key_pair) => key_pair,
@@ -67,71 +95,75 @@ impl<'a> LoginStateMachine<'a> {
                 };
                 CohabitingAfterKeyPair(new_state)
             }
-            CohabitingAfterKeyPair(state) => {
-                debug!("Signing public key.");
-                let resp = self
-                    .client
-                    .sign(&state.token_and_keys.session_token, &state.key_pair);
-                match resp {
-                    Ok(resp) => {
-                        info!("Signed public key! Transitioning to Married.");
-                        let new_state = MarriedState {
-                            token_keys_and_key_pair: state,
-                            certificate: resp.certificate,
-                            certificate_expires_at: now() + 24 * 3600 * 1000,
+            (CohabitingAfterKeyPair(state), Fetched::Certificate(resp)) => match resp {
+                Ok(resp) => {
+                    info!("Signed public key! Transitioning to Married.");
+                    let now = now();
+                    // Express the certificate expiry in local time, so we don't keep using
+                    // it past its real expiry if our clock is behind the server's.
+                    let (certificate_expires_at, clock_skew) =
+                        match jwt_utils::parse_certificate(&resp.certificate) {
+                            Ok(info) => {
+                                let clock_skew = info.issued_at as i64 - now as i64;
+                                ((info.expires_at as i64 - clock_skew) as u64, clock_skew)
+                            }
+                            Err(e) => {
+                                warn!("Could not parse certificate: {:?}.", e);
+                                (now + 24 * 3600 * 1000, 0)
+                            }
                         };
-                        Married(new_state)
-                    }
-                    Err(e) => {
-                        if let ErrorKind::RemoteError { .. } = e.kind() {
-                            error!("Server error: {:?}. Transitioning to Separated.", e);
-                            Separated(state.token_and_keys.base)
-                        } else {
-                            error!(
-                                "Unknown error: ({:?}). Assuming transient, not transitioning.",
-                                e
-                            );
-                            CohabitingAfterKeyPair(state)
-                        }
+                    let new_state = MarriedState {
+                        token_keys_and_key_pair: state,
+                        certificate: resp.certificate,
+                        certificate_expires_at,
+                        clock_skew,
+                    };
+                    Married(new_state)
+                }
+                Err(e) => {
+                    if let ErrorKind::RemoteError { .. } = e.kind() {
+                        error!("Server error: {:?}. Transitioning to Separated.", e);
+                        Separated(state.token_and_keys.base)
+                    } else {
+                        error!(
+                            "Unknown error: ({:?}). Assuming transient, not transitioning.",
+                            e
+                        );
+                        CohabitingAfterKeyPair(state)
                     }
                 }
+            },
+            (EngagedBeforeVerified(state), Fetched::Keys(resp)) => {
+                LoginStateMachine::handle_ready_for_key_state(EngagedBeforeVerified, state, resp)
             }
-            EngagedBeforeVerified(state) => {
-                self.handle_ready_for_key_state(EngagedBeforeVerified, state)
-            }
-            EngagedAfterVerified(state) => {
-                self.handle_ready_for_key_state(EngagedAfterVerified, state)
+            (EngagedAfterVerified(state), Fetched::Keys(resp)) => {
+                LoginStateMachine::handle_ready_for_key_state(EngagedAfterVerified, state, resp)
             }
-            Separated(_) => from,
-            Unknown => from,
+            // Nothing was fetched for a state that needs it: stay put.
+            (from, _) => from,
         }
     }
 
     fn handle_ready_for_key_state<F: FnOnce(ReadyForKeysState) -> LoginState>(
-        &self,
         same: F,
         state: ReadyForKeysState,
+        resp: Result<KeysResponse>,
     ) -> LoginState {
-        debug!("Fetching keys.");
-        let resp = self.client.keys(&state.key_fetch_token);
         match resp {
             Ok(resp) => {
                 let kb = match resp.wrap_kb.xored_with(&state.unwrap_kb) {
-                    Ok(kb) => kb,
+                    Ok(kb) => SecretBytes::new(kb),
                     Err(_) => {
                         error!("Failed to unwrap keys response!  Transitioning to Separated.");
                         return same(state);
                     }
                 };
                 info!("Unwrapped keys response.  Transition to CohabitingBeforeKeyPair.");
-                let sync_key = Client::derive_sync_key(&kb);
-                let xcs = Client::compute_client_state(&kb);
-                CohabitingBeforeKeyPair(TokenAndKeysState {
-                    base: state.base,
-                    session_token: state.session_token.to_vec(),
-                    sync_key,
-                    xcs,
-                })
+                CohabitingBeforeKeyPair(TokenAndKeysState::new(
+                    state.base,
+                    state.session_token,
+                    kb,
+                ))
             }
             Err(e) => match e.kind() {
                 ErrorKind::RemoteError { errno: 104, .. } => {
@@ -154,7 +186,7 @@ impl<'a> LoginStateMachine<'a> {
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub enum LoginState {
     Married(MarriedState),
     CohabitingBeforeKeyPair(CohabitingBeforeKeyPairState),
@@ -165,11 +197,15 @@ pub enum LoginState {
     Unknown, // If a client never uses the session_token flows, we will be in this state.
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct MarriedState {
     token_keys_and_key_pair: TokenKeysAndKeyPairState,
     certificate: String,
+    // In local time.
     certificate_expires_at: u64,
+    // Server time minus local time, in ms.
+    #[serde(default)]
+    clock_skew: i64,
 }
 
 pub type CohabitingBeforeKeyPairState = TokenAndKeysState;
@@ -178,23 +214,23 @@ pub type EngagedBeforeVerifiedState = ReadyForKeysState;
 pub type EngagedAfterVerifiedState = ReadyForKeysState;
 pub type SeparatedState = BaseState;
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ReadyForKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    key_fetch_token: Vec<u8>,
-    unwrap_kb: Vec<u8>,
+    session_token: SecretBytes,
+    key_fetch_token: SecretBytes,
+    unwrap_kb: SecretBytes,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TokenAndKeysState {
     base: BaseState,
-    session_token: Vec<u8>,
-    sync_key: Vec<u8>,
+    session_token: SecretBytes,
+    sync_key: SecretBytes,
     xcs: String,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct TokenKeysAndKeyPairState {
     token_and_keys: TokenAndKeysState,
     key_pair: RSABrowserIDKeyPair,
@@ -213,17 +249,30 @@ impl ReadyForKeysState {
         email: String,
         session_token: Vec<u8>,
         key_fetch_token: Vec<u8>,
-        unwrap_kb: Vec<u8>,
+        unwrap_kb: SecretBytes,
     ) -> ReadyForKeysState {
         ReadyForKeysState {
-            base: BaseState { uid, email },
-            session_token,
-            key_fetch_token,
+            base: BaseState::new(uid, email),
+            session_token: session_token.into(),
+            key_fetch_token: key_fetch_token.into(),
             unwrap_kb,
         }
     }
 }
 
+impl TokenAndKeysState {
+    pub fn new(base: BaseState, session_token: SecretBytes, kb: SecretBytes) -> TokenAndKeysState {
+        let sync_key = Client::derive_sync_key(&kb);
+        let xcs = Client::compute_client_state(&kb);
+        TokenAndKeysState {
+            base,
+            session_token,
+            sync_key,
+            xcs,
+        }
+    }
+}
+
 pub trait SessionTokenState {
     fn session_token(&self) -> &[u8];
 }
@@ -252,13 +301,34 @@ impl SessionTokenState for MarriedState {
     }
 }
 
+impl BaseState {
+    pub fn new(uid: String, email: String) -> BaseState {
+        BaseState { uid, email }
+    }
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+    pub fn email(&self) -> &str {
+        &self.email
+    }
+}
+
 impl MarriedState {
+    pub fn base(&self) -> &BaseState {
+        &self.token_keys_and_key_pair.token_and_keys.base
+    }
     pub fn key_pair(&self) -> &RSABrowserIDKeyPair {
         &self.token_keys_and_key_pair.key_pair
     }
     pub fn certificate(&self) -> &str {
         &self.certificate
     }
+    pub fn certificate_info(&self) -> Result<CertificateInfo> {
+        jwt_utils::parse_certificate(&self.certificate)
+    }
+    pub fn clock_skew(&self) -> i64 {
+        self.clock_skew
+    }
     pub fn sync_key(&self) -> &[u8] {
         &self.token_keys_and_key_pair.token_and_keys.sync_key
     }
@@ -268,6 +338,39 @@ impl MarriedState {
 }
 
 impl LoginState {
+    pub fn base(&self) -> Option<&BaseState> {
+        match self {
+            Married(state) => Some(state.base()),
+            CohabitingBeforeKeyPair(state) => Some(&state.base),
+            CohabitingAfterKeyPair(state) => Some(&state.token_and_keys.base),
+            EngagedBeforeVerified(state) | EngagedAfterVerified(state) => Some(&state.base),
+            Separated(state) => Some(state),
+            Unknown => None,
+        }
+    }
+
+    /// The client state derived from kB, if we have fetched the keys.
+    pub fn xcs(&self) -> Option<&str> {
+        match self {
+            Married(state) => Some(state.xcs()),
+            CohabitingBeforeKeyPair(state) => Some(&state.xcs),
+            CohabitingAfterKeyPair(state) => Some(&state.token_and_keys.xcs),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Married(_) => "Married",
+            CohabitingBeforeKeyPair(_) => "CohabitingBeforeKeyPair",
+            CohabitingAfterKeyPair(_) => "CohabitingAfterKeyPair",
+            EngagedBeforeVerified(_) => "EngagedBeforeVerified",
+            EngagedAfterVerified(_) => "EngagedAfterVerified",
+            Separated(_) => "Separated",
+            Unknown => "Unknown",
+        }
+    }
+
     pub fn to_separated(self) -> LoginState {
         match self {
             Married(state) => Separated(state.token_keys_and_key_pair.token_and_keys.base),
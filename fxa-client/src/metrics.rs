@@ -0,0 +1,112 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::panic::RefUnwindSafe;
+
+use serde_json;
+
+use errors::*;
+
+/// Structured events describing what the account is doing, for the host app
+/// to forward to its own telemetry.
+///
+/// OAuth flow events carry the `flow_id` of the flow: the one passed in
+/// `OAuthFlowOptions::metrics` if any (so events line up with the content
+/// server's flow metrics), a random one otherwise.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event")]
+pub enum MetricsEvent {
+    OAuthFlowBegun {
+        flow_id: String,
+        scopes: Vec<String>,
+        wants_keys: bool,
+    },
+    OAuthFlowCompleted {
+        flow_id: String,
+    },
+    OAuthFlowFailed {
+        flow_id: String,
+        errno: Option<u64>,
+        reason: String,
+    },
+    /// A new access token was obtained for an already connected account,
+    /// `grant` being either `refresh_token` or `session_token`.
+    TokenRefreshed {
+        scopes: Vec<String>,
+        grant: &'static str,
+    },
+    LoginStateChanged {
+        from: &'static str,
+        to: &'static str,
+    },
+    /// The auth, OAuth or profile server returned an error.
+    RemoteError {
+        code: u64,
+        errno: u64,
+    },
+}
+
+impl MetricsEvent {
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|e| e.into())
+    }
+
+    /// The `errno` of `error` if it was returned by the server.
+    pub(crate) fn errno(error: &Error) -> Option<u64> {
+        match error.kind() {
+            ErrorKind::RemoteError { errno, .. } => Some(*errno),
+            _ => None,
+        }
+    }
+}
+
+pub struct MetricsCallback {
+    callback_fn: Box<Fn(&MetricsEvent) + Send + RefUnwindSafe>,
+}
+
+impl MetricsCallback {
+    pub fn new<F>(callback_fn: F) -> MetricsCallback
+    where
+        F: Fn(&MetricsEvent) + 'static + Send + RefUnwindSafe,
+    {
+        MetricsCallback {
+            callback_fn: Box::new(callback_fn),
+        }
+    }
+
+    pub fn call(&self, event: &MetricsEvent) {
+        (*self.callback_fn)(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_event_to_json() {
+        let event = MetricsEvent::OAuthFlowFailed {
+            flow_id: "abcd".to_string(),
+            errno: Some(110),
+            reason: "Invalid token".to_string(),
+        };
+        assert_eq!(
+            event.to_json().unwrap(),
+            r#"{"event":"OAuthFlowFailed","flow_id":"abcd","errno":110,"reason":"Invalid token"}"#
+        );
+    }
+
+    #[test]
+    fn test_errno() {
+        let error = Error::from(ErrorKind::RemoteError {
+            code: 401,
+            errno: 110,
+            error: "Unauthorized".to_string(),
+            message: "Invalid authentication token".to_string(),
+            info: "".to_string(),
+        });
+        assert_eq!(MetricsEvent::errno(&error), Some(110));
+        assert_eq!(MetricsEvent::errno(&ErrorKind::NotMarried.into()), None);
+    }
+}
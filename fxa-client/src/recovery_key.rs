@@ -0,0 +1,185 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use base64;
+use hex;
+use ring::aead;
+use ring::rand::SecureRandom;
+use serde_json;
+
+use errors::*;
+use http_client::Client;
+use secret::SecretBytes;
+
+const RECOVERY_KEY_LENGTH: usize = 16;
+const RECOVERY_KEY_ID_LENGTH: usize = 16;
+const ENCRYPTION_KEY_LENGTH: usize = 32;
+const IV_LENGTH: usize = 96 / 8;
+const AUTH_TAG_LENGTH: usize = 128 / 8;
+
+/// An account recovery key, which lets the user restore kB after a password reset.
+///
+/// kB is wrapped in a JWE (`dir` + `A256GCM`) keyed with a key derived from the
+/// recovery key and the account uid, then stored on the auth server under the
+/// recovery key id. Only the user knows the recovery key itself.
+pub struct RecoveryKey {
+    key: SecretBytes,
+}
+
+/// What gets registered with the auth server.
+pub struct RecoveryBundle {
+    pub recovery_key_id: String,
+    pub recovery_data: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RecoveryData {
+    #[serde(rename = "kB")]
+    kb: String,
+}
+
+impl RecoveryKey {
+    pub fn generate(rng: &SecureRandom) -> Result<RecoveryKey> {
+        let mut key = vec![0u8; RECOVERY_KEY_LENGTH];
+        rng.fill(&mut key).map_err(|_| ErrorKind::RngFailure)?;
+        Ok(RecoveryKey { key: key.into() })
+    }
+
+    pub fn from_hex(recovery_key: &str) -> Result<RecoveryKey> {
+        let key = hex::decode(recovery_key.trim())?;
+        if key.len() != RECOVERY_KEY_LENGTH {
+            return Err(
+                ErrorKind::BadKeyLength("recoveryKey", key.len(), RECOVERY_KEY_LENGTH).into(),
+            );
+        }
+        Ok(RecoveryKey { key: key.into() })
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(&self.key)
+    }
+
+    pub fn key_id(&self, uid: &str) -> Result<String> {
+        Ok(hex::encode(self.derive(
+            uid,
+            "fxa recovery fingerprint",
+            RECOVERY_KEY_ID_LENGTH,
+        )?))
+    }
+
+    pub fn wrap_kb(&self, uid: &str, kb: &[u8], rng: &SecureRandom) -> Result<RecoveryBundle> {
+        let recovery_key_id = self.key_id(uid)?;
+        let plaintext = serde_json::to_string(&RecoveryData {
+            kb: hex::encode(kb),
+        })?;
+        let protected_header = json!({
+            "alg": "dir",
+            "enc": "A256GCM",
+            "kid": recovery_key_id,
+        }).to_string();
+        let protected_header =
+            base64::encode_config(protected_header.as_bytes(), base64::URL_SAFE_NO_PAD);
+
+        let mut iv = vec![0u8; IV_LENGTH];
+        rng.fill(&mut iv).map_err(|_| ErrorKind::RngFailure)?;
+        let encryption_key = self.derive(uid, "fxa recovery encrypt key", ENCRYPTION_KEY_LENGTH)?;
+        let sealing_key = aead::SealingKey::new(&aead::AES_256_GCM, &encryption_key)
+            .map_err(|_| ErrorKind::KeyImportFailed)?;
+        let mut in_out = plaintext.into_bytes();
+        in_out.extend_from_slice(&[0u8; AUTH_TAG_LENGTH]);
+        let out_len = aead::seal_in_place(
+            &sealing_key,
+            &iv,
+            protected_header.as_bytes(),
+            &mut in_out,
+            AUTH_TAG_LENGTH,
+        ).map_err(|_| ErrorKind::AEADSealFailure)?;
+        let (ciphertext, auth_tag) = in_out[..out_len].split_at(out_len - AUTH_TAG_LENGTH);
+
+        let recovery_data = format!(
+            "{}..{}.{}.{}",
+            protected_header,
+            base64::encode_config(&iv, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(ciphertext, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(auth_tag, base64::URL_SAFE_NO_PAD),
+        );
+        Ok(RecoveryBundle {
+            recovery_key_id,
+            recovery_data,
+        })
+    }
+
+    pub fn unwrap_kb(&self, uid: &str, recovery_data: &str) -> Result<Vec<u8>> {
+        let segments: Vec<&str> = recovery_data.split(".").collect();
+        if segments.len() != 5 || segments[1].len() != 0 {
+            return Err(ErrorKind::InvalidRecoveryData.into());
+        }
+        let header = base64::decode_config(&segments[0], base64::URL_SAFE_NO_PAD)?;
+        let protected_header: serde_json::Value = serde_json::from_slice(&header)?;
+        if protected_header["alg"] != "dir" || protected_header["enc"] != "A256GCM" {
+            return Err(ErrorKind::InvalidRecoveryData.into());
+        }
+        let iv = base64::decode_config(&segments[2], base64::URL_SAFE_NO_PAD)?;
+        let ciphertext = base64::decode_config(&segments[3], base64::URL_SAFE_NO_PAD)?;
+        let auth_tag = base64::decode_config(&segments[4], base64::URL_SAFE_NO_PAD)?;
+        if iv.len() != IV_LENGTH || auth_tag.len() != AUTH_TAG_LENGTH {
+            return Err(ErrorKind::InvalidRecoveryData.into());
+        }
+
+        let encryption_key = self.derive(uid, "fxa recovery encrypt key", ENCRYPTION_KEY_LENGTH)?;
+        let opening_key = aead::OpeningKey::new(&aead::AES_256_GCM, &encryption_key)
+            .map_err(|_| ErrorKind::KeyImportFailed)?;
+        let mut in_out = ciphertext;
+        in_out.extend_from_slice(&auth_tag);
+        let plaintext =
+            aead::open_in_place(&opening_key, &iv, segments[0].as_bytes(), 0, &mut in_out)
+                .map_err(|_| ErrorKind::AEADOpenFailure)?;
+        let data: RecoveryData = serde_json::from_slice(plaintext)?;
+        Ok(hex::decode(data.kb)?)
+    }
+
+    fn derive(&self, uid: &str, info: &str, len: usize) -> Result<Vec<u8>> {
+        let salt = hex::decode(uid)?;
+        Ok(Client::derive_hkdf_sha256_key(
+            &self.key,
+            &salt,
+            info.as_bytes(),
+            len,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+
+    const UID: &str = "aeaa1725c7a24ff983c6295725d5fc9b";
+
+    #[test]
+    fn test_wrap_unwrap_kb() {
+        let rng = SystemRandom::new();
+        let kb = hex::decode("a095c51c1c6e384e8d5d8da4bcb2dba8f1d1e1d5add5bf67fd1c7a8fd46e5b3b")
+            .unwrap();
+        let recovery_key = RecoveryKey::generate(&rng).unwrap();
+        let bundle = recovery_key.wrap_kb(UID, &kb, &rng).unwrap();
+        assert_eq!(bundle.recovery_key_id, recovery_key.key_id(UID).unwrap());
+
+        let recovery_key = RecoveryKey::from_hex(&recovery_key.to_hex()).unwrap();
+        assert_eq!(
+            recovery_key.unwrap_kb(UID, &bundle.recovery_data).unwrap(),
+            kb
+        );
+
+        let wrong_key = RecoveryKey::generate(&rng).unwrap();
+        assert!(wrong_key.unwrap_kb(UID, &bundle.recovery_data).is_err());
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert!(RecoveryKey::from_hex("00112233445566778899aabbccddeeff").is_ok());
+        assert!(RecoveryKey::from_hex("00112233").is_err());
+        assert!(RecoveryKey::from_hex("not hex at all").is_err());
+    }
+}
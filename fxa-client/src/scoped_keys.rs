@@ -113,6 +113,28 @@ impl ScopedKeysFlow {
     }
 }
 
+/// Checks that `keys_jwk` (as sent in the `keys_jwk` authorization parameter, i.e.
+/// base64url-encoded) holds an uncompressed P-256 public key, in the same format
+/// `ScopedKeysFlow::generate_keys_jwk` produces.
+pub fn validate_keys_jwk(keys_jwk: &str) -> Result<()> {
+    let jwk = base64::decode_config(keys_jwk, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::InvalidKeysJwk)?;
+    let jwk: serde_json::Value =
+        serde_json::from_slice(&jwk).map_err(|_| ErrorKind::InvalidKeysJwk)?;
+    if jwk["kty"] != "EC" || jwk["crv"] != "P-256" {
+        return Err(ErrorKind::InvalidKeysJwk.into());
+    }
+    for coord in &["x", "y"] {
+        let value = jwk[*coord].as_str().ok_or_else(|| ErrorKind::InvalidKeysJwk)?;
+        let value = base64::decode_config(value, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| ErrorKind::InvalidKeysJwk)?;
+        if value.len() != 256 / 8 {
+            return Err(ErrorKind::InvalidKeysJwk.into());
+        }
+    }
+    Ok(())
+}
+
 fn to_32b_buf(n: u32) -> Vec<u8> {
     let mut buf = [0; 4];
     BigEndian::write_u32(&mut buf, n);
@@ -140,4 +162,21 @@ mod tests {
         let keys = flow.decrypt_keys_jwe(jwe).unwrap();
         assert_eq!(keys, "{\"https://identity.mozilla.com/apps/oldsync\":{\"kty\":\"oct\",\"scope\":\"https://identity.mozilla.com/apps/oldsync\",\"k\":\"8ek1VNk4sjrNP0DhGC4crzQtwmpoR64zHuFMHb4Tw-exR70Z2SSIfMSrJDTLEZid9lD05-hbA3n2Q4Esjlu1tA\",\"kid\":\"1526414944666-zgTjf5oXmPmBjxwXWFsDWg\"}}");
     }
+
+    #[test]
+    fn test_validate_keys_jwk() {
+        let json = "{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"ARvGIPJ5eIFdp6YTM-INVDqwfun2R9FfCUvXbH7QCIU\",\"y\":\"hk8gP0Po8nBh-WSiTsvsyesC5c1L6fGOEVuX8FHsvTs\"}";
+        let keys_jwk = base64::encode_config(&json, base64::URL_SAFE_NO_PAD);
+        assert!(validate_keys_jwk(&keys_jwk).is_ok());
+
+        let wrong_curve = json.replace("P-256", "P-384");
+        let keys_jwk = base64::encode_config(&wrong_curve, base64::URL_SAFE_NO_PAD);
+        assert!(validate_keys_jwk(&keys_jwk).is_err());
+
+        let short_coord = json.replace("ARvGIPJ5eIFdp6YTM-INVDqwfun2R9FfCUvXbH7QCIU", "ARvG");
+        let keys_jwk = base64::encode_config(&short_coord, base64::URL_SAFE_NO_PAD);
+        assert!(validate_keys_jwk(&keys_jwk).is_err());
+
+        assert!(validate_keys_jwk("not base64!").is_err());
+    }
 }
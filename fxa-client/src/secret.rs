@@ -0,0 +1,192 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Wrappers for secret material (tokens, keys) that get zeroed when dropped
+//! and never show up in `Debug` output.
+//!
+//! They can't be cloned, so every copy of a secret is an explicit `from` that
+//! gets zeroed in turn, and they compare in constant time.
+//!
+//! They serialize exactly like the type they wrap, so persisted state is
+//! unaffected.
+
+use std::fmt;
+use std::ops::Deref;
+
+use clear_on_drop::clear::Clear;
+use ring::constant_time;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Only the lengths leak, which aren't secret.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    constant_time::verify_slices_are_equal(a, b).is_ok()
+}
+
+#[derive(Default)]
+pub struct SecretBytes(Vec<u8>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> SecretBytes {
+        SecretBytes(bytes)
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> SecretBytes {
+        SecretBytes(bytes)
+    }
+}
+
+impl<'a> From<&'a [u8]> for SecretBytes {
+    fn from(bytes: &'a [u8]) -> SecretBytes {
+        SecretBytes(bytes.to_vec())
+    }
+}
+
+impl Drop for SecretBytes {
+    fn drop(&mut self) {
+        Clear::clear(&mut self.0[..]);
+    }
+}
+
+impl PartialEq for SecretBytes {
+    fn eq(&self, other: &SecretBytes) -> bool {
+        constant_time_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SecretBytes {}
+
+impl fmt::Debug for SecretBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretBytes(<{} bytes redacted>)", self.0.len())
+    }
+}
+
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SecretBytes, D::Error> {
+        Vec::<u8>::deserialize(deserializer).map(SecretBytes)
+    }
+}
+
+#[derive(Default)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(s: String) -> SecretString {
+        SecretString(s)
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for SecretString {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(s: String) -> SecretString {
+        SecretString(s)
+    }
+}
+
+impl<'a> From<&'a str> for SecretString {
+    fn from(s: &'a str) -> SecretString {
+        SecretString(s.to_string())
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // Zero bytes are valid UTF-8, so the string stays well-formed.
+        Clear::clear(unsafe { &mut self.0.as_mut_vec()[..] });
+    }
+}
+
+impl PartialEq for SecretString {
+    fn eq(&self, other: &SecretString) -> bool {
+        constant_time_eq(self.0.as_bytes(), other.0.as_bytes())
+    }
+}
+
+impl Eq for SecretString {}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretString(<redacted>)")
+    }
+}
+
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<SecretString, D::Error> {
+        String::deserialize(deserializer).map(SecretString)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_debug_is_redacted() {
+        let bytes = SecretBytes::new(vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(format!("{:?}", bytes), "SecretBytes(<4 bytes redacted>)");
+        let s = SecretString::from("hunter2");
+        assert!(!format!("{:?}", s).contains("hunter2"));
+    }
+
+    #[test]
+    fn test_eq() {
+        assert_eq!(SecretBytes::new(vec![1, 2, 3]), SecretBytes::from(&[1, 2, 3][..]));
+        assert_ne!(SecretBytes::new(vec![1, 2, 3]), SecretBytes::new(vec![1, 2, 4]));
+        assert_ne!(SecretBytes::new(vec![1, 2, 3]), SecretBytes::new(vec![1, 2]));
+        assert_eq!(SecretString::from("token"), SecretString::from("token".to_string()));
+        assert_ne!(SecretString::from("token"), SecretString::from("tokens"));
+    }
+
+    #[test]
+    fn test_serialization_is_transparent() {
+        let bytes = SecretBytes::new(vec![1, 2, 3]);
+        assert_eq!(serde_json::to_string(&bytes).unwrap(), "[1,2,3]");
+        let bytes: SecretBytes = serde_json::from_str("[1,2,3]").unwrap();
+        assert_eq!(&bytes[..], &[1, 2, 3]);
+        let s = SecretString::from("token");
+        assert_eq!(serde_json::to_string(&s).unwrap(), "\"token\"");
+        let s: SecretString = serde_json::from_str("\"token\"").unwrap();
+        assert_eq!(&*s, "token");
+    }
+}
@@ -0,0 +1,144 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde_json;
+
+use errors::*;
+use WebChannelResponse;
+
+/// The fxaccounts WebChannel messages we care about, as sent by the content server.
+pub enum WebChannelCommand {
+    CanLinkAccount { email: String },
+    Login(WebChannelResponse),
+    ChangePassword(WebChannelResponse),
+    DeleteAccount { uid: String },
+    Logout { uid: String },
+    ProfileChange { uid: Option<String> },
+}
+
+/// Everything needed to address a reply to a WebChannel message.
+pub struct WebChannelHeader {
+    channel_id: String,
+    message_id: Option<String>,
+    command: String,
+}
+
+impl WebChannelHeader {
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Builds the message to post back to the content server in reply.
+    pub fn reply(&self, data: serde_json::Value) -> String {
+        json!({
+            "id": self.channel_id,
+            "message": {
+                "command": self.command,
+                "messageId": self.message_id,
+                "data": data,
+            }
+        }).to_string()
+    }
+}
+
+#[derive(Deserialize)]
+struct WebChannelEnvelope {
+    id: String,
+    message: WebChannelMessage,
+}
+
+#[derive(Deserialize)]
+struct WebChannelMessage {
+    command: String,
+    #[serde(rename = "messageId")]
+    message_id: Option<String>,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct EmailData {
+    email: String,
+}
+
+#[derive(Deserialize)]
+struct UidData {
+    uid: Option<String>,
+}
+
+/// Parses a `WebChannelMessageToChrome` event detail. Commands we don't handle
+/// (e.g. `fxaccounts:loaded`) are returned as `None`.
+pub fn parse_message(json: &str) -> Result<(WebChannelHeader, Option<WebChannelCommand>)> {
+    let envelope: WebChannelEnvelope = serde_json::from_str(json)?;
+    let message = envelope.message;
+    let command = match message.command.as_str() {
+        "fxaccounts:can_link_account" => {
+            let data: EmailData = serde_json::from_value(message.data)?;
+            Some(WebChannelCommand::CanLinkAccount { email: data.email })
+        }
+        "fxaccounts:login" => Some(WebChannelCommand::Login(serde_json::from_value(
+            message.data,
+        )?)),
+        "fxaccounts:change_password" => Some(WebChannelCommand::ChangePassword(
+            serde_json::from_value(message.data)?,
+        )),
+        "fxaccounts:delete" | "fxaccounts:delete_account" => {
+            let data: UidData = serde_json::from_value(message.data)?;
+            data.uid.map(|uid| WebChannelCommand::DeleteAccount { uid })
+        }
+        "fxaccounts:logout" => {
+            let data: UidData = serde_json::from_value(message.data)?;
+            data.uid.map(|uid| WebChannelCommand::Logout { uid })
+        }
+        "profile:change" => {
+            let data: UidData = serde_json::from_value(message.data)?;
+            Some(WebChannelCommand::ProfileChange { uid: data.uid })
+        }
+        _ => None,
+    };
+    let header = WebChannelHeader {
+        channel_id: envelope.id,
+        message_id: message.message_id,
+        command: message.command,
+    };
+    Ok((header, command))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_can_link_account() {
+        let json = r#"{"id":"account_updates","message":{"command":"fxaccounts:can_link_account","data":{"email":"foo@bar.com"},"messageId":"123"}}"#;
+        let (header, command) = parse_message(json).unwrap();
+        match command {
+            Some(WebChannelCommand::CanLinkAccount { email }) => assert_eq!(email, "foo@bar.com"),
+            _ => panic!("Wrong command"),
+        }
+        let reply: serde_json::Value =
+            serde_json::from_str(&header.reply(json!({ "ok": true }))).unwrap();
+        assert_eq!(reply["id"], "account_updates");
+        assert_eq!(reply["message"]["command"], "fxaccounts:can_link_account");
+        assert_eq!(reply["message"]["messageId"], "123");
+        assert_eq!(reply["message"]["data"]["ok"], true);
+    }
+
+    #[test]
+    fn test_parse_login() {
+        let json = r#"{"id":"account_updates","message":{"command":"fxaccounts:login","data":{"uid":"aeaa1725c7a24ff983c6295725d5fc9b","email":"foo@bar.com","verified":true,"sessionToken":"00","keyFetchToken":"01","unwrapBKey":"02","customizeSync":false}}}"#;
+        match parse_message(json).unwrap().1 {
+            Some(WebChannelCommand::Login(credentials)) => assert!(credentials.verified),
+            _ => panic!("Wrong command"),
+        }
+    }
+
+    #[test]
+    fn test_parse_unknown_command() {
+        let json = r#"{"id":"account_updates","message":{"command":"fxaccounts:loaded"}}"#;
+        let (header, command) = parse_message(json).unwrap();
+        assert_eq!(header.command(), "fxaccounts:loaded");
+        assert!(command.is_none());
+    }
+}
@@ -21,27 +21,19 @@ extern crate fxa_client;
 
 use std::io::{self, Read, Write};
 use std::fs;
+use std::mem;
 use std::process;
 use std::collections::HashMap;
 use std::borrow::Cow;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use fxa_client::{FirefoxAccount, Config, OAuthInfo};
-use sync::{error, ServerTimestamp, OutgoingChangeset, Payload, Store};
+use fxa_client::{FirefoxAccount, Config};
+use sync::{error, ServerTimestamp, OutgoingChangeset, Payload, Store, SYNC_SCOPE};
 
 const CLIENT_ID: &str = "3c8bd3fe92e1ddf1";
 const REDIRECT_URI: &str = "http://localhost:13131/oauth/complete";
-const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
 
 
-#[derive(Debug, Deserialize)]
-struct ScopedKeyData {
-    k: String,
-    kty: String,
-    kid: String,
-    scope: String,
-}
-
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct PasswordRecord {
@@ -249,6 +241,12 @@ struct PasswordEngine {
     pub last_sync: ServerTimestamp,
     pub records: HashMap<String, PasswordRecord>,
     pub changes: HashMap<String, u64>,
+    // Reconciling needs every incoming change at once, so we keep the pages
+    // until they're all downloaded.
+    #[serde(skip)]
+    incoming: Vec<(Payload, ServerTimestamp)>,
+    #[serde(skip)]
+    incoming_timestamp: ServerTimestamp,
     // TODO: meta global stuff
 }
 
@@ -362,27 +360,37 @@ impl Store for PasswordEngine {
 
     fn apply_incoming(
         &mut self,
-        inbound: sync::IncomingChangeset
-    ) -> sync::Result<OutgoingChangeset> {
-        info!("Remote collection has {} changes", inbound.changes.len());
+        mut inbound: sync::IncomingChangeset
+    ) -> sync::Result<()> {
+        info!("Downloaded {} remote changes", inbound.changes.len());
+        self.incoming.append(&mut inbound.changes);
+        self.incoming_timestamp = inbound.timestamp;
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> sync::Result<OutgoingChangeset> {
+        let incoming = mem::replace(&mut self.incoming, Vec::new());
+        info!("Remote collection has {} changes", incoming.len());
 
         let (outbound_changes, last_sync) = self.get_unsynced_changes()?;
         info!("Local collection has {} changes", outbound_changes.len());
 
         let reconciled = Reconciliation::between(outbound_changes,
-                                                 inbound.changes,
-                                                 inbound.timestamp)?;
+                                                 incoming,
+                                                 self.incoming_timestamp)?;
 
         info!("Finished Reconciling: apply local {}, apply remote {}",
               reconciled.apply_as_incoming.len(),
               reconciled.apply_as_outgoing.len());
 
-        self.apply_reconciled_changes(&reconciled.apply_as_incoming[..], inbound.timestamp)?;
+        let incoming_timestamp = self.incoming_timestamp;
+        self.apply_reconciled_changes(&reconciled.apply_as_incoming[..], incoming_timestamp)?;
 
         Ok(OutgoingChangeset {
             changes: reconciled.apply_as_outgoing,
             timestamp: last_sync,
-            collection: "passwords".into()
+            collection: "passwords".into(),
+            ttl: None,
         })
     }
 
@@ -532,32 +540,19 @@ fn main() -> Result<(), failure::Error> {
     env_logger::init();
 
     let cfg = Config::import_from("https://oauth-sync.dev.lcip.org")?;
-    let tokenserver_url = cfg.token_server_endpoint_url()?;
 
     let mut acct = load_or_create_fxa_creds(cfg.clone())?;
-    let token: OAuthInfo;
-    match acct.get_oauth_token(&[SYNC_SCOPE])? {
-        Some(t) => token = t,
-        None => {
-            // The cached credentials did not have appropriate scope, sign in again.
-            println!("Credentials do not have appropriate scope, launching OAuth flow.");
-            acct = create_fxa_creds(cfg.clone())?;
-            token = acct.get_oauth_token(&[SYNC_SCOPE])?.unwrap();
-        }
+    if acct.get_oauth_token(&[SYNC_SCOPE])?.is_none() {
+        // The cached credentials did not have appropriate scope, sign in again.
+        println!("Credentials do not have appropriate scope, launching OAuth flow.");
+        acct = create_fxa_creds(cfg.clone())?;
     }
-    let keys: HashMap<String, ScopedKeyData> = serde_json::from_str(&token.keys.unwrap())?;
-    let key = keys.get(SYNC_SCOPE).unwrap();
-
-    let client = sync::Sync15StorageClient::new(sync::Sync15StorageClientInit {
-        key_id: key.kid.clone(),
-        access_token: token.access_token.clone(),
-        tokenserver_url,
-    })?;
+    let account_client = sync::AccountStorageClient::new(&mut acct)?;
+    let client = account_client.client();
+    let root_sync_key = account_client.root_key();
     let mut state = sync::GlobalState::default();
 
-    let root_sync_key = sync::KeyBundle::from_ksync_base64(&key.k)?;
-
-    let mut state_machine = sync::SetupStateMachine::for_readonly_sync(&client, &root_sync_key);
+    let mut state_machine = sync::SetupStateMachine::for_readonly_sync(client, root_sync_key);
     state = state_machine.to_ready(state)?;
     let engines_that_need_reset = state.engines_that_need_local_reset();
     if engines_that_need_reset.contains("passwords") {
@@ -0,0 +1,165 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Glue between a signed-in `fxa_client::FirefoxAccount` and the storage client.
+
+use std::collections::HashMap;
+
+use fxa_client::{FirefoxAccount, OAuthInfo};
+use reqwest::Url;
+use serde_json;
+
+use client::{Sync15StorageClient, Sync15StorageClientInit};
+use error::{self, ErrorKind};
+use key_bundle::KeyBundle;
+
+/// The OAuth scope giving access to Sync, and to its scoped key.
+pub const SYNC_SCOPE: &str = "https://identity.mozilla.com/apps/oldsync";
+
+#[derive(Deserialize)]
+struct ScopedKeyData {
+    k: String,
+    kid: String,
+}
+
+/// Everything needed to talk to the storage servers on behalf of an account.
+#[derive(Debug, PartialEq)]
+pub struct SyncAuthInfo {
+    pub client_init: Sync15StorageClientInit,
+    pub root_key: KeyBundle,
+}
+
+impl SyncAuthInfo {
+    /// Gets the Sync OAuth token of `account`, which transparently gets a new
+    /// one if the cached token has expired.
+    pub fn from_account(account: &mut FirefoxAccount) -> error::Result<SyncAuthInfo> {
+        let oauth_info = match account.get_oauth_token(&[SYNC_SCOPE])? {
+            Some(oauth_info) => oauth_info,
+            None => return Err(ErrorKind::NoSyncAuthorization.into()),
+        };
+        let tokenserver_url = account.get_token_server_endpoint_url()?;
+        SyncAuthInfo::from_oauth_info(&oauth_info, tokenserver_url)
+    }
+
+    pub fn from_oauth_info(
+        oauth_info: &OAuthInfo,
+        tokenserver_url: Url,
+    ) -> error::Result<SyncAuthInfo> {
+        let keys = match oauth_info.keys {
+            Some(ref keys) => keys,
+            None => return Err(ErrorKind::NoSyncKey.into()),
+        };
+        let mut keys: HashMap<String, ScopedKeyData> = serde_json::from_str(keys)?;
+        let key = match keys.remove(SYNC_SCOPE) {
+            Some(key) => key,
+            None => return Err(ErrorKind::NoSyncKey.into()),
+        };
+        Ok(SyncAuthInfo {
+            client_init: Sync15StorageClientInit {
+                key_id: key.kid,
+                access_token: oauth_info.access_token.to_string(),
+                tokenserver_url,
+            },
+            root_key: KeyBundle::from_ksync_base64(&key.k)?,
+        })
+    }
+}
+
+/// A storage client bound to an account, which gets rebuilt whenever the
+/// account's Sync credentials change (e.g. once the access token expired).
+pub struct AccountStorageClient {
+    auth_info: SyncAuthInfo,
+    client: Sync15StorageClient,
+}
+
+impl AccountStorageClient {
+    pub fn new(account: &mut FirefoxAccount) -> error::Result<AccountStorageClient> {
+        let auth_info = SyncAuthInfo::from_account(account)?;
+        let client = Sync15StorageClient::new(auth_info.client_init.clone())?;
+        Ok(AccountStorageClient { auth_info, client })
+    }
+
+    /// Runs `f` with fresh credentials: we refresh them first, which gets a
+    /// new access token if the cached one expired. If the token server still
+    /// rejects them (e.g. the token was revoked early), we drop the cached
+    /// token and try once more with a new one.
+    pub fn with_client<T, F>(&mut self, account: &mut FirefoxAccount, mut f: F) -> error::Result<T>
+    where
+        F: FnMut(&Sync15StorageClient, &KeyBundle) -> error::Result<T>,
+    {
+        self.refresh(account)?;
+        match f(&self.client, &self.auth_info.root_key) {
+            Err(ref e) if e.is_unauthorized() => {}
+            result => return result,
+        }
+        info!("The token server rejected our access token, getting a new one.");
+        account.invalidate_oauth_token(&[SYNC_SCOPE]);
+        self.refresh(account)?;
+        f(&self.client, &self.auth_info.root_key)
+    }
+
+    /// Returns true if the credentials changed, in which case the storage
+    /// client was replaced. `with_client` calls this for you.
+    pub fn refresh(&mut self, account: &mut FirefoxAccount) -> error::Result<bool> {
+        let auth_info = SyncAuthInfo::from_account(account)?;
+        if auth_info == self.auth_info {
+            return Ok(false);
+        }
+        info!("Sync credentials changed, recreating the storage client.");
+        self.client = Sync15StorageClient::new(auth_info.client_init.clone())?;
+        self.auth_info = auth_info;
+        Ok(true)
+    }
+
+    #[inline]
+    pub fn client(&self) -> &Sync15StorageClient {
+        &self.client
+    }
+
+    #[inline]
+    pub fn root_key(&self) -> &KeyBundle {
+        &self.auth_info.root_key
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fxa_client::secret::SecretString;
+
+    fn oauth_info(keys: Option<String>) -> OAuthInfo {
+        OAuthInfo {
+            access_token: "access".into(),
+            keys: keys.map(SecretString::from),
+            refresh_token: None,
+            expires_at: 0,
+            scopes: vec![SYNC_SCOPE.to_string()],
+        }
+    }
+
+    #[test]
+    fn test_from_oauth_info() {
+        let k = "QkN8xyMc5rBlwRzBj-sXu7j8W4Q7UWvoRsrGSK8dF8Y8jA3fc6ygcSeQfY1iEMYB1Wg8oNyyK3YB45rM0Hjx2w";
+        let keys = json!({
+            SYNC_SCOPE: {
+                "kty": "oct",
+                "scope": SYNC_SCOPE,
+                "k": k,
+                "kid": "1234-qqo",
+            }
+        }).to_string();
+        let url = Url::parse("https://token.services.mozilla.com/1.0/sync/1.5").unwrap();
+        let auth_info = SyncAuthInfo::from_oauth_info(&oauth_info(Some(keys)), url.clone()).unwrap();
+        assert_eq!(auth_info.client_init, Sync15StorageClientInit {
+            key_id: "1234-qqo".to_string(),
+            access_token: "access".to_string(),
+            tokenserver_url: url.clone(),
+        });
+        assert_eq!(auth_info.root_key, KeyBundle::from_ksync_base64(k).unwrap());
+
+        assert!(SyncAuthInfo::from_oauth_info(&oauth_info(None), url.clone()).is_err());
+        let other_keys = json!({ "profile": { "k": k, "kid": "1234-qqo" } }).to_string();
+        assert!(SyncAuthInfo::from_oauth_info(&oauth_info(Some(other_keys)), url).is_err());
+    }
+}
@@ -146,6 +146,11 @@ pub struct Payload {
 
     #[serde(flatten)]
     pub data: Map<String, JsonValue>,
+
+    /// The `sortindex` of the BSO holding this payload. This isn't part of
+    /// the encrypted payload; it's here so that stores can see and set it.
+    #[serde(skip)]
+    pub sortindex: Option<i32>,
 }
 
 // `#[serde(skip_if)]` only allows a function (not an expression).
@@ -159,7 +164,7 @@ impl Payload {
 
     #[inline]
     pub fn new_tombstone(id: String) -> Payload {
-        Payload { id, deleted: true, data: Map::new() }
+        Payload { id, deleted: true, data: Map::new(), sortindex: None }
     }
 
     #[inline]
@@ -181,7 +186,7 @@ impl Payload {
             id,
             collection,
             modified: 0.0.into(), // Doesn't matter.
-            sortindex: None, // Should we let consumer's set this?
+            sortindex: self.sortindex,
             ttl: None, // Should we let consumer's set this?
             payload: self,
         }
@@ -210,7 +215,7 @@ impl Payload {
 
 impl From<Payload> for JsonValue {
     fn from(cleartext: Payload) -> Self {
-        let Payload { mut data, id, deleted } = cleartext;
+        let Payload { mut data, id, deleted, .. } = cleartext;
         data.insert("id".to_string(), JsonValue::String(id.into()));
         if deleted {
             data.insert("deleted".to_string(), JsonValue::Bool(true));
@@ -278,7 +283,8 @@ impl EncryptedBso {
 
         let new_payload = serde_json::from_str(&cleartext)?;
 
-        let result = self.with_payload(new_payload);
+        let mut result: CleartextBso = self.with_payload(new_payload);
+        result.payload.sortindex = result.sortindex;
         Ok(result)
     }
 
@@ -409,5 +415,23 @@ mod tests {
         assert_eq!(serde_json::to_value(decrypted.payload).unwrap(), payload);
     }
 
+    #[test]
+    fn test_roundtrip_crypt_sortindex() {
+        let mut payload = Payload::from_json(json!({ "id": "aaaaaaaaaaaa" })).unwrap();
+        payload.sortindex = Some(100);
+        let orig_record = payload.into_bso("dummy".into());
+        assert_eq!(orig_record.sortindex, Some(100));
+
+        let keybundle = KeyBundle::new_random().unwrap();
+        let encrypted = orig_record.clone().encrypt(&keybundle).unwrap();
+        let val_rec = serde_json::to_value(&encrypted).unwrap();
+        assert_eq!(val_rec["sortindex"], json!(100));
+        assert!(!val_rec["payload"].as_str().unwrap().contains("sortindex"));
+
+        let decrypted = encrypted.decrypt(&keybundle).unwrap();
+        assert_eq!(decrypted.payload.sortindex, Some(100));
+        assert_eq!(decrypted, orig_record);
+    }
+
 
 }
@@ -2,6 +2,8 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::result;
+
 use bso_record::{EncryptedBso, Payload};
 use client::Sync15StorageClient;
 use error::{self, ErrorKind, Result};
@@ -15,13 +17,11 @@ pub struct RecordChangeset<Payload> {
     pub changes: Vec<Payload>,
     /// For GETs, the last sync timestamp that should be persisted after
     /// applying the records.
-    /// For POSTs, this is the XIUS timestamp.
     pub timestamp: ServerTimestamp,
     pub collection: String,
 }
 
 pub type IncomingChangeset = RecordChangeset<(Payload, ServerTimestamp)>;
-pub type OutgoingChangeset = RecordChangeset<Payload>;
 
 // TODO: use a trait to unify this with the non-json versions
 impl<T> RecordChangeset<T> {
@@ -38,16 +38,41 @@ impl<T> RecordChangeset<T> {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct OutgoingChangeset {
+    pub changes: Vec<Payload>,
+    /// The XIUS timestamp for the POSTs.
+    pub timestamp: ServerTimestamp,
+    pub collection: String,
+    /// How long the server should keep the records, in seconds.
+    pub ttl: Option<u32>,
+}
+
 impl OutgoingChangeset {
+    #[inline]
+    pub fn new(collection: String, timestamp: ServerTimestamp) -> OutgoingChangeset {
+        OutgoingChangeset {
+            changes: vec![],
+            timestamp,
+            collection,
+            ttl: None,
+        }
+    }
+
     pub fn encrypt(self, key: &KeyBundle) -> Result<Vec<EncryptedBso>> {
-        let RecordChangeset {
+        let OutgoingChangeset {
             changes,
             collection,
+            ttl,
             ..
         } = self;
         changes
             .into_iter()
-            .map(|change| change.into_bso(collection.clone()).encrypt(key))
+            .map(|change| {
+                let mut bso = change.into_bso(collection.clone());
+                bso.ttl = ttl;
+                bso.encrypt(key)
+            })
             .collect()
     }
 
@@ -62,23 +87,33 @@ impl OutgoingChangeset {
 }
 
 impl IncomingChangeset {
-    pub fn fetch(
+    /// Downloads the records changed since `since`, and hands them to
+    /// `on_page` one page at a time, so that we never hold the whole
+    /// collection in memory. Every page is timestamped with the collection's
+    /// last modified time.
+    pub fn fetch<F, E>(
         client: &Sync15StorageClient,
         state: &GlobalState,
         collection: String,
         since: ServerTimestamp,
-    ) -> Result<IncomingChangeset> {
-        let records = client.get_encrypted_records(&collection, since)?;
+        mut on_page: F,
+    ) -> result::Result<(), E>
+    where
+        F: FnMut(IncomingChangeset) -> result::Result<(), E>,
+        E: From<error::Error>,
+    {
         let timestamp = state.last_modified_or_zero(&collection);
-        let mut result = IncomingChangeset::new(collection, timestamp);
-        result.changes.reserve(records.len());
-        let key = state.key_for_collection(&result.collection)?;
-        for record in records {
-            // TODO: if we see a HMAC error, may need to update crypto/keys?
-            let decrypted = record.decrypt(&key)?;
-            result.changes.push(decrypted.into_timestamped_payload());
-        }
-        Ok(result)
+        let key = state.key_for_collection(&collection)?;
+        client.get_encrypted_records_paged(&collection, since, |records| {
+            let mut page = IncomingChangeset::new(collection.clone(), timestamp);
+            page.changes.reserve(records.len());
+            for record in records {
+                // TODO: if we see a HMAC error, may need to update crypto/keys?
+                let decrypted = record.decrypt(&key)?;
+                page.changes.push(decrypted.into_timestamped_payload());
+            }
+            on_page(page)
+        })
     }
 }
 
@@ -3,9 +3,10 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::cell::Cell;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use hyper::{Method};
+use hyper::{Method, StatusCode};
+use hyper::header::RetryAfter;
 use reqwest::{Client, Request, Response, Url, header::{self, Accept}};
 use serde;
 use serde_json;
@@ -14,7 +15,8 @@ use bso_record::{BsoRecord, EncryptedBso};
 use error::{self, ErrorKind};
 use record_types::MetaGlobalRecord;
 use request::{BatchPoster, CollectionRequest, InfoConfiguration, PostQueue, PostResponse,
-              PostResponseHandler, XIfUnmodifiedSince, XWeaveTimestamp, InfoCollections};
+              PostResponseHandler, RequestOrder, XIfUnmodifiedSince, XLastModified,
+              XWeaveBackoff, XWeaveNextOffset, XWeaveTimestamp, InfoCollections};
 use token;
 use util::ServerTimestamp;
 
@@ -32,17 +34,40 @@ pub trait SetupStorageClient {
     fn fetch_info_configuration(&self) -> error::Result<InfoConfiguration>;
     fn fetch_info_collections(&self) -> error::Result<InfoCollections>;
     fn fetch_meta_global(&self) -> error::Result<BsoRecord<MetaGlobalRecord>>;
-    fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()>;
+    /// Uploads `global`, only if `meta/global` hasn't changed since `xius`
+    /// if given. Returns the new modified time of the record.
+    fn put_meta_global(
+        &self,
+        xius: Option<ServerTimestamp>,
+        global: &BsoRecord<MetaGlobalRecord>,
+    ) -> error::Result<ServerTimestamp>;
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso>;
     fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()>;
     fn wipe_all_remote(&self) -> error::Result<()>;
+    /// Called after a `StorageResetError`: returns true if we were reassigned
+    /// to a new storage node, which is used from now on.
+    fn acknowledge_node_reassignment(&self) -> bool;
+    /// Fails with a `BackoffError` if the server asked us to back off.
+    fn check_backoff(&self) -> error::Result<()>;
 }
 
+// How long we back off for after a 503 that didn't come with a Retry-After.
+const DEFAULT_BACKOFF_SECS: u64 = 15 * 60;
+
+/// How many records we ask for at once when downloading a collection.
+pub const DOWNLOAD_PAGE_SIZE: usize = 1000;
+
 #[derive(Debug)]
 pub struct Sync15StorageClient {
     http_client: Client,
     // We update this when we make requests
     timestamp: Cell<ServerTimestamp>,
+    // Set when the server asks us to back off right away; every request
+    // fails until then.
+    backoff: Cell<Option<SystemTime>>,
+    // Set when the server asks us to back off once we're done syncing; we
+    // only check it when the next sync starts.
+    soft_backoff: Cell<Option<SystemTime>>,
     tsc: token::TokenProvider,
 }
 
@@ -69,8 +94,12 @@ impl SetupStorageClient for Sync15StorageClient {
         Ok(meta_global)
     }
 
-    fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
-        self.put("storage/meta/global", None, global)
+    fn put_meta_global(
+        &self,
+        xius: Option<ServerTimestamp>,
+        global: &BsoRecord<MetaGlobalRecord>,
+    ) -> error::Result<ServerTimestamp> {
+        self.put("storage/meta/global", xius, global)
     }
 
     fn fetch_crypto_keys(&self) -> error::Result<EncryptedBso> {
@@ -80,7 +109,8 @@ impl SetupStorageClient for Sync15StorageClient {
     }
 
     fn put_crypto_keys(&self, keys: &EncryptedBso) -> error::Result<()> {
-        self.put("storage/crypto/keys", None, keys)
+        self.put("storage/crypto/keys", None, keys)?;
+        Ok(())
     }
 
     fn wipe_all_remote(&self) -> error::Result<()> {
@@ -94,6 +124,14 @@ impl SetupStorageClient for Sync15StorageClient {
             Err(e) => Err(e)
         }
     }
+
+    fn acknowledge_node_reassignment(&self) -> bool {
+        self.tsc.acknowledge_node_reassignment()
+    }
+
+    fn check_backoff(&self) -> error::Result<()> {
+        Sync15StorageClient::check_backoff(self)
+    }
 }
 
 impl Sync15StorageClient {
@@ -108,6 +146,8 @@ impl Sync15StorageClient {
         Ok(Sync15StorageClient {
             http_client: client,
             timestamp: Cell::new(timestamp),
+            backoff: Cell::new(None),
+            soft_backoff: Cell::new(None),
             tsc,
         })
     }
@@ -117,16 +157,91 @@ impl Sync15StorageClient {
         return self.timestamp.get();
     }
 
-    pub fn get_encrypted_records(
+    /// If the server asked us to back off, the time until which we shouldn't
+    /// sync. Meant for the app's sync scheduler.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        let until = match (self.backoff.get(), self.soft_backoff.get()) {
+            (Some(hard), Some(soft)) => Some(if hard > soft { hard } else { soft }),
+            (hard, soft) => hard.or(soft),
+        };
+        until.and_then(|until| {
+            if until > SystemTime::now() { Some(until) } else { None }
+        })
+    }
+
+    /// Fails with a `BackoffError` if the server asked us to back off. Call
+    /// this before starting a sync: an `X-Weave-Backoff` on a successful
+    /// response lets the current sync finish, but not the next one.
+    pub fn check_backoff(&self) -> error::Result<()> {
+        match self.backoff_until() {
+            Some(until) => Err(ErrorKind::BackoffError(until).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Downloads the records changed since `since`, `DOWNLOAD_PAGE_SIZE` at a
+    /// time, handing every page to `on_page` as soon as we get it. Fails with
+    /// `DownloadInterrupted` if the collection changes while we're paging
+    /// through it. `on_page` can fail with its own error type, which stops
+    /// the download.
+    pub fn get_encrypted_records_paged<F, E>(
         &self,
         collection: &str,
         since: ServerTimestamp,
-    ) -> error::Result<Vec<EncryptedBso>> {
-        let mut resp = self.collection_request(
-            Method::Get,
-            CollectionRequest::new(collection).full().newer_than(since),
-        )?;
-        Ok(resp.json()?)
+        mut on_page: F,
+    ) -> Result<(), E>
+    where
+        F: FnMut(Vec<EncryptedBso>) -> Result<(), E>,
+        E: From<error::Error>,
+    {
+        let mut request = CollectionRequest::new(collection);
+        request.full()
+               .newer_than(since)
+               .sort_by(RequestOrder::Oldest)
+               .limit(DOWNLOAD_PAGE_SIZE);
+        let mut xius = None;
+        loop {
+            let (records, next_offset) = self.get_page(collection, &request, &mut xius)?;
+            on_page(records)?;
+            match next_offset {
+                Some(offset) => { request.offset(Some(offset)); }
+                None => return Ok(()),
+            }
+        }
+    }
+
+    // Fetches a single page of records, and returns it with the offset of the
+    // next one, if there's more.
+    fn get_page(
+        &self,
+        collection: &str,
+        request: &CollectionRequest,
+        xius: &mut Option<ServerTimestamp>,
+    ) -> error::Result<(Vec<EncryptedBso>, Option<String>)> {
+        let url = request.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?;
+        let mut req = self.build_request(Method::Get, url)?;
+        if let Some(ts) = *xius {
+            req.headers_mut().set(XIfUnmodifiedSince(ts));
+        }
+        let mut resp = self.exec_request(req, false)?;
+        if resp.status() == StatusCode::PreconditionFailed {
+            warn!("{} was modified during the download", collection);
+            return Err(ErrorKind::DownloadInterrupted.into());
+        }
+        if !resp.status().is_success() {
+            return Err(ErrorKind::StorageHttpError {
+                code: resp.status(),
+                route: resp.url().path().into(),
+            }.into());
+        }
+        // Every following page must come from the same version of the
+        // collection as the first one.
+        if xius.is_none() {
+            *xius = Some(resp.headers().get::<XLastModified>().map(|h| **h)
+                             .unwrap_or_else(|| self.last_server_time()));
+        }
+        let next_offset = resp.headers().get::<XWeaveNextOffset>().map(|h| (**h).clone());
+        Ok((resp.json()?, next_offset))
     }
 
     #[inline]
@@ -164,10 +279,38 @@ impl Sync15StorageClient {
     }
 
     fn exec_request(&self, req: Request, require_success: bool) -> error::Result<Response> {
+        if let Some(until) = self.backoff.get() {
+            if until > SystemTime::now() {
+                return Err(ErrorKind::BackoffError(until).into());
+            }
+        }
+
         let resp = self.http_client.execute(req)?;
 
         self.update_timestamp(resp.headers());
 
+        match backoff_from_response(resp.status(), resp.headers(), SystemTime::now()) {
+            Some(ServerBackoff::Now(until)) => {
+                warn!("Server requested backoff until {:?}", until);
+                let until = later(self.backoff.get(), until);
+                self.backoff.set(Some(until));
+                return Err(ErrorKind::BackoffError(until).into());
+            }
+            Some(ServerBackoff::BeforeNextSync(until)) => {
+                warn!("Server requested backoff after this sync, until {:?}", until);
+                self.soft_backoff.set(Some(later(self.soft_backoff.get(), until)));
+            }
+            None => {}
+        }
+
+        if resp.status() == StatusCode::Unauthorized {
+            // We might have been moved to another node. Fetch a new token
+            // right away: if it's for another node, this fails with a
+            // `StorageResetError`, so the caller starts over on the new node.
+            self.tsc.drop_token();
+            self.tsc.api_endpoint(&self.http_client)?;
+        }
+
         if require_success && !resp.status().is_success() {
             error!(
                 "HTTP error {} ({}) during storage request to {}",
@@ -182,20 +325,12 @@ impl Sync15StorageClient {
         }
 
         // TODO:
-        // - handle backoff
         // - x-weave-quota?
         // - ... almost certainly other things too...
 
         Ok(resp)
     }
 
-    fn collection_request(&self, method: Method, r: &CollectionRequest) -> error::Result<Response> {
-        self.make_storage_request(
-            method.clone(),
-            r.build_url(Url::parse(&self.tsc.api_endpoint(&self.http_client)?)?)?,
-        )
-    }
-
     fn fetch_info<T>(&self, path: &str) -> error::Result<T>
     where
         for<'a> T: serde::de::Deserialize<'a>,
@@ -233,7 +368,7 @@ impl Sync15StorageClient {
         relative_path: P,
         xius: Option<ServerTimestamp>,
         body: &B,
-    ) -> error::Result<()>
+    ) -> error::Result<ServerTimestamp>
     where
         P: AsRef<str>,
         B: serde::ser::Serialize,
@@ -249,9 +384,54 @@ impl Sync15StorageClient {
             req.headers_mut().set(XIfUnmodifiedSince(ts));
         }
         *req.body_mut() = Some(bytes.into());
-        let _ = self.exec_request(req, true)?;
+        let resp = self.exec_request(req, true)?;
 
-        Ok(())
+        Ok(resp.headers().get::<XLastModified>().map(|h| **h)
+               .unwrap_or_else(|| self.last_server_time()))
+    }
+}
+
+/// How the server asked us to back off.
+#[derive(Debug, PartialEq)]
+enum ServerBackoff {
+    /// A 503, or a `Retry-After`: we stop making requests right away.
+    Now(SystemTime),
+    /// An `X-Weave-Backoff` on any other response: we finish the current
+    /// sync, but don't start another one before then.
+    BeforeNextSync(SystemTime),
+}
+
+/// Returns the backoff implied by `Retry-After` (either as seconds or as an
+/// HTTP date), by `X-Weave-Backoff`, or by a 503 without either of them.
+fn backoff_from_response(
+    status: StatusCode,
+    headers: &header::Headers,
+    now: SystemTime,
+) -> Option<ServerBackoff> {
+    let retry_after = headers.get::<RetryAfter>().map(|h| match *h {
+        RetryAfter::Delay(delay) => now + delay,
+        RetryAfter::DateTime(date) => date.into(),
+    });
+    let weave_backoff = headers.get::<XWeaveBackoff>()
+        .map(|h| now + Duration::from_millis((**h * 1000f64) as u64));
+    match (retry_after, weave_backoff) {
+        (Some(until), _) => Some(ServerBackoff::Now(until)),
+        (None, Some(until)) if status == StatusCode::ServiceUnavailable => {
+            Some(ServerBackoff::Now(until))
+        }
+        (None, Some(until)) => Some(ServerBackoff::BeforeNextSync(until)),
+        (None, None) if status == StatusCode::ServiceUnavailable => {
+            Some(ServerBackoff::Now(now + Duration::from_secs(DEFAULT_BACKOFF_SECS)))
+        }
+        (None, None) => None,
+    }
+}
+
+// Never shorten a backoff we already know about.
+fn later(previous: Option<SystemTime>, until: SystemTime) -> SystemTime {
+    match previous {
+        Some(previous) if previous > until => previous,
+        _ => until,
     }
 }
 
@@ -286,3 +466,39 @@ impl<'a> BatchPoster for PostWrapper<'a> {
         Ok(PostResponse::from_response(&mut resp)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_from_response() {
+        let now = SystemTime::now();
+        let mut headers = header::Headers::new();
+        assert_eq!(backoff_from_response(StatusCode::Ok, &headers, now), None);
+        assert_eq!(backoff_from_response(StatusCode::ServiceUnavailable, &headers, now),
+                   Some(ServerBackoff::Now(now + Duration::from_secs(DEFAULT_BACKOFF_SECS))));
+
+        // X-Weave-Backoff can come along a successful response, and only
+        // applies to the next sync.
+        headers.set(XWeaveBackoff(60f64));
+        assert_eq!(backoff_from_response(StatusCode::Ok, &headers, now),
+                   Some(ServerBackoff::BeforeNextSync(now + Duration::from_secs(60))));
+        assert_eq!(backoff_from_response(StatusCode::ServiceUnavailable, &headers, now),
+                   Some(ServerBackoff::Now(now + Duration::from_secs(60))));
+
+        headers.set(RetryAfter::Delay(Duration::from_secs(30)));
+        assert_eq!(backoff_from_response(StatusCode::ServiceUnavailable, &headers, now),
+                   Some(ServerBackoff::Now(now + Duration::from_secs(30))));
+
+        let date = now + Duration::from_secs(120);
+        headers.set(RetryAfter::DateTime(date.into()));
+        match backoff_from_response(StatusCode::TooManyRequests, &headers, now) {
+            // HTTP dates only have a resolution of one second.
+            Some(ServerBackoff::Now(until)) => {
+                assert!(until <= date && until + Duration::from_secs(1) > date)
+            }
+            other => panic!("Unexpected backoff: {:?}", other),
+        }
+    }
+}
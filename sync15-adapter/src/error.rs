@@ -11,6 +11,8 @@ use openssl;
 use base64;
 use serde_json;
 use hawk;
+#[cfg(feature = "fxa-client")]
+use fxa_client;
 
 pub type Result<T> = result::Result<T, Error>;
 
@@ -48,6 +50,21 @@ impl Error {
             _ => false
         }
     }
+
+    /// Whether the token server rejected our OAuth token.
+    pub fn is_unauthorized(&self) -> bool {
+        match self.kind() {
+            ErrorKind::TokenserverHttpError(HttpStatusCode::Unauthorized) => true,
+            _ => false
+        }
+    }
+
+    pub fn is_precondition_failed(&self) -> bool {
+        match self.kind() {
+            ErrorKind::StorageHttpError { code: HttpStatusCode::PreconditionFailed, .. } => true,
+            _ => false
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -94,6 +111,9 @@ pub enum ErrorKind {
     #[fail(display = "The batch was not committed due to being interrupted")]
     BatchInterrupted,
 
+    #[fail(display = "The collection was modified while we were downloading it")]
+    DownloadInterrupted,
+
     // Do we want to record the concrete problems?
     #[fail(display = "Not all records were successfully uploaded")]
     RecordUploadFailed,
@@ -124,6 +144,12 @@ pub enum ErrorKind {
     #[fail(display = "Setup state machine disallowed state {}", _0)]
     DisallowedStateError(&'static str),
 
+    #[fail(display = "The account is not authorized to use Sync, an OAuth flow is required")]
+    NoSyncAuthorization,
+
+    #[fail(display = "The Sync OAuth token came without the Sync key")]
+    NoSyncKey,
+
     // Basically reimplement error_chain's foreign_links. (Ugh, this sucks)
 
     #[fail(display = "OpenSSL error: {}", _0)]
@@ -146,6 +172,10 @@ pub enum ErrorKind {
 
     #[fail(display = "Malformed URL error: {}", _0)]
     MalformedUrl(#[fail(cause)] reqwest::UrlError),
+
+    #[cfg(feature = "fxa-client")]
+    #[fail(display = "Firefox Account error: {}", _0)]
+    AccountError(#[fail(cause)] fxa_client::errors::Error),
 }
 
 macro_rules! impl_from_error {
@@ -175,6 +205,11 @@ impl_from_error! {
     (MalformedUrl, ::reqwest::UrlError)
 }
 
+#[cfg(feature = "fxa-client")]
+impl_from_error! {
+    (AccountError, ::fxa_client::errors::Error)
+}
+
 // ::hawk::Error uses error_chain, and so it's not trivially compatible with failure.
 // We have to box it inside a SyncError (which allows errors to be accessed from multiple
 // threads at the same time, which failure requires for some reason...).
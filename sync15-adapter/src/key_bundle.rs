@@ -9,8 +9,12 @@ use openssl::{self, symm};
 use openssl::hash::MessageDigest;
 use openssl::pkey::PKey;
 use openssl::sign::Signer;
+use clear_on_drop::clear::Clear;
+use std::fmt;
 
-#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+/// The keys are zeroed when the bundle is dropped, compared in constant time,
+/// and never show up in `Debug` output. Bundles can't be cloned, so there's
+/// only ever one copy of each key to zero.
 pub struct KeyBundle {
     enc_key: Vec<u8>,
     mac_key: Vec<u8>,
@@ -19,23 +23,28 @@ pub struct KeyBundle {
 impl KeyBundle {
 
     /// Construct a key bundle from the already-decoded encrypt and hmac keys.
-    /// Panics (asserts) if they aren't both 32 bytes.
-    pub fn new(enc: Vec<u8>, mac: Vec<u8>) -> Result<KeyBundle> {
-        if enc.len() != 32 {
+    /// Fails if they aren't both 32 bytes, in which case they're zeroed.
+    pub fn new(mut enc: Vec<u8>, mut mac: Vec<u8>) -> Result<KeyBundle> {
+        let err = if enc.len() != 32 {
             error!("Bad key length (enc_key): {} != 32", enc.len());
-            return Err(ErrorKind::BadKeyLength("enc_key", enc.len(), 32).into());
-        }
-        if mac.len() != 32 {
+            ErrorKind::BadKeyLength("enc_key", enc.len(), 32)
+        } else if mac.len() != 32 {
             error!("Bad key length (mac_key): {} != 32", mac.len());
-            return Err(ErrorKind::BadKeyLength("mac_key", mac.len(), 32).into());
-        }
-        Ok(KeyBundle { enc_key: enc, mac_key: mac })
+            ErrorKind::BadKeyLength("mac_key", mac.len(), 32)
+        } else {
+            return Ok(KeyBundle { enc_key: enc, mac_key: mac });
+        };
+        Clear::clear(&mut enc[..]);
+        Clear::clear(&mut mac[..]);
+        Err(err.into())
     }
 
     pub fn new_random() -> Result<KeyBundle> {
         let mut buffer = [0u8; 64];
         openssl::rand::rand_bytes(&mut buffer)?;
-        KeyBundle::from_ksync_bytes(&buffer)
+        let result = KeyBundle::from_ksync_bytes(&buffer);
+        Clear::clear(&mut buffer[..]);
+        result
     }
 
     pub fn from_ksync_bytes(ksync: &[u8]) -> Result<KeyBundle> {
@@ -50,14 +59,22 @@ impl KeyBundle {
     }
 
     pub fn from_ksync_base64(ksync: &str) -> Result<KeyBundle> {
-        let bytes = base64::decode_config(&ksync, base64::URL_SAFE_NO_PAD)?;
-        KeyBundle::from_ksync_bytes(&bytes)
+        let mut bytes = base64::decode_config(&ksync, base64::URL_SAFE_NO_PAD)?;
+        let result = KeyBundle::from_ksync_bytes(&bytes);
+        Clear::clear(&mut bytes[..]);
+        result
     }
 
     pub fn from_base64(enc: &str, mac: &str) -> Result<KeyBundle> {
-        let enc_bytes = base64::decode(&enc)?;
-        let mac_bytes = base64::decode(&mac)?;
-        KeyBundle::new(enc_bytes.into(), mac_bytes.into())
+        let mut enc_bytes = base64::decode(&enc)?;
+        let mac_bytes = match base64::decode(&mac) {
+            Ok(mac_bytes) => mac_bytes,
+            Err(e) => {
+                Clear::clear(&mut enc_bytes[..]);
+                return Err(e.into());
+            }
+        };
+        KeyBundle::new(enc_bytes, mac_bytes)
     }
 
     #[inline]
@@ -159,6 +176,29 @@ impl KeyBundle {
     }
 }
 
+impl Drop for KeyBundle {
+    fn drop(&mut self) {
+        Clear::clear(&mut self.enc_key[..]);
+        Clear::clear(&mut self.mac_key[..]);
+    }
+}
+
+impl PartialEq for KeyBundle {
+    fn eq(&self, other: &KeyBundle) -> bool {
+        // Both keys are always 32 bytes, which `memcmp::eq` requires.
+        openssl::memcmp::eq(&self.enc_key, &other.enc_key) &&
+            openssl::memcmp::eq(&self.mac_key, &other.mac_key)
+    }
+}
+
+impl Eq for KeyBundle {}
+
+impl fmt::Debug for KeyBundle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KeyBundle(<redacted>)")
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
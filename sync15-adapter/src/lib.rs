@@ -30,6 +30,9 @@ extern crate serde_json;
 
 extern crate url;
 extern crate base16;
+extern crate clear_on_drop;
+#[cfg(feature = "fxa-client")]
+extern crate fxa_client;
 
 // TODO: Some of these don't need to be pub...
 pub mod key_bundle;
@@ -44,6 +47,9 @@ pub mod changeset;
 pub mod sync;
 pub mod client;
 pub mod state;
+#[cfg(feature = "fxa-client")]
+pub mod account;
+pub mod manager;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use bso_record::{BsoRecord, EncryptedBso, Payload, CleartextBso};
@@ -54,3 +60,6 @@ pub use util::{ServerTimestamp, SERVER_EPOCH};
 pub use key_bundle::KeyBundle;
 pub use client::{Sync15StorageClientInit, Sync15StorageClient};
 pub use state::{GlobalState, SetupStateMachine};
+#[cfg(feature = "fxa-client")]
+pub use account::{AccountStorageClient, SyncAuthInfo, SYNC_SCOPE};
+pub use manager::{EngineSyncResult, SyncEngine, SyncManager, SyncResult};
@@ -0,0 +1,427 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+
+use client::Sync15StorageClient;
+use error::{self, ErrorKind};
+use state::{GlobalState, SetupStateMachine};
+
+/// An engine that can be synced by a `SyncManager`. Engines with their own
+/// error types can wrap them in `ErrorKind::StoreError`.
+pub trait SyncEngine {
+    /// The name of the engine, which is also the name of its collection.
+    fn collection_name(&self) -> &str;
+
+    /// Syncs the engine's collection. `state` is always ready.
+    fn sync(&mut self, client: &Sync15StorageClient, state: &GlobalState) -> error::Result<()>;
+
+    /// Forgets the last sync time and any other sync metadata, so that the
+    /// next sync is a first sync. Called when the engine's sync ID or keys
+    /// change on the server.
+    fn reset(&mut self) -> error::Result<()>;
+}
+
+/// The outcome of syncing a single engine.
+#[derive(Debug)]
+pub enum EngineSyncResult {
+    Synced,
+    /// The engine is declined in `meta/global`, so we didn't sync it.
+    Declined,
+    /// Resetting or syncing the engine failed. Other engines were still
+    /// synced.
+    Failed(error::Error),
+}
+
+impl EngineSyncResult {
+    pub fn is_failure(&self) -> bool {
+        match self {
+            EngineSyncResult::Failed(_) => true,
+            _ => false,
+        }
+    }
+
+    fn is_node_reassigned(&self) -> bool {
+        match self {
+            EngineSyncResult::Failed(e) => match e.kind() {
+                ErrorKind::StorageResetError => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SyncResult {
+    /// The global state after setup, for the next sync.
+    pub state: GlobalState,
+    /// One result for each registered engine, in registration order.
+    pub engine_results: Vec<(String, EngineSyncResult)>,
+}
+
+/// Syncs several engines at once, running the setup state machine a single
+/// time for all of them.
+#[derive(Default)]
+pub struct SyncManager<'e> {
+    engines: Vec<&'e mut SyncEngine>,
+}
+
+impl<'e> SyncManager<'e> {
+    pub fn new() -> SyncManager<'e> {
+        SyncManager { engines: Vec::new() }
+    }
+
+    /// Adds an engine to sync. Engines are synced in the order they're
+    /// registered.
+    pub fn register(&mut self, engine: &'e mut SyncEngine) {
+        self.engines.push(engine);
+    }
+
+    /// Brings `state` to ready with `state_machine`, then syncs every
+    /// registered engine. Only a backoff or a failure to get ready is returned
+    /// as an error; engine failures are reported in `SyncResult::engine_results`.
+    ///
+    /// If we're reassigned to a new storage node while syncing the engines,
+    /// we get ready on the new node, which resets every engine, and sync them
+    /// all again. That only happens once per sync.
+    ///
+    /// Engines that fail to reset are kept in the returned state's
+    /// `pending_resets`, and reset on the next sync. For that, the caller
+    /// needs to pass `SyncResult::state` to the next sync, or persist it with
+    /// `GlobalState::to_persisted_string`.
+    pub fn sync(
+        &mut self,
+        client: &Sync15StorageClient,
+        state_machine: &mut SetupStateMachine,
+        state: GlobalState,
+    ) -> error::Result<SyncResult> {
+        state_machine.check_backoff()?;
+        let mut state = state_machine.to_ready(state)?;
+        let mut engine_results = self.sync_engines(client, &mut state);
+        if engine_results.iter().any(|(_, result)| result.is_node_reassigned()) {
+            info!("Node reassigned while syncing engines, starting over on the new node.");
+            state = state_machine.to_ready(state)?;
+            engine_results = self.sync_engines(client, &mut state);
+        }
+        Ok(SyncResult {
+            state,
+            engine_results,
+        })
+    }
+
+    fn sync_engines(
+        &mut self,
+        client: &Sync15StorageClient,
+        state: &mut GlobalState,
+    ) -> Vec<(String, EngineSyncResult)> {
+        let declined: HashSet<String> = state
+            .global
+            .as_ref()
+            .map(|global| global.declined.iter().cloned().collect())
+            .unwrap_or_else(HashSet::new);
+        let engines_to_reset = state.engines_that_need_local_reset();
+
+        let mut results = Vec::with_capacity(self.engines.len());
+        for engine in self.engines.iter_mut() {
+            let name = engine.collection_name().to_string();
+            let result = if declined.contains(&name) {
+                info!("Not syncing declined engine {}", name);
+                EngineSyncResult::Declined
+            } else {
+                let reset = if engines_to_reset.contains(&name) {
+                    info!("Resetting engine {}", name);
+                    let reset = engine.reset();
+                    if reset.is_ok() {
+                        state.pending_resets.remove(&name);
+                    } else {
+                        state.pending_resets.insert(name.clone());
+                    }
+                    reset
+                } else {
+                    Ok(())
+                };
+                match reset.and_then(|_| engine.sync(client, state)) {
+                    Ok(()) => EngineSyncResult::Synced,
+                    Err(e) => {
+                        warn!("Failed to sync engine {}: {}", name, e);
+                        EngineSyncResult::Failed(e)
+                    }
+                }
+            };
+            results.push((name, result));
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bso_record::BsoRecord;
+    use client::Sync15StorageClientInit;
+    use key_bundle::KeyBundle;
+    use record_types::{MetaGlobalEngine, MetaGlobalRecord};
+    use reqwest::Url;
+    use state::tests::{in_memory_client, InMemoryClient};
+    use state::EngineStateChange;
+    use std::cell::Cell;
+    use std::time::{Duration, SystemTime};
+
+    struct TestEngine<'a> {
+        name: &'static str,
+        fail: bool,
+        fail_reset: bool,
+        // Syncing fails this many times with a node reassignment, which we
+        // report to the setup client through `node_reassigned`.
+        reassignments: usize,
+        node_reassigned: Option<&'a Cell<bool>>,
+        resets: usize,
+        syncs: usize,
+    }
+
+    impl<'a> TestEngine<'a> {
+        fn new(name: &'static str) -> TestEngine<'a> {
+            TestEngine {
+                name,
+                fail: false,
+                fail_reset: false,
+                reassignments: 0,
+                node_reassigned: None,
+                resets: 0,
+                syncs: 0,
+            }
+        }
+    }
+
+    impl<'a> SyncEngine for TestEngine<'a> {
+        fn collection_name(&self) -> &str {
+            self.name
+        }
+
+        fn sync(&mut self, _: &Sync15StorageClient, _: &GlobalState) -> error::Result<()> {
+            self.syncs += 1;
+            if self.reassignments > 0 {
+                self.reassignments -= 1;
+                if let Some(node_reassigned) = self.node_reassigned {
+                    node_reassigned.set(true);
+                }
+                return Err(ErrorKind::StorageResetError.into());
+            }
+            if self.fail {
+                return Err(ErrorKind::BatchInterrupted.into());
+            }
+            Ok(())
+        }
+
+        fn reset(&mut self) -> error::Result<()> {
+            self.resets += 1;
+            if self.fail_reset {
+                return Err(ErrorKind::BatchInterrupted.into());
+            }
+            Ok(())
+        }
+    }
+
+    fn engine(sync_id: &str) -> MetaGlobalEngine {
+        MetaGlobalEngine {
+            version: 1,
+            sync_id: sync_id.to_string(),
+        }
+    }
+
+    // The engines never use the client, so it never makes a request.
+    fn storage_client() -> Sync15StorageClient {
+        Sync15StorageClient::new(Sync15StorageClientInit {
+            key_id: "key".into(),
+            access_token: "token".into(),
+            tokenserver_url: Url::parse("https://token.example.com").unwrap(),
+        }).unwrap()
+    }
+
+    #[test]
+    fn test_sync_engines() {
+        let mut state = GlobalState::default();
+        state.global = Some(BsoRecord::new_record(
+            "global".into(),
+            "meta".into(),
+            MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".into(),
+                storage_version: 5,
+                engines: vec![
+                    ("bookmarks".to_string(), engine("syncIDBBBBBB")),
+                    ("history".to_string(), engine("syncIDCCCCCC")),
+                ].into_iter()
+                    .collect(),
+                declined: vec!["passwords".to_string()],
+            },
+        ));
+        state.engine_state_changes = vec![EngineStateChange::Reset("history".into())];
+
+        let client = storage_client();
+
+        let mut bookmarks = TestEngine::new("bookmarks");
+        bookmarks.fail = true;
+        let mut history = TestEngine::new("history");
+        let mut passwords = TestEngine::new("passwords");
+        let results = {
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.register(&mut history);
+            manager.register(&mut passwords);
+            manager.sync_engines(&client, &mut state)
+        };
+
+        let names: Vec<&str> = results.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["bookmarks", "history", "passwords"]);
+        assert!(results[0].1.is_failure());
+        match results[1].1 {
+            EngineSyncResult::Synced => {}
+            ref other => panic!("Unexpected result for history: {:?}", other),
+        }
+        match results[2].1 {
+            EngineSyncResult::Declined => {}
+            ref other => panic!("Unexpected result for passwords: {:?}", other),
+        }
+
+        assert_eq!((bookmarks.resets, bookmarks.syncs), (0, 1));
+        assert_eq!((history.resets, history.syncs), (1, 1));
+        assert_eq!((passwords.resets, passwords.syncs), (0, 0));
+    }
+
+    #[test]
+    fn test_sync_backoff() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut setup_client = in_memory_client(&root_key);
+        setup_client.backoff = Some(SystemTime::now() + Duration::from_secs(60));
+        let client = storage_client();
+
+        let mut bookmarks = TestEngine::new("bookmarks");
+        let result = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, &root_key);
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.sync(&client, &mut state_machine, GlobalState::default())
+        };
+
+        match result {
+            Err(ref e) => match e.kind() {
+                ErrorKind::BackoffError(_) => {}
+                other => panic!("Unexpected error: {:?}", other),
+            },
+            Ok(_) => panic!("Should back off"),
+        }
+        assert!(setup_client.uploaded_globals.borrow().is_empty());
+        assert_eq!((bookmarks.resets, bookmarks.syncs), (0, 0));
+    }
+
+    // Returns a setup client with `bookmarks` and `history` in `meta/global`,
+    // and a state that's already ready with it.
+    fn ready_client_and_state(root_key: &KeyBundle) -> (InMemoryClient, GlobalState) {
+        let mut setup_client = in_memory_client(root_key);
+        if let Ok(ref mut global) = setup_client.meta_global {
+            global.payload.engines.insert("history".to_string(), engine("syncIDCCCCCC"));
+        }
+        let state = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, root_key);
+            state_machine.to_ready(GlobalState::default()).unwrap()
+        };
+        (setup_client, state)
+    }
+
+    #[test]
+    fn test_sync_node_reassignment() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let (setup_client, state) = ready_client_and_state(&root_key);
+        let client = storage_client();
+
+        let mut bookmarks = TestEngine::new("bookmarks");
+        bookmarks.reassignments = 1;
+        bookmarks.node_reassigned = Some(&setup_client.node_reassigned);
+        let mut history = TestEngine::new("history");
+        let result = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, &root_key);
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.register(&mut history);
+            manager
+                .sync(&client, &mut state_machine, state)
+                .expect("Should sync on the new node")
+        };
+
+        assert!(!setup_client.node_reassigned.get());
+        assert!(result.engine_results.iter().all(|(_, result)| !result.is_failure()));
+        // Every engine is reset on the new node, and synced again, once.
+        assert_eq!((bookmarks.resets, bookmarks.syncs), (1, 2));
+        assert_eq!((history.resets, history.syncs), (1, 2));
+    }
+
+    #[test]
+    fn test_sync_second_node_reassignment() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let (setup_client, state) = ready_client_and_state(&root_key);
+        let client = storage_client();
+
+        let mut bookmarks = TestEngine::new("bookmarks");
+        bookmarks.reassignments = 2;
+        bookmarks.node_reassigned = Some(&setup_client.node_reassigned);
+        let mut history = TestEngine::new("history");
+        let result = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, &root_key);
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.register(&mut history);
+            manager.sync(&client, &mut state_machine, state).unwrap()
+        };
+
+        // We only start over once per sync: the second reassignment is left
+        // for the next sync to pick up.
+        assert!(setup_client.node_reassigned.get());
+        assert!(result.engine_results[0].1.is_node_reassigned());
+        assert!(!result.engine_results[1].1.is_failure());
+        assert_eq!((bookmarks.resets, bookmarks.syncs), (1, 2));
+        assert_eq!((history.resets, history.syncs), (1, 2));
+    }
+
+    #[test]
+    fn test_sync_failed_reset() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let (setup_client, _) = ready_client_and_state(&root_key);
+        let client = storage_client();
+
+        // A first sync resets every engine.
+        let mut bookmarks = TestEngine::new("bookmarks");
+        bookmarks.fail_reset = true;
+        let mut history = TestEngine::new("history");
+        let result = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, &root_key);
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.register(&mut history);
+            manager
+                .sync(&client, &mut state_machine, GlobalState::default())
+                .unwrap()
+        };
+        assert!(result.engine_results[0].1.is_failure());
+        assert_eq!(
+            result.state.pending_resets,
+            vec!["bookmarks".to_string()].into_iter().collect()
+        );
+
+        // The next sync finds no changes on the server, but still resets
+        // bookmarks.
+        bookmarks.fail_reset = false;
+        let result = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&setup_client, &root_key);
+            let mut manager = SyncManager::new();
+            manager.register(&mut bookmarks);
+            manager.register(&mut history);
+            manager.sync(&client, &mut state_machine, result.state).unwrap()
+        };
+        assert!(result.engine_results.iter().all(|(_, result)| !result.is_failure()));
+        assert!(result.state.pending_resets.is_empty());
+        assert_eq!((bookmarks.resets, bookmarks.syncs), (2, 1));
+        assert_eq!((history.resets, history.syncs), (1, 2));
+    }
+}
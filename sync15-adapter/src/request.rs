@@ -21,6 +21,11 @@ pub enum RequestOrder { Oldest, Newest, Index }
 header! { (XIfUnmodifiedSince, "X-If-Unmodified-Since") => [ServerTimestamp] }
 header! { (XLastModified, "X-Last-Modified") => [ServerTimestamp] }
 header! { (XWeaveTimestamp, "X-Weave-Timestamp") => [ServerTimestamp] }
+/// Sent when a GET with a `limit` didn't return every record: the `offset` to
+/// request the next page with.
+header! { (XWeaveNextOffset, "X-Weave-Next-Offset") => [String] }
+/// Seconds the server wants us to back off for, sent along a successful response.
+header! { (XWeaveBackoff, "X-Weave-Backoff") => [f64] }
 
 impl fmt::Display for RequestOrder {
     #[inline]
@@ -44,6 +49,7 @@ pub struct CollectionRequest {
     pub order: Option<RequestOrder>,
     pub commit: bool,
     pub batch: Option<String>,
+    pub offset: Option<String>,
 }
 
 impl CollectionRequest {
@@ -59,6 +65,7 @@ impl CollectionRequest {
             order: None,
             commit: false,
             batch: None,
+            offset: None,
         }
     }
 
@@ -110,6 +117,12 @@ impl CollectionRequest {
         self
     }
 
+    #[inline]
+    pub fn offset(&mut self, offset: Option<String>) -> &mut CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
     fn build_query(&self, pairs: &mut Serializer<UrlQuery>) {
         if self.full {
             pairs.append_pair("full", "1");
@@ -135,6 +148,9 @@ impl CollectionRequest {
         if let Some(o) = self.order {
             pairs.append_pair("sort", &format!("{}", o));
         }
+        if let &Some(ref offset) = &self.offset {
+            pairs.append_pair("offset", &offset);
+        }
         pairs.finish();
     }
 
@@ -198,7 +214,7 @@ impl LimitTracker {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct InfoConfiguration {
     /// The maximum size in bytes of the overall HTTP request body that will be accepted by the
     /// server.
@@ -247,7 +263,7 @@ impl Default for InfoConfiguration {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct InfoCollections(HashMap<String, ServerTimestamp>);
 
 impl InfoCollections {
@@ -636,6 +652,9 @@ mod test {
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
 
+        let paged = CollectionRequest::new("history").full().limit(100).offset(Some("200".into()))
+                                                     .build_url(base.clone()).unwrap();
+        assert_eq!(paged.as_str(), "https://example.com/sync/storage/history?full=1&limit=100&offset=200");
     }
 
     #[derive(Debug, Clone)]
@@ -932,6 +951,34 @@ mod test {
                    request_bytes_for_payloads(&[payload_size]));
     }
 
+    #[test]
+    fn test_pq_max_post_records_no_batch() {
+        let cfg = InfoConfiguration {
+            max_post_records: 2,
+            ..InfoConfiguration::default()
+        };
+        let time = 11111111.0;
+        let (mut pq, tester) = pq_test_setup(cfg, time, vec![
+            fake_response(StatusCode::Ok, time + 100.0, None),
+            fake_response(StatusCode::Ok, time + 200.0, None),
+            fake_response(StatusCode::Ok, time + 300.0, None),
+        ]);
+
+        for _ in 0..5 {
+            pq.enqueue(&make_record(100)).unwrap();
+        }
+        pq.flush(true).unwrap();
+
+        let t = tester.borrow();
+        assert!(t.cur_batch.is_none());
+        let records: Vec<usize> = t.all_posts.iter().map(|post| post.records).collect();
+        assert_eq!(records, vec![2, 2, 1]);
+        // Without batches, every post stands on its own.
+        assert_eq!(t.batches.len(), 3);
+        assert_eq!(t.all_posts[1].batch, None);
+        assert_eq!(t.all_posts[2].batch, None);
+    }
+
     #[test]
     fn test_pq_max_record_payload_bytes_no_batch() {
         let cfg = InfoConfiguration {
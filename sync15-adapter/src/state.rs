@@ -4,7 +4,9 @@
 
 use std::collections::{HashMap, HashSet};
 
-use bso_record::BsoRecord;
+use serde_json;
+
+use bso_record::{BsoRecord, EncryptedBso};
 use client::SetupStorageClient;
 use collection_keys::CollectionKeys;
 use error::{self, ErrorKind};
@@ -49,6 +51,9 @@ pub struct GlobalState {
     pub global: Option<BsoRecord<MetaGlobalRecord>>,
     pub keys: Option<CollectionKeys>,
     pub engine_state_changes: Vec<EngineStateChange>,
+    /// Engines we failed to reset locally. Unlike `engine_state_changes`,
+    /// these are kept, and persisted, until the engines are reset.
+    pub pending_resets: HashSet<String>,
 }
 
 impl GlobalState {
@@ -75,7 +80,7 @@ impl GlobalState {
                     .collect::<HashSet<String>>()
             })
             .unwrap_or_default();
-        let mut engines_to_reset = HashSet::new();
+        let mut engines_to_reset = self.pending_resets.clone();
         for change in &self.engine_state_changes {
             match change {
                 EngineStateChange::Reset(name) => {
@@ -99,6 +104,72 @@ impl GlobalState {
     }
 }
 
+/// The serialized form of `GlobalState`. The collection keys are kept sealed,
+/// as an encrypted `crypto/keys` record, so they can't be read back without
+/// the root key. `BsoRecord` doesn't serialize `modified`, so we keep the
+/// timestamps alongside.
+#[derive(Serialize, Deserialize)]
+struct PersistedGlobalState {
+    config: InfoConfiguration,
+    collections: InfoCollections,
+    global: Option<BsoRecord<MetaGlobalRecord>>,
+    #[serde(default)]
+    global_modified: ServerTimestamp,
+    keys: Option<EncryptedBso>,
+    #[serde(default)]
+    keys_modified: ServerTimestamp,
+    #[serde(default)]
+    pending_resets: HashSet<String>,
+}
+
+impl GlobalState {
+    /// Serializes the state to JSON, for apps to persist between syncs. A
+    /// state restored with `from_persisted_string` lets a fast sync skip
+    /// fetching `meta/global` and `crypto/keys`.
+    pub fn to_persisted_string(&self, root_key: &KeyBundle) -> error::Result<String> {
+        let keys = match &self.keys {
+            Some(keys) => Some(keys.to_encrypted_bso(root_key)?),
+            None => None,
+        };
+        let persisted = PersistedGlobalState {
+            config: self.config.clone(),
+            collections: self.collections.clone(),
+            global: self.global.clone(),
+            global_modified: self.global
+                .as_ref()
+                .map(|global| global.modified)
+                .unwrap_or_default(),
+            keys,
+            keys_modified: self.keys.as_ref().map(|keys| keys.timestamp).unwrap_or_default(),
+            pending_resets: self.pending_resets.clone(),
+        };
+        Ok(serde_json::to_string(&persisted)?)
+    }
+
+    pub fn from_persisted_string(data: &str, root_key: &KeyBundle) -> error::Result<GlobalState> {
+        let persisted: PersistedGlobalState = serde_json::from_str(data)?;
+        let global = persisted.global.map(|mut global| {
+            global.modified = persisted.global_modified;
+            global
+        });
+        let keys = match persisted.keys {
+            Some(mut keys) => {
+                keys.modified = persisted.keys_modified;
+                Some(CollectionKeys::from_encrypted_bso(keys, root_key)?)
+            }
+            None => None,
+        };
+        Ok(GlobalState {
+            config: persisted.config,
+            collections: persisted.collections,
+            global,
+            keys,
+            engine_state_changes: Vec::new(),
+            pending_resets: persisted.pending_resets,
+        })
+    }
+}
+
 fn resolve_global(
     previous_state: GlobalState,
     new_global: BsoRecord<MetaGlobalRecord>,
@@ -127,6 +198,7 @@ fn resolve_global(
         global: Some(new_global),
         keys: previous_keys,
         engine_state_changes: changes,
+        pending_resets: previous_state.pending_resets,
     }
 }
 
@@ -206,6 +278,7 @@ fn resolve_keys(previous_state: GlobalState, new_keys: CollectionKeys) -> Global
         global: previous_state.global,
         keys: Some(new_keys),
         engine_state_changes: changes,
+        pending_resets: previous_state.pending_resets,
     }
 }
 
@@ -242,11 +315,56 @@ fn new_global_from_previous(
     })
 }
 
+/// Updates `engines` and `declined` in `global` to match the engines the user
+/// enabled (`true`) or declined (`false`) locally. Returns the new record and
+/// the resulting engine state changes, or `None` if `global` already matches.
+fn apply_engine_selection(
+    global: &MetaGlobalRecord,
+    selection: &HashMap<String, bool>,
+) -> error::Result<Option<(MetaGlobalRecord, Vec<EngineStateChange>)>> {
+    let mut new_global = global.clone();
+    let mut changes = Vec::new();
+    for (name, &enabled) in selection {
+        if enabled {
+            new_global.declined.retain(|declined| declined != name);
+            if !new_global.engines.contains_key(name) {
+                let version = DEFAULT_ENGINES
+                    .iter()
+                    .find(|(default_name, _)| *default_name == name.as_str())
+                    .map(|(_, version)| *version)
+                    .unwrap_or(1);
+                new_global.engines.insert(
+                    name.to_string(),
+                    MetaGlobalEngine {
+                        version,
+                        sync_id: random_guid()?,
+                    },
+                );
+                // The engine gets a new sync ID, so it starts over.
+                changes.push(EngineStateChange::Enable(name.to_string()));
+                changes.push(EngineStateChange::Reset(name.to_string()));
+            }
+        } else {
+            if new_global.engines.remove(name).is_some() {
+                changes.push(EngineStateChange::Disable(name.to_string()));
+            }
+            if !new_global.declined.contains(name) {
+                new_global.declined.push(name.to_string());
+            }
+        }
+    }
+    if new_global.declined == global.declined && changes.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((new_global, changes)))
+}
+
 pub struct SetupStateMachine<'client, 'keys> {
     client: &'client SetupStorageClient,
     root_key: &'keys KeyBundle,
     allowed_states: Vec<&'static str>,
     sequence: Vec<&'static str>,
+    engine_selection: HashMap<String, bool>,
 }
 
 impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
@@ -267,6 +385,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 "NeedsFreshMetaGlobal",
                 "HasMetaGlobal",
                 "ResolveMetaGlobal",
+                "UploadMetaGlobal",
                 "NeedsFreshCryptoKeys",
                 "Ready",
                 "FreshStartRequired",
@@ -329,9 +448,24 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             root_key,
             sequence: Vec::new(),
             allowed_states,
+            engine_selection: HashMap::new(),
         }
     }
 
+    /// Declares the engines the user enabled (`true`) or declined (`false`)
+    /// on this device. If `meta/global` doesn't agree, and the state machine
+    /// is allowed to upload it, we update `engines` and `declined` in
+    /// `meta/global`, and report `Enable` and `Disable` engine state changes.
+    pub fn set_engine_selection(&mut self, selection: HashMap<String, bool>) {
+        self.engine_selection = selection;
+    }
+
+    /// Fails with a `BackoffError` if the server asked us to back off, in
+    /// which case we shouldn't even start setting up.
+    pub fn check_backoff(&self) -> error::Result<()> {
+        self.client.check_backoff()
+    }
+
     fn advance(&self, from: SetupState) -> error::Result<SetupState> {
         match from {
             // Fetch `info/configuration` with current server limits, and
@@ -345,7 +479,8 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     collections: state.collections,
                     global: state.global,
                     keys: state.keys,
-                    engine_state_changes: Vec::new(),
+                    engine_state_changes: state.engine_state_changes,
+                    pending_resets: state.pending_resets,
                 }))
             }
 
@@ -357,6 +492,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: state.global,
                     keys: state.keys,
                     engine_state_changes: state.engine_state_changes,
+                    pending_resets: state.pending_resets,
                 }))
             }
 
@@ -388,6 +524,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: None,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        pending_resets: state.pending_resets,
                     }),
                 })
             }
@@ -423,8 +560,25 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
             // Check if our locally cached `crypto/keys` collection is
             // up-to-date.
             HasMetaGlobal(state) => {
-                // TODO(lina): Check if we've enabled or disabled any engines
-                // locally, and update `m/g` to reflect that.
+                // If the user enabled or declined engines locally, we need to
+                // update `meta/global` first. We work out the new record once
+                // here, so the sync IDs we upload are the ones we report.
+                let selected = match &state.global {
+                    Some(global) if self.allowed_states.contains(&"UploadMetaGlobal") => {
+                        apply_engine_selection(global, &self.engine_selection)?
+                            .map(|(payload, changes)| {
+                                let new_global = BsoRecord {
+                                    payload,
+                                    ..global.clone()
+                                };
+                                (new_global, changes)
+                            })
+                    }
+                    _ => None,
+                };
+                if let Some((new_global, changes)) = selected {
+                    return Ok(UploadMetaGlobal(state, new_global, changes));
+                }
                 let action = {
                     let local = state.keys.as_ref().map(|keys| &keys.timestamp);
                     let remote = state.collections.get("crypto");
@@ -444,6 +598,7 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                         global: state.global,
                         keys: None,
                         engine_state_changes: state.engine_state_changes,
+                        pending_resets: state.pending_resets,
                     }),
                 })
             }
@@ -465,6 +620,34 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 }
             }
 
+            // Upload a `meta/global` with the user's engine selection,
+            // unless another client changed it since we fetched it. If they
+            // did, we fetch theirs and apply our selection again.
+            UploadMetaGlobal(state, new_global, mut changes) => {
+                match self.client.put_meta_global(Some(new_global.modified), &new_global) {
+                    Ok(modified) => {
+                        let mut engine_state_changes = state.engine_state_changes;
+                        engine_state_changes.append(&mut changes);
+                        Ok(HasMetaGlobal(GlobalState {
+                            config: state.config,
+                            collections: state.collections,
+                            global: Some(BsoRecord {
+                                modified,
+                                ..new_global
+                            }),
+                            keys: state.keys,
+                            engine_state_changes,
+                            pending_resets: state.pending_resets,
+                        }))
+                    }
+                    Err(ref err) if err.is_precondition_failed() => {
+                        info!("meta/global changed on the server, fetching it again.");
+                        Ok(NeedsFreshMetaGlobal(state))
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+
             Ready(state) => Ok(Ready(state)),
 
             FreshStartRequired(state) => {
@@ -472,12 +655,13 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 self.client.wipe_all_remote()?;
 
                 // Upload a fresh `meta/global`...
-                let new_global = BsoRecord::new_record(
-                    "global".into(),
-                    "meta".into(),
-                    new_global_from_previous(state.global)?,
-                );
-                self.client.put_meta_global(&new_global)?;
+                let mut payload = new_global_from_previous(state.global)?;
+                let selected = apply_engine_selection(&payload, &self.engine_selection)?;
+                if let Some((selected, _)) = selected {
+                    payload = selected;
+                }
+                let new_global = BsoRecord::new_record("global".into(), "meta".into(), payload);
+                self.client.put_meta_global(None, &new_global)?;
 
                 // ...And a fresh `crypto/keys`. Note that we'll update the
                 // global state when we go around the state machine again,
@@ -494,14 +678,25 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                     global: None,
                     keys: None,
                     engine_state_changes: vec![EngineStateChange::ResetAll],
+                    pending_resets: state.pending_resets,
                 }))
             }
         }
     }
 
     /// Runs through the state machine to the ready state.
+    ///
+    /// If we got reassigned to a new storage node, everything we knew about
+    /// the old one is dropped, and we start over on the new node with an
+    /// `EngineStateChange::ResetAll`.
     pub fn to_ready(&mut self, state: GlobalState) -> error::Result<GlobalState> {
-        let mut s = InitialWithLiveToken(state);
+        self.sequence.clear();
+        // Engine state changes only matter to the sync that found them.
+        let mut s = InitialWithLiveToken(GlobalState {
+            engine_state_changes: Vec::new(),
+            ..state
+        });
+        let mut reassigned = false;
         loop {
             let label = &s.label();
             match s {
@@ -517,12 +712,52 @@ impl<'client, 'keys> SetupStateMachine<'client, 'keys> {
                 FreshStartRequired(_) if self.sequence.contains(&label) => {
                     return Err(ErrorKind::SetupStateCycleError.into());
                 }
+                // We retry once if another client changes `meta/global`
+                // while we're uploading ours.
+                UploadMetaGlobal(_, _, _)
+                    if self.sequence.iter().filter(|l| *l == label).count() > 1 =>
+                {
+                    return Err(ErrorKind::SetupStateCycleError.into());
+                }
                 previous_s => {
                     if !self.allowed_states.contains(&label) {
                         return Err(ErrorKind::DisallowedStateError(&label).into());
                     }
                     self.sequence.push(label);
-                    s = self.advance(previous_s)?;
+                    // Keep what we need to start over if we're reassigned.
+                    let (config, engine_state_changes, pending_resets) = {
+                        let state = previous_s.global_state();
+                        (
+                            state.config.clone(),
+                            state.engine_state_changes.clone(),
+                            state.pending_resets.clone(),
+                        )
+                    };
+                    s = match self.advance(previous_s) {
+                        Ok(new_s) => new_s,
+                        Err(err) => match err.kind() {
+                            ErrorKind::StorageResetError
+                                if !reassigned && self.client.acknowledge_node_reassignment() =>
+                            {
+                                info!("Node reassigned, resetting the global state.");
+                                reassigned = true;
+                                // Our `meta/global` and `crypto/keys`, and their
+                                // timestamps, came from the old node, so we drop
+                                // them and fetch the new node's. Without a cached
+                                // `meta/global`, resolving the new one reports
+                                // `ResetAll`, on top of the changes we found so far.
+                                InitialWithLiveToken(GlobalState {
+                                    config,
+                                    collections: InfoCollections::default(),
+                                    global: None,
+                                    keys: None,
+                                    engine_state_changes,
+                                    pending_resets,
+                                })
+                            }
+                            _ => return Err(err),
+                        },
+                    };
                 }
             }
         }
@@ -539,6 +774,9 @@ enum SetupState {
     NeedsFreshMetaGlobal(GlobalState),
     HasMetaGlobal(GlobalState),
     ResolveMetaGlobal(GlobalState, BsoRecord<MetaGlobalRecord>),
+    /// Holds the `meta/global` to upload, and the engine state changes to
+    /// report once it's uploaded.
+    UploadMetaGlobal(GlobalState, BsoRecord<MetaGlobalRecord>, Vec<EngineStateChange>),
     NeedsFreshCryptoKeys(GlobalState),
     Ready(GlobalState),
     FreshStartRequired(GlobalState),
@@ -553,11 +791,27 @@ impl SetupState {
             NeedsFreshMetaGlobal(_) => "NeedsFreshMetaGlobal",
             HasMetaGlobal(_) => "HasMetaGlobal",
             ResolveMetaGlobal(_, _) => "ResolveMetaGlobal",
+            UploadMetaGlobal(_, _, _) => "UploadMetaGlobal",
             NeedsFreshCryptoKeys(_) => "NeedsFreshCryptoKeys",
             Ready(_) => "Ready",
             FreshStartRequired(_) => "FreshStartRequired",
         }
     }
+
+    fn global_state(&self) -> &GlobalState {
+        match self {
+            InitialWithLiveToken(state)
+            | InitialWithLiveTokenAndConfig(state)
+            | InitialWithLiveTokenAndInfo(state)
+            | NeedsFreshMetaGlobal(state)
+            | HasMetaGlobal(state)
+            | ResolveMetaGlobal(state, _)
+            | UploadMetaGlobal(state, _, _)
+            | NeedsFreshCryptoKeys(state)
+            | Ready(state)
+            | FreshStartRequired(state) => state,
+        }
+    }
 }
 
 /// Whether we should skip fetching `meta/global` or `crypto/keys` from the
@@ -590,7 +844,7 @@ impl FetchAction {
 }
 
 /// Flags an engine for enablement or disablement.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum EngineStateChange {
     ResetAll,
     ResetAllExcept(HashSet<String>),
@@ -599,18 +853,25 @@ pub enum EngineStateChange {
     Reset(String),
 }
 
+// The in-memory client is also used by the `manager` tests.
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
     use reqwest;
+    use std::cell::{Cell, RefCell};
+    use std::time::SystemTime;
 
     use bso_record::{BsoRecord, EncryptedBso, EncryptedPayload};
 
-    struct InMemoryClient {
-        info_configuration: error::Result<InfoConfiguration>,
-        info_collections: error::Result<InfoCollections>,
-        meta_global: error::Result<BsoRecord<MetaGlobalRecord>>,
-        crypto_keys: error::Result<BsoRecord<EncryptedPayload>>,
+    pub(crate) struct InMemoryClient {
+        pub(crate) info_configuration: error::Result<InfoConfiguration>,
+        pub(crate) info_collections: error::Result<InfoCollections>,
+        pub(crate) meta_global: error::Result<BsoRecord<MetaGlobalRecord>>,
+        pub(crate) crypto_keys: error::Result<BsoRecord<EncryptedPayload>>,
+        pub(crate) node_reassigned: Cell<bool>,
+        pub(crate) backoff: Option<SystemTime>,
+        pub(crate) uploaded_globals:
+            RefCell<Vec<(Option<ServerTimestamp>, BsoRecord<MetaGlobalRecord>)>>,
     }
 
     impl SetupStorageClient for InMemoryClient {
@@ -625,6 +886,9 @@ mod tests {
         }
 
         fn fetch_info_collections(&self) -> error::Result<InfoCollections> {
+            if self.node_reassigned.get() {
+                return Err(ErrorKind::StorageResetError.into());
+            }
             match &self.info_collections {
                 Ok(collections) => Ok(collections.clone()),
                 Err(_) => Err(ErrorKind::StorageHttpError {
@@ -646,11 +910,13 @@ mod tests {
             }
         }
 
-        fn put_meta_global(&self, global: &BsoRecord<MetaGlobalRecord>) -> error::Result<()> {
-            Err(ErrorKind::StorageHttpError {
-                code: reqwest::StatusCode::InternalServerError,
-                route: "meta/global".to_string(),
-            }.into())
+        fn put_meta_global(
+            &self,
+            xius: Option<ServerTimestamp>,
+            global: &BsoRecord<MetaGlobalRecord>,
+        ) -> error::Result<ServerTimestamp> {
+            self.uploaded_globals.borrow_mut().push((xius, global.clone()));
+            Ok(ServerTimestamp(1000.0))
         }
 
         fn fetch_crypto_keys(&self) -> error::Result<BsoRecord<EncryptedPayload>> {
@@ -674,17 +940,26 @@ mod tests {
         fn wipe_all_remote(&self) -> error::Result<()> {
             Ok(())
         }
+
+        fn acknowledge_node_reassignment(&self) -> bool {
+            self.node_reassigned.replace(false)
+        }
+
+        fn check_backoff(&self) -> error::Result<()> {
+            match self.backoff {
+                Some(until) => Err(ErrorKind::BackoffError(until).into()),
+                None => Ok(()),
+            }
+        }
     }
 
-    #[test]
-    fn test_state_machine_ready_from_empty() {
-        let root_key = KeyBundle::new_random().unwrap();
+    pub(crate) fn in_memory_client(root_key: &KeyBundle) -> InMemoryClient {
         let keys = CollectionKeys {
             timestamp: 123.4.into(),
             default: KeyBundle::new_random().unwrap(),
             collections: HashMap::new(),
         };
-        let client = InMemoryClient {
+        InMemoryClient {
             info_configuration: Ok(InfoConfiguration::default()),
             info_collections: Ok(InfoCollections::new(
                 vec![("meta", 123.456), ("crypto", 145.0)]
@@ -715,8 +990,20 @@ mod tests {
                     declined: vec![],
                 },
             }),
-            crypto_keys: keys.to_encrypted_bso(&root_key),
-        };
+            crypto_keys: keys.to_encrypted_bso(root_key).map(|mut bso| {
+                bso.modified = keys.timestamp;
+                bso
+            }),
+            node_reassigned: Cell::new(false),
+            backoff: None,
+            uploaded_globals: RefCell::new(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_state_machine_ready_from_empty() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = in_memory_client(&root_key);
 
         let state = GlobalState::default();
         let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
@@ -739,4 +1026,165 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    #[test]
+    fn test_state_machine_node_reassignment() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = in_memory_client(&root_key);
+        client.info_configuration = Ok(InfoConfiguration {
+            max_post_records: 10,
+            ..InfoConfiguration::default()
+        });
+        let state = {
+            let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+            state_machine.to_ready(GlobalState::default()).unwrap()
+        };
+
+        // We keep the config we had if we can't fetch a new one.
+        client.info_configuration = Err(ErrorKind::StorageHttpError {
+            code: reqwest::StatusCode::InternalServerError,
+            route: "info/configuration".to_string(),
+        }.into());
+        client.node_reassigned.set(true);
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let state = state_machine.to_ready(state).expect("Should recover from reassignment");
+        assert!(!client.node_reassigned.get());
+        assert_eq!(state.config.max_post_records, 10);
+        assert!(state.global.is_some());
+        assert!(state.keys.is_some());
+        assert_eq!(
+            state_machine.sequence,
+            vec![
+                "InitialWithLiveToken",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveToken",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveTokenAndInfo",
+                "NeedsFreshMetaGlobal",
+                "ResolveMetaGlobal",
+                "HasMetaGlobal",
+                "NeedsFreshCryptoKeys",
+                "Ready",
+            ],
+            "Should start over after the reassignment"
+        );
+        assert_eq!(
+            state.engines_that_need_local_reset(),
+            vec!["bookmarks".to_string()].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_persisted_global_state() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut client = in_memory_client(&root_key);
+        // The fixture's `crypto/keys` is older than `info/collections` says,
+        // so a fast sync would want to refetch it.
+        client.info_collections = Ok(InfoCollections::new(
+            vec![("meta", 123.456), ("crypto", 123.4)]
+                .into_iter()
+                .map(|(key, value)| (key.to_owned(), value.into()))
+                .collect(),
+        ));
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        let mut state = state_machine.to_ready(GlobalState::default()).unwrap();
+        state.pending_resets.insert("bookmarks".to_string());
+        let persisted = state.to_persisted_string(&root_key).unwrap();
+
+        let other_key = KeyBundle::new_random().unwrap();
+        assert!(GlobalState::from_persisted_string(&persisted, &other_key).is_err());
+
+        let restored = GlobalState::from_persisted_string(&persisted, &root_key).unwrap();
+        {
+            let global = restored.global.as_ref().unwrap();
+            assert_eq!(global.modified, ServerTimestamp(999.0));
+            assert_eq!(global.sync_id, state.global.as_ref().unwrap().sync_id);
+        }
+        assert_eq!(restored.keys, state.keys);
+        // Engine state changes only matter to the sync that found them.
+        assert!(restored.engine_state_changes.is_empty());
+        // Failed resets are kept until they succeed.
+        assert_eq!(restored.pending_resets, state.pending_resets);
+        assert_eq!(*restored.collections, *state.collections);
+
+        // With everything cached, a fast sync doesn't need to refetch
+        // `meta/global` or `crypto/keys`.
+        let mut state_machine = SetupStateMachine::for_fast_sync(&client, &root_key);
+        assert!(state_machine.to_ready(restored).is_ok());
+        assert_eq!(
+            state_machine.sequence,
+            vec![
+                "InitialWithLiveToken",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveTokenAndInfo",
+                "HasMetaGlobal",
+                "Ready",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_machine_engine_selection() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = in_memory_client(&root_key);
+
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        state_machine.set_engine_selection(
+            vec![("bookmarks".to_string(), false), ("history".to_string(), true)]
+                .into_iter()
+                .collect(),
+        );
+        let state = state_machine.to_ready(GlobalState::default()).unwrap();
+        assert_eq!(
+            state_machine.sequence,
+            vec![
+                "InitialWithLiveToken",
+                "InitialWithLiveTokenAndConfig",
+                "InitialWithLiveTokenAndInfo",
+                "NeedsFreshMetaGlobal",
+                "ResolveMetaGlobal",
+                "HasMetaGlobal",
+                "UploadMetaGlobal",
+                "HasMetaGlobal",
+                "NeedsFreshCryptoKeys",
+                "Ready",
+            ]
+        );
+
+        {
+            let uploaded = client.uploaded_globals.borrow();
+            assert_eq!(uploaded.len(), 1);
+            let (xius, ref uploaded_global) = uploaded[0];
+            assert_eq!(xius, Some(ServerTimestamp(999.0)));
+            assert_eq!(uploaded_global.sync_id, "syncIDAAAAAA");
+            assert!(!uploaded_global.engines.contains_key("bookmarks"));
+            assert_eq!(uploaded_global.engines["history"].version, 1);
+            assert_eq!(uploaded_global.declined, vec!["bookmarks".to_string()]);
+
+            let global = state.global.as_ref().unwrap();
+            assert_eq!(global.modified, ServerTimestamp(1000.0));
+            // We report the same sync ID we uploaded.
+            assert_eq!(
+                global.engines["history"].sync_id,
+                uploaded_global.engines["history"].sync_id
+            );
+        }
+        assert!(state
+            .engine_state_changes
+            .contains(&EngineStateChange::Disable("bookmarks".into())));
+        assert!(state
+            .engine_state_changes
+            .contains(&EngineStateChange::Enable("history".into())));
+
+        // `meta/global` now matches, so we don't upload it again.
+        let mut state_machine = SetupStateMachine::for_full_sync(&client, &root_key);
+        state_machine.set_engine_selection(
+            vec![("bookmarks".to_string(), false)].into_iter().collect(),
+        );
+        state_machine.to_ready(state).unwrap();
+        assert!(!state_machine.sequence.contains(&"UploadMetaGlobal"));
+    }
+
 }
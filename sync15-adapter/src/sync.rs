@@ -18,10 +18,17 @@ use util::ServerTimestamp;
 pub trait Store {
     type Error;
 
+    /// Applies a page of incoming records. `synchronize` calls this once for
+    /// every page it downloads, as soon as it arrives.
     fn apply_incoming(
         &mut self,
         inbound: IncomingChangeset
-    ) -> Result<OutgoingChangeset, Self::Error>;
+    ) -> Result<(), Self::Error>;
+
+    /// Returns the local changes to upload, once every incoming page is
+    /// applied. The changeset's timestamp must be the last sync time we
+    /// downloaded changes since.
+    fn outgoing_changes(&mut self) -> Result<OutgoingChangeset, Self::Error>;
 
     fn sync_finished(
         &mut self,
@@ -40,11 +47,15 @@ where E: From<error::Error>
 {
 
     info!("Syncing collection {}", collection);
-    let incoming_changes = IncomingChangeset::fetch(client, state, collection.clone(), timestamp)?;
-    let last_changed_remote = incoming_changes.timestamp;
+    let mut downloaded = 0;
+    IncomingChangeset::fetch(client, state, collection.clone(), timestamp, |page| {
+        downloaded += page.changes.len();
+        store.apply_incoming(page)
+    })?;
+    let last_changed_remote = state.last_modified_or_zero(&collection);
 
-    info!("Downloaded {} remote changes", incoming_changes.changes.len());
-    let mut outgoing = store.apply_incoming(incoming_changes)?;
+    info!("Downloaded {} remote changes", downloaded);
+    let mut outgoing = store.outgoing_changes()?;
 
     assert_eq!(outgoing.timestamp, timestamp,
         "last sync timestamp should never change unless we change it");
@@ -8,6 +8,7 @@ use reqwest::{Client, Request, Url};
 use hyper::header::{Authorization, Bearer};
 use error::{self, Result, ErrorKind};
 use std::fmt;
+use std::mem;
 use std::borrow::{Borrow, Cow};
 use std::time::{SystemTime, Duration};
 use std::cell::{RefCell};
@@ -177,9 +178,10 @@ enum TokenState {
     // elt is the api_endpoint we had before we hit the backoff error.
     // XXX - should we roll Backoff and Failed together?
     Backoff(SystemTime, Option<String>),
-    // api_endpoint changed - we are never going to get a token nor move out
-    // of this state.
-    NodeReassigned,
+    // api_endpoint changed. We hold on to the token for the new node, but
+    // refuse to use it until the caller acknowledged the reassignment (and
+    // dropped whatever it knew about the old node).
+    NodeReassigned(TokenContext),
 }
 
 /// The generic TokenProvider implementation - long lived and fetches tokens
@@ -228,7 +230,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                             TokenState::Token(tc)
                         } else {
                             warn!("api_endpoint changed from {} to {}", prev, tc.token.api_endpoint);
-                            TokenState::NodeReassigned
+                            TokenState::NodeReassigned(tc)
                         }
                     },
                     None => {
@@ -275,8 +277,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                     Some(self.fetch_token(request_client, existing_endpoint.as_ref().map(|e| e.as_str())))
                 }
             },
-            TokenState::NodeReassigned => {
-                // We never leave this state.
+            TokenState::NodeReassigned(_) => {
+                // We only leave this state in `acknowledge_node_reassignment`.
                 None
             }
         }
@@ -308,8 +310,8 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
                 // We swap the error out of the state enum and return it.
                 return Err(e.take().unwrap());
             }
-            TokenState::NodeReassigned => {
-                // this is unrecoverable.
+            TokenState::NodeReassigned(_) => {
+                // The caller needs to reset its state before we can go on.
                 return Err(ErrorKind::StorageResetError.into());
             }
             TokenState::Backoff(ref remaining, _) => {
@@ -325,9 +327,35 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn api_endpoint(&self, http_client: &Client) -> Result<String> {
         self.with_token(http_client, |ctx| Ok(ctx.token.api_endpoint.clone()))
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
+
+    // Starts using the token for the new node, if we were reassigned one.
+    // Returns true if that was the case.
+    fn acknowledge_node_reassignment(&self) -> bool {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        match mem::replace(state, TokenState::NoToken) {
+            TokenState::NodeReassigned(tc) => {
+                info!("Switching to the new node {}", tc.token.api_endpoint);
+                *state = TokenState::Token(tc);
+                true
+            }
+            other => {
+                *state = other;
+                false
+            }
+        }
+    }
+
+    // Forgets our token after the storage server rejected it, so the next
+    // call fetches a new one. We keep the endpoint around, so we can tell if
+    // the new token is for another node.
+    fn drop_token(&self) {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        let endpoint = match state {
+            TokenState::Token(tc) => tc.token.api_endpoint.clone(),
+            _ => return,
+        };
+        *state = TokenState::Failed(None, Some(endpoint));
+    }
 }
 
 // The public concrete object exposed by this module
@@ -351,6 +379,19 @@ impl TokenProvider {
     pub fn api_endpoint(&self, http_client: &Client) -> Result<String> {
         self.imp.api_endpoint(http_client)
     }
+
+    /// Once the storage node changed, every call fails with a
+    /// `StorageResetError` until this is called. Returns false if there was
+    /// no reassignment to acknowledge.
+    pub fn acknowledge_node_reassignment(&self) -> bool {
+        self.imp.acknowledge_node_reassignment()
+    }
+
+    /// Drops our token, so the next call fetches a new one. Used when the
+    /// storage server rejects the token we have.
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
+    }
 }
 
 #[cfg(test)]
@@ -484,4 +525,82 @@ mod tests {
         tsc.api_endpoint(&make_client()).expect("should re-fetch");
         assert_eq!(counter.get(), 2);
     }
+
+    #[test]
+    fn test_node_reassignment() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: format!("api_endpoint_{}", if counter.get() == 1 { 1 } else { 2 }),
+                    uid: 1,
+                    duration: 10,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let now: Cell<SystemTime> = Cell::new(SystemTime::now());
+        let tsc = make_tsc(fetch, || {now.get()});
+
+        assert_eq!(tsc.api_endpoint(&make_client()).unwrap(), "api_endpoint_1");
+        assert!(!tsc.acknowledge_node_reassignment());
+
+        // Our token expires, and the new one points to another node.
+        now.set(now.get() + Duration::new(20, 0));
+        let err = tsc.api_endpoint(&make_client()).expect_err("should bail");
+        match err.kind() {
+            ErrorKind::StorageResetError => {},
+            _ => panic!("Wrong error: {}", err),
+        }
+        // We keep failing, without re-fetching, until we're told to move on.
+        tsc.api_endpoint(&make_client()).expect_err("should bail");
+        assert_eq!(counter.get(), 2);
+
+        assert!(tsc.acknowledge_node_reassignment());
+        assert_eq!(tsc.api_endpoint(&make_client()).unwrap(), "api_endpoint_2");
+        assert_eq!(counter.get(), 2);
+        assert!(!tsc.acknowledge_node_reassignment());
+    }
+
+    #[test]
+    fn test_drop_token() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: format!("api_endpoint_{}", if counter.get() < 3 { 1 } else { 2 }),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let tsc = make_tsc(fetch, SystemTime::now);
+
+        assert_eq!(tsc.api_endpoint(&make_client()).unwrap(), "api_endpoint_1");
+        assert_eq!(counter.get(), 1);
+
+        // Dropping a valid token fetches a new one on the same node.
+        tsc.drop_token();
+        assert_eq!(tsc.api_endpoint(&make_client()).unwrap(), "api_endpoint_1");
+        assert_eq!(counter.get(), 2);
+
+        // If the new token is for another node, we report the reassignment.
+        tsc.drop_token();
+        let err = tsc.api_endpoint(&make_client()).expect_err("should bail");
+        match err.kind() {
+            ErrorKind::StorageResetError => {},
+            _ => panic!("Wrong error: {}", err),
+        }
+        assert!(tsc.acknowledge_node_reassignment());
+        assert_eq!(tsc.api_endpoint(&make_client()).unwrap(), "api_endpoint_2");
+    }
 }
@@ -0,0 +1,375 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+use std::mem;
+
+use sync15_adapter as sync;
+use self::sync::{IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp};
+
+use merge::{merge, MergedItem};
+use record::BookmarkRecord;
+use tree::{Tree, TreeItem};
+
+const COLLECTION_NAME: &str = "bookmarks";
+
+/// The sortindex of the content roots. Each level down the tree gets one
+/// less, so that clients that download by sortindex get folders before their
+/// contents.
+const ROOT_SORTINDEX: i32 = 1_000_000;
+
+/// The local bookmarks database, implemented by the app.
+pub trait BookmarksStore {
+    /// Returns every local bookmark, folder, etc., including the content
+    /// roots. Items changed since the last sync must have `needs_merge` set.
+    fn local_items(&self) -> sync::Result<Vec<TreeItem>>;
+
+    /// Returns the IDs of the items deleted locally since the last sync.
+    fn local_deletions(&self) -> sync::Result<Vec<String>>;
+
+    /// Returns the mirror: the server's records as of the last sync, as
+    /// passed to `finish_sync`.
+    fn mirror(&self) -> sync::Result<Vec<TreeItem>>;
+
+    /// Updates the local tree to match the merged tree. `items` come parents
+    /// first, with their children in order. This must not change which items
+    /// need to be merged; we clear that once the server has the merged tree.
+    fn apply(&mut self, items: &[BookmarkRecord], deletions: &[String]) -> sync::Result<()>;
+
+    /// Called once the server has the merged tree. Replaces the mirror with
+    /// `mirror`, clears the changed and deleted flags of `synced`, and stores
+    /// the new last sync time.
+    fn finish_sync(
+        &mut self,
+        mirror: Vec<TreeItem>,
+        synced: &[String],
+        last_sync: ServerTimestamp,
+    ) -> sync::Result<()>;
+
+    fn last_sync(&self) -> sync::Result<ServerTimestamp>;
+
+    /// Forgets the mirror and the last sync time, and marks every item as
+    /// changed, so that the next sync merges the whole tree again.
+    fn reset(&mut self) -> sync::Result<()>;
+}
+
+// The merged tree, kept until the server has it.
+struct PendingSync {
+    mirror: Vec<TreeItem>,
+    synced: Vec<String>,
+}
+
+pub struct BookmarksEngine<S> {
+    store: S,
+    // We can only merge once we have the whole remote tree, so we keep the
+    // incoming records, by ID, until every page is downloaded.
+    incoming: HashMap<String, TreeItem>,
+    incoming_deletions: HashSet<String>,
+    pending: Option<PendingSync>,
+}
+
+impl<S: BookmarksStore> BookmarksEngine<S> {
+    pub fn new(store: S) -> BookmarksEngine<S> {
+        BookmarksEngine {
+            store,
+            incoming: HashMap::new(),
+            incoming_deletions: HashSet::new(),
+            pending: None,
+        }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    // Builds the remote tree from the mirror and the incoming records, and
+    // returns it with the IDs of the incoming tombstones.
+    fn remote_tree(&mut self) -> sync::Result<(Tree, HashSet<String>)> {
+        let mut items: HashMap<String, TreeItem> = self.store
+            .mirror()?
+            .into_iter()
+            .map(|item| (item.record.id.clone(), item))
+            .collect();
+        let deletions = mem::replace(&mut self.incoming_deletions, HashSet::new());
+        for guid in &deletions {
+            items.remove(guid);
+        }
+        items.extend(self.incoming.drain());
+        Ok((Tree::from_items(items.into_iter().map(|(_, item)| item)), deletions))
+    }
+}
+
+fn sortindex(item: &MergedItem) -> i32 {
+    ROOT_SORTINDEX + 1 - item.depth as i32
+}
+
+impl<S: BookmarksStore> sync::Store for BookmarksEngine<S> {
+    type Error = sync::Error;
+
+    fn apply_incoming(&mut self, inbound: IncomingChangeset) -> sync::Result<()> {
+        for (payload, modified) in inbound.changes {
+            if payload.is_tombstone() {
+                self.incoming.remove(payload.id());
+                self.incoming_deletions.insert(payload.id().to_string());
+                continue;
+            }
+            let record: BookmarkRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring malformed bookmark record: {}", e);
+                    continue;
+                }
+            };
+            self.incoming_deletions.remove(&record.id);
+            self.incoming.insert(record.id.clone(), TreeItem {
+                record,
+                modified: modified.as_millis(),
+                needs_merge: true,
+            });
+        }
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> sync::Result<OutgoingChangeset> {
+        info!("Merging {} incoming bookmark records", self.incoming.len() + self.incoming_deletions.len());
+        let (remote, remote_deletions) = self.remote_tree()?;
+        let local = Tree::from_items(self.store.local_items()?);
+        let local_deletions: HashSet<String> = self.store.local_deletions()?.into_iter().collect();
+        let result = merge(&local, &local_deletions, &remote, &remote_deletions);
+
+        let applied: Vec<BookmarkRecord> = result.items
+            .iter()
+            .filter(|item| item.apply_locally)
+            .map(|item| item.record.clone())
+            .collect();
+        info!("Applying {} merged items and {} deletions", applied.len(), result.local_deletions.len());
+        self.store.apply(&applied, &result.local_deletions)?;
+
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), self.store.last_sync()?);
+        for item in result.items.iter().filter(|item| item.upload) {
+            let mut payload = Payload::from_record(item.record.clone())?;
+            payload.sortindex = Some(sortindex(item));
+            outgoing.changes.push(payload);
+        }
+        for guid in &result.remote_deletions {
+            outgoing.changes.push(Payload::new_tombstone(guid.clone()));
+        }
+
+        // Once the upload finishes, the server has the merged tree. We only
+        // clear the flags of items that we applied, uploaded, or read as
+        // changed: anything else might have changed locally since we read the
+        // tree, and still needs to be synced next time.
+        let mirror = result.items
+            .iter()
+            .map(|item| TreeItem {
+                record: item.record.clone(),
+                modified: remote.modified(&item.record.id),
+                needs_merge: false,
+            })
+            .collect();
+        let synced = result.items
+            .into_iter()
+            .filter(|item| item.apply_locally || item.upload || local.needs_merge(&item.record.id))
+            .map(|item| item.record.id)
+            .chain(local_deletions)
+            .collect();
+        self.pending = Some(PendingSync { mirror, synced });
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> sync::Result<()> {
+        let PendingSync { mut mirror, synced } = self.pending
+            .take()
+            .expect("Bug: sync_finished called without outgoing_changes");
+        let uploaded: HashSet<&String> = records_synced.iter().collect();
+        for item in &mut mirror {
+            if uploaded.contains(&item.record.id) {
+                item.modified = new_timestamp.as_millis();
+            }
+        }
+        self.store.finish_sync(mirror, &synced, new_timestamp)
+    }
+}
+
+impl<S: BookmarksStore> sync::SyncEngine for BookmarksEngine<S> {
+    fn collection_name(&self) -> &str {
+        COLLECTION_NAME
+    }
+
+    /// Syncs the bookmarks collection. The upload is fully atomic, so that
+    /// other clients never see half of our changes to the tree.
+    fn sync(
+        &mut self,
+        client: &sync::Sync15StorageClient,
+        state: &sync::GlobalState,
+    ) -> sync::Result<()> {
+        let last_sync = self.store.last_sync()?;
+        // Drop whatever an interrupted download left behind.
+        self.incoming.clear();
+        self.incoming_deletions.clear();
+        sync::synchronize(client, state, self, COLLECTION_NAME.into(), last_sync, true)
+    }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        self.incoming.clear();
+        self.incoming_deletions.clear();
+        self.pending = None;
+        self.store.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sync15_adapter as sync;
+    use self::sync::{Store, SERVER_EPOCH};
+    use tree::tests::{bookmark, folder};
+
+    #[derive(Default)]
+    struct MemoryStore {
+        items: HashMap<String, TreeItem>,
+        deleted: HashSet<String>,
+        mirror: Vec<TreeItem>,
+        last_sync: ServerTimestamp,
+    }
+
+    impl BookmarksStore for MemoryStore {
+        fn local_items(&self) -> sync::Result<Vec<TreeItem>> {
+            Ok(self.items.values().cloned().collect())
+        }
+
+        fn local_deletions(&self) -> sync::Result<Vec<String>> {
+            Ok(self.deleted.iter().cloned().collect())
+        }
+
+        fn mirror(&self) -> sync::Result<Vec<TreeItem>> {
+            Ok(self.mirror.clone())
+        }
+
+        fn apply(&mut self, items: &[BookmarkRecord], deletions: &[String]) -> sync::Result<()> {
+            for record in items {
+                let needs_merge = self.items.get(&record.id).map_or(false, |item| item.needs_merge);
+                self.items.insert(record.id.clone(), TreeItem {
+                    record: record.clone(),
+                    modified: 0,
+                    needs_merge,
+                });
+            }
+            for guid in deletions {
+                self.items.remove(guid);
+            }
+            Ok(())
+        }
+
+        fn finish_sync(
+            &mut self,
+            mirror: Vec<TreeItem>,
+            synced: &[String],
+            last_sync: ServerTimestamp,
+        ) -> sync::Result<()> {
+            self.mirror = mirror;
+            for guid in synced {
+                if let Some(item) = self.items.get_mut(guid) {
+                    item.needs_merge = false;
+                }
+                self.deleted.remove(guid);
+            }
+            self.last_sync = last_sync;
+            Ok(())
+        }
+
+        fn last_sync(&self) -> sync::Result<ServerTimestamp> {
+            Ok(self.last_sync)
+        }
+
+        fn reset(&mut self) -> sync::Result<()> {
+            for item in self.items.values_mut() {
+                item.needs_merge = true;
+            }
+            self.mirror.clear();
+            self.last_sync = SERVER_EPOCH;
+            Ok(())
+        }
+    }
+
+    fn record(item: TreeItem) -> (Payload, ServerTimestamp) {
+        (Payload::from_record(item.record).unwrap(), ServerTimestamp(10.0))
+    }
+
+    #[test]
+    fn test_first_sync() {
+        let mut store = MemoryStore::default();
+        for mut item in vec![
+            folder("menu", "places", &["bookmarkAAAA"]),
+            folder("toolbar", "places", &[]),
+            folder("unfiled", "places", &[]),
+            folder("mobile", "places", &[]),
+            bookmark("bookmarkAAAA", "menu"),
+        ] {
+            item.needs_merge = true;
+            store.items.insert(item.record.id.clone(), item);
+        }
+        let mut engine = BookmarksEngine::new(store);
+
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(10.0));
+        inbound.changes.push(record(folder("toolbar", "places", &["bookmarkBBBB"])));
+        inbound.changes.push(record(bookmark("bookmarkBBBB", "toolbar")));
+        engine.apply_incoming(inbound).unwrap();
+        let outgoing = engine.outgoing_changes().unwrap();
+
+        assert_eq!(engine.store().items["toolbar"].record.children, vec!["bookmarkBBBB"]);
+        assert!(engine.store().items.contains_key("bookmarkBBBB"));
+
+        assert_eq!(outgoing.timestamp, SERVER_EPOCH);
+        assert_eq!(
+            outgoing.changes.iter().map(|payload| payload.id()).collect::<Vec<_>>(),
+            vec!["menu", "bookmarkAAAA", "unfiled", "mobile"]
+        );
+        assert_eq!(outgoing.changes[0].sortindex, Some(ROOT_SORTINDEX));
+        assert_eq!(outgoing.changes[1].sortindex, Some(ROOT_SORTINDEX - 1));
+        let uploaded: BookmarkRecord = outgoing.changes[1].clone().into_record().unwrap();
+        assert_eq!(uploaded.parent_id, "menu");
+
+        let synced: Vec<String> = outgoing.changes.iter().map(|payload| payload.id.clone()).collect();
+        engine.sync_finished(ServerTimestamp(11.0), &synced).unwrap();
+        assert!(engine.store().items.values().all(|item| !item.needs_merge));
+        assert_eq!(engine.store().mirror.len(), 6);
+        assert_eq!(engine.store().last_sync, ServerTimestamp(11.0));
+        let modified: HashMap<&str, u64> = engine.store().mirror
+            .iter()
+            .map(|item| (item.record.id.as_str(), item.modified))
+            .collect();
+        assert_eq!(modified["bookmarkAAAA"], 11000);
+        assert_eq!(modified["bookmarkBBBB"], 10000);
+
+        // The folder on the server still lists the deleted bookmark, so we
+        // fix it.
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(12.0));
+        inbound.changes.push((Payload::new_tombstone("bookmarkAAAA".into()), ServerTimestamp(12.0)));
+        engine.apply_incoming(inbound).unwrap();
+        let outgoing = engine.outgoing_changes().unwrap();
+        assert!(!engine.store().items.contains_key("bookmarkAAAA"));
+        assert!(engine.store().items["menu"].record.children.is_empty());
+        assert_eq!(outgoing.timestamp, ServerTimestamp(11.0));
+        assert_eq!(outgoing.changes.len(), 1);
+        let uploaded: BookmarkRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.id, "menu");
+        assert!(uploaded.children.is_empty());
+
+        // The toolbar changed locally while we were uploading. We didn't
+        // apply or upload it, so it still needs to be synced.
+        engine.store_mut().items.get_mut("toolbar").unwrap().needs_merge = true;
+        engine.sync_finished(ServerTimestamp(13.0), &["menu".to_string()]).unwrap();
+        assert!(!engine.store().items["menu"].needs_merge);
+        assert!(engine.store().items["toolbar"].needs_merge);
+    }
+}
@@ -0,0 +1,25 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `bookmarks` engine, which merges the bookmarks tree on the server
+//! with the one in a `BookmarksStore` implemented by the app.
+
+#![crate_name = "sync15_bookmarks"]
+
+#[macro_use] extern crate log;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+extern crate sync15_adapter;
+
+pub mod engine;
+pub use engine::{BookmarksEngine, BookmarksStore};
+pub mod merge;
+pub use merge::{merge, MergeResult, MergedItem};
+pub mod record;
+pub use record::{BookmarkKind, BookmarkRecord};
+pub mod tree;
+pub use tree::{Tree, TreeItem};
@@ -0,0 +1,470 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+
+use record::{BookmarkKind, BookmarkRecord};
+use tree::{is_root, Tree, CONTENT_ROOTS, ROOT_GUID, UNFILED_GUID};
+
+/// An item in the merged tree.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MergedItem {
+    /// The item as it should be on both sides, with the parent, children and
+    /// position it has in the merged tree.
+    pub record: BookmarkRecord,
+    /// How far the item is from the Places root. The content roots are at
+    /// depth 1.
+    pub depth: usize,
+    /// Whether the local tree needs to change to match.
+    pub apply_locally: bool,
+    /// Whether the server needs to change to match.
+    pub upload: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MergeResult {
+    /// Everything in the merged tree except the Places root, parents before
+    /// their children.
+    pub items: Vec<MergedItem>,
+    /// Items to delete locally.
+    pub local_deletions: Vec<String>,
+    /// Items to delete on the server.
+    pub remote_deletions: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Side {
+    Local,
+    Remote,
+}
+
+impl Side {
+    fn other(self) -> Side {
+        match self {
+            Side::Local => Side::Remote,
+            Side::Remote => Side::Local,
+        }
+    }
+}
+
+/// Merges the local and remote trees into one that both sides can agree on.
+/// `local_deletions` are the items deleted locally since the last sync, and
+/// `remote_deletions` are the tombstones we downloaded.
+///
+/// Each item takes its content from the side that changed it, and its parent
+/// from the side that moved it or changed its old folder. If both sides did,
+/// the most recent change wins; if neither did, the server wins. Folders list
+/// their children in the winning side's order, followed by the children that
+/// only the other side knows about.
+///
+/// Deleting an item wins unless the other side changed it since. Deleted
+/// folders stay deleted, but anything the other side added to them or changed
+/// moves to the closest folder that's still around.
+pub fn merge(
+    local: &Tree,
+    local_deletions: &HashSet<String>,
+    remote: &Tree,
+    remote_deletions: &HashSet<String>,
+) -> MergeResult {
+    let mut merger = Merger {
+        local,
+        remote,
+        deleted: HashSet::new(),
+        parents: HashMap::new(),
+    };
+    merger.merge_deletions(local_deletions, remote_deletions);
+    merger.merge_parents();
+    merger.break_cycles();
+    merger.into_result(remote_deletions)
+}
+
+struct Merger<'t> {
+    local: &'t Tree,
+    remote: &'t Tree,
+    // Items that stay deleted.
+    deleted: HashSet<String>,
+    // The parent of every item in the merged tree, except the Places root.
+    parents: HashMap<String, String>,
+}
+
+impl<'t> Merger<'t> {
+    fn tree(&self, side: Side) -> &'t Tree {
+        match side {
+            Side::Local => self.local,
+            Side::Remote => self.remote,
+        }
+    }
+
+    // The side that changed wins. If both did, the most recent change wins,
+    // and if neither did, the server does.
+    fn pick_side(
+        &self,
+        local_changed: bool,
+        local_modified: u64,
+        remote_changed: bool,
+        remote_modified: u64,
+    ) -> Side {
+        match (local_changed, remote_changed) {
+            (true, false) => Side::Local,
+            (true, true) if local_modified > remote_modified => Side::Local,
+            _ => Side::Remote,
+        }
+    }
+
+    fn content_side(&self, guid: &str) -> Side {
+        match (self.local.item(guid), self.remote.item(guid)) {
+            (Some(_), None) => Side::Local,
+            (None, _) => Side::Remote,
+            (Some(local), Some(remote)) => self.pick_side(
+                local.needs_merge,
+                local.modified,
+                remote.needs_merge,
+                remote.modified,
+            ),
+        }
+    }
+
+    fn content(&self, guid: &str) -> Option<&'t BookmarkRecord> {
+        self.tree(self.content_side(guid)).item(guid).map(|item| &item.record)
+    }
+
+    fn is_live(&self, guid: &str) -> bool {
+        !self.deleted.contains(guid) && (self.local.contains(guid) || self.remote.contains(guid))
+    }
+
+    fn can_hold(&self, guid: &str) -> bool {
+        guid != ROOT_GUID && self.is_live(guid) && (
+            CONTENT_ROOTS.contains(&guid) ||
+            self.content(guid).map_or(false, |record| record.kind.is_folder())
+        )
+    }
+
+    fn merge_deletions(
+        &mut self,
+        local_deletions: &HashSet<String>,
+        remote_deletions: &HashSet<String>,
+    ) {
+        let mut deletions: Vec<(&String, Side)> = local_deletions
+            .iter()
+            .map(|guid| (guid, Side::Local))
+            .chain(remote_deletions.iter().map(|guid| (guid, Side::Remote)))
+            .collect();
+        deletions.sort_by(|a, b| a.0.cmp(b.0));
+        for (guid, side) in deletions {
+            if is_root(guid) {
+                warn!("Ignoring deletion of root {}", guid);
+                continue;
+            }
+            let other = self.tree(side.other());
+            let changed_item = other.item(guid)
+                .map_or(false, |item| item.needs_merge && !item.record.kind.is_folder());
+            if changed_item {
+                debug!("Keeping {}, which was deleted on one side but changed on the other", guid);
+                continue;
+            }
+            for descendant in other.descendants(guid) {
+                if descendant == *guid || !other.needs_merge(&descendant) {
+                    self.deleted.insert(descendant);
+                }
+            }
+        }
+    }
+
+    fn merge_parents(&mut self) {
+        let mut guids: Vec<String> = self.local
+            .items()
+            .chain(self.remote.items())
+            .map(|item| item.record.id.clone())
+            .filter(|guid| !is_root(guid) && self.is_live(guid))
+            .collect();
+        guids.sort();
+        guids.dedup();
+        for guid in guids {
+            let parent = self.merged_parent(&guid);
+            self.parents.insert(guid, parent);
+        }
+        for root in &CONTENT_ROOTS {
+            self.parents.insert(root.to_string(), ROOT_GUID.to_string());
+        }
+    }
+
+    fn merged_parent(&self, guid: &str) -> String {
+        let (side, mut parent) = match (self.local.parent(guid), self.remote.parent(guid)) {
+            (Some(local), Some(remote)) if local == remote => (Side::Remote, remote),
+            (Some(local), Some(remote)) => {
+                let side = self.pick_side(
+                    self.local.needs_merge(guid) || self.local.needs_merge(local),
+                    self.local.modified(guid).max(self.local.modified(local)),
+                    self.remote.needs_merge(guid) || self.remote.needs_merge(remote),
+                    self.remote.modified(guid).max(self.remote.modified(remote)),
+                );
+                (side, if side == Side::Local { local } else { remote })
+            }
+            (Some(local), None) => (Side::Local, local),
+            (_, remote) => (Side::Remote, remote.unwrap_or(UNFILED_GUID)),
+        };
+        // The parent might be deleted, or not be a folder anymore. If so, we
+        // move the item up to the closest folder that's still around.
+        while !self.can_hold(parent) {
+            let grandparent = self.tree(side)
+                .parent(parent)
+                .or_else(|| self.tree(side.other()).parent(parent));
+            parent = match grandparent {
+                Some(grandparent) => grandparent,
+                None => return UNFILED_GUID.to_string(),
+            };
+        }
+        parent.to_string()
+    }
+
+    // Moving folders on both sides can make a cycle, like A in B on one side
+    // and B in A on the other. We break those by moving a folder in the cycle
+    // to `unfiled`.
+    fn break_cycles(&mut self) {
+        let mut guids: Vec<String> = self.parents.keys().cloned().collect();
+        guids.sort();
+        for guid in guids {
+            let mut current = guid.clone();
+            for _ in 0..self.parents.len() {
+                current = match self.parents.get(&current) {
+                    Some(parent) => parent.clone(),
+                    None => break,
+                };
+                if current == guid {
+                    warn!("Moving {} to {} to break a cycle", guid, UNFILED_GUID);
+                    self.parents.insert(guid.clone(), UNFILED_GUID.to_string());
+                    break;
+                }
+            }
+        }
+    }
+
+    fn merged_children(&self, folder: &str, candidates: &HashSet<&str>) -> Vec<String> {
+        let side = self.pick_side(
+            self.local.needs_merge(folder),
+            self.local.modified(folder),
+            self.remote.needs_merge(folder),
+            self.remote.modified(folder),
+        );
+        let mut children = Vec::with_capacity(candidates.len());
+        let mut added = HashSet::with_capacity(candidates.len());
+        for side in &[side, side.other()] {
+            for child in self.tree(*side).children(folder) {
+                if candidates.contains(child.as_str()) && added.insert(child.as_str()) {
+                    children.push(child.clone());
+                }
+            }
+        }
+        // Items moved here from deleted folders, or to break a cycle.
+        let mut rest: Vec<&str> = candidates
+            .iter()
+            .filter(|child| !added.contains(*child))
+            .cloned()
+            .collect();
+        rest.sort();
+        children.extend(rest.into_iter().map(String::from));
+        children
+    }
+
+    fn merged_item(
+        &self,
+        guid: &str,
+        parent: String,
+        children: Vec<String>,
+        position: usize,
+        depth: usize,
+    ) -> MergedItem {
+        let mut record = match self.content(guid) {
+            Some(record) => record.clone(),
+            // Only roots can be missing on both sides.
+            None => BookmarkRecord::new(guid.to_string(), BookmarkKind::Folder),
+        };
+        record.parent_id = parent;
+        if record.kind.is_folder() {
+            record.children = children;
+        }
+        if record.kind == BookmarkKind::Separator {
+            record.pos = Some(position as u32);
+        }
+        let (apply_locally, upload) = {
+            let differs = |tree: &Tree| {
+                tree.item(guid).map_or(true, |item| !item.record.same_item(&record))
+            };
+            (differs(self.local), differs(self.remote))
+        };
+        MergedItem { record, depth, apply_locally, upload }
+    }
+
+    fn into_result(self, remote_deletions: &HashSet<String>) -> MergeResult {
+        let mut candidates: HashMap<&str, HashSet<&str>> = HashMap::new();
+        for (child, parent) in &self.parents {
+            candidates.entry(parent.as_str()).or_insert_with(HashSet::new).insert(child.as_str());
+        }
+
+        let mut result = MergeResult::default();
+        // Each entry is an item, its parent, its position in the parent, and
+        // its depth.
+        let mut stack = vec![(ROOT_GUID.to_string(), String::new(), 0, 0)];
+        while let Some((guid, parent, position, depth)) = stack.pop() {
+            let children = match candidates.get(guid.as_str()) {
+                Some(candidates) => self.merged_children(&guid, candidates),
+                None => Vec::new(),
+            };
+            for (i, child) in children.iter().enumerate().rev() {
+                stack.push((child.clone(), guid.clone(), i, depth + 1));
+            }
+            if guid != ROOT_GUID {
+                result.items.push(self.merged_item(&guid, parent, children, position, depth));
+            }
+        }
+
+        let mut deleted: Vec<&String> = self.deleted.iter().collect();
+        deleted.sort();
+        for guid in deleted {
+            if self.local.contains(guid) {
+                result.local_deletions.push(guid.clone());
+            }
+            // The server already has tombstones for the remote deletions.
+            if !remote_deletions.contains(guid) {
+                result.remote_deletions.push(guid.clone());
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tree::TreeItem;
+    use tree::tests::{bookmark, folder};
+
+    fn roots(menu: &[&str], toolbar: &[&str]) -> Vec<TreeItem> {
+        vec![
+            folder("menu", "places", menu),
+            folder("toolbar", "places", toolbar),
+            folder("unfiled", "places", &[]),
+            folder("mobile", "places", &[]),
+        ]
+    }
+
+    fn changed(mut item: TreeItem, modified: u64) -> TreeItem {
+        item.modified = modified;
+        item.needs_merge = true;
+        item
+    }
+
+    fn tree(roots: Vec<TreeItem>, items: Vec<TreeItem>) -> Tree {
+        Tree::from_items(roots.into_iter().chain(items))
+    }
+
+    fn find<'a>(result: &'a MergeResult, guid: &str) -> &'a MergedItem {
+        result.items.iter().find(|item| item.record.id == guid).unwrap()
+    }
+
+    fn guids(items: &[MergedItem], filter: fn(&MergedItem) -> bool) -> Vec<&str> {
+        items.iter().filter(|item| filter(item)).map(|item| item.record.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_merge_moves() {
+        let mut local_roots = roots(&["folderAAAAAA", "bookmarkCCCC"], &[]);
+        local_roots[0] = changed(local_roots[0].clone(), 5);
+        let local = tree(local_roots, vec![
+            changed(folder("folderAAAAAA", "menu", &["bookmarkAAAA", "bookmarkBBBB", "bookmarkDDDD"]), 5),
+            bookmark("bookmarkAAAA", "folderAAAAAA"),
+            bookmark("bookmarkBBBB", "folderAAAAAA"),
+            bookmark("bookmarkCCCC", "menu"),
+            changed(bookmark("bookmarkDDDD", "folderAAAAAA"), 5),
+        ]);
+        let mut remote_roots = roots(&["folderAAAAAA"], &["bookmarkCCCC"]);
+        remote_roots[0] = changed(remote_roots[0].clone(), 10);
+        remote_roots[1] = changed(remote_roots[1].clone(), 10);
+        let remote = tree(remote_roots, vec![
+            changed(folder("folderAAAAAA", "menu", &["bookmarkBBBB", "bookmarkAAAA"]), 10),
+            bookmark("bookmarkAAAA", "folderAAAAAA"),
+            bookmark("bookmarkBBBB", "folderAAAAAA"),
+            changed(bookmark("bookmarkCCCC", "toolbar"), 10),
+        ]);
+
+        let result = merge(&local, &HashSet::new(), &remote, &HashSet::new());
+        assert_eq!(
+            result.items.iter().map(|item| (item.record.id.as_str(), item.depth)).collect::<Vec<_>>(),
+            vec![
+                ("menu", 1),
+                ("folderAAAAAA", 2),
+                ("bookmarkBBBB", 3),
+                ("bookmarkAAAA", 3),
+                ("bookmarkDDDD", 3),
+                ("toolbar", 1),
+                ("bookmarkCCCC", 2),
+                ("unfiled", 1),
+                ("mobile", 1),
+            ]
+        );
+        // The server's order wins, and our new bookmark goes at the end.
+        assert_eq!(
+            find(&result, "folderAAAAAA").record.children,
+            vec!["bookmarkBBBB", "bookmarkAAAA", "bookmarkDDDD"]
+        );
+        assert_eq!(find(&result, "bookmarkCCCC").record.parent_id, "toolbar");
+        assert_eq!(
+            guids(&result.items, |item| item.apply_locally),
+            vec!["menu", "folderAAAAAA", "toolbar", "bookmarkCCCC"]
+        );
+        assert_eq!(
+            guids(&result.items, |item| item.upload),
+            vec!["folderAAAAAA", "bookmarkDDDD"]
+        );
+        assert!(result.local_deletions.is_empty());
+        assert!(result.remote_deletions.is_empty());
+    }
+
+    #[test]
+    fn test_merge_deletions() {
+        let local = tree(roots(&["folderAAAAAA"], &[]), vec![
+            changed(folder("folderAAAAAA", "menu", &["bookmarkAAAA", "bookmarkBBBB"]), 5),
+            bookmark("bookmarkAAAA", "folderAAAAAA"),
+            // Added to a folder that's deleted on the server.
+            changed(bookmark("bookmarkBBBB", "folderAAAAAA"), 5),
+        ]);
+        let local_deletions: HashSet<String> = vec!["bookmarkCCCC".to_string(), "bookmarkDDDD".to_string()]
+            .into_iter()
+            .collect();
+        let mut remote_roots = roots(&["bookmarkCCCC", "bookmarkDDDD"], &[]);
+        remote_roots[0] = changed(remote_roots[0].clone(), 10);
+        let remote = tree(remote_roots, vec![
+            // Changed on the server after we deleted it.
+            changed(bookmark("bookmarkCCCC", "menu"), 10),
+            bookmark("bookmarkDDDD", "menu"),
+        ]);
+        let remote_deletions: HashSet<String> = vec!["folderAAAAAA".to_string()].into_iter().collect();
+
+        let result = merge(&local, &local_deletions, &remote, &remote_deletions);
+        assert_eq!(find(&result, "menu").record.children, vec!["bookmarkCCCC", "bookmarkBBBB"]);
+        assert!(find(&result, "bookmarkCCCC").apply_locally);
+        assert_eq!(find(&result, "bookmarkBBBB").record.parent_id, "menu");
+        assert!(find(&result, "bookmarkBBBB").upload);
+        assert_eq!(result.local_deletions, vec!["bookmarkAAAA", "folderAAAAAA"]);
+        assert_eq!(result.remote_deletions, vec!["bookmarkAAAA", "bookmarkDDDD"]);
+    }
+
+    #[test]
+    fn test_merge_cycle() {
+        let local = tree(roots(&["folderAAAAAA"], &[]), vec![
+            folder("folderAAAAAA", "menu", &["folderBBBBBB"]),
+            changed(folder("folderBBBBBB", "folderAAAAAA", &[]), 5),
+        ]);
+        let remote = tree(roots(&["folderBBBBBB"], &[]), vec![
+            folder("folderBBBBBB", "menu", &["folderAAAAAA"]),
+            changed(folder("folderAAAAAA", "folderBBBBBB", &[]), 10),
+        ]);
+
+        let result = merge(&local, &HashSet::new(), &remote, &HashSet::new());
+        assert!(find(&result, "menu").record.children.is_empty());
+        assert_eq!(find(&result, "unfiled").record.children, vec!["folderAAAAAA"]);
+        assert_eq!(find(&result, "folderAAAAAA").record.children, vec!["folderBBBBBB"]);
+        assert!(find(&result, "folderBBBBBB").record.children.is_empty());
+    }
+}
@@ -0,0 +1,147 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum BookmarkKind {
+    Bookmark,
+    Query,
+    Folder,
+    Livemark,
+    Separator,
+}
+
+impl BookmarkKind {
+    /// Whether items of this kind have children. Livemarks are folders in
+    /// Places, but their children come from the feed and aren't synced.
+    pub fn is_folder(self) -> bool {
+        self == BookmarkKind::Folder
+    }
+}
+
+/// A record in the `bookmarks` collection. Which of the optional fields are
+/// set depends on `kind`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarkRecord {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: BookmarkKind,
+    #[serde(rename = "parentid", default)]
+    pub parent_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_name: Option<String>,
+    /// When the item was added, in milliseconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date_added: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// The URL of a bookmark or query.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bmk_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub folder_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub feed_uri: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub site_uri: Option<String>,
+    /// The IDs of a folder's children, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<String>,
+    /// A separator's position in its folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pos: Option<u32>,
+}
+
+impl BookmarkRecord {
+    pub fn new(id: String, kind: BookmarkKind) -> BookmarkRecord {
+        BookmarkRecord {
+            id,
+            kind,
+            parent_id: String::new(),
+            parent_name: None,
+            date_added: None,
+            title: None,
+            bmk_uri: None,
+            description: None,
+            keyword: None,
+            tags: vec![],
+            folder_name: None,
+            query_id: None,
+            feed_uri: None,
+            site_uri: None,
+            children: vec![],
+            pos: None,
+        }
+    }
+
+    /// Whether `self` and `other` describe the same item in the same place.
+    /// `parentName` and `dateAdded` are only informational, so they don't
+    /// count.
+    pub fn same_item(&self, other: &BookmarkRecord) -> bool {
+        let without_info = |record: &BookmarkRecord| BookmarkRecord {
+            parent_name: None,
+            date_added: None,
+            ..record.clone()
+        };
+        without_info(self) == without_info(other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_bookmark_record() {
+        let record: BookmarkRecord = serde_json::from_value(json!({
+            "id": "AAAAAAAAAAAA",
+            "type": "bookmark",
+            "parentid": "menu",
+            "parentName": "Bookmarks Menu",
+            "title": "Example",
+            "bmkUri": "https://example.com/",
+            "tags": ["a", "b"],
+        })).unwrap();
+        assert_eq!(record.kind, BookmarkKind::Bookmark);
+        assert_eq!(record.parent_id, "menu");
+        assert_eq!(record.bmk_uri, Some("https://example.com/".into()));
+
+        let mut moved = record.clone();
+        moved.parent_name = Some("Other Bookmarks".into());
+        assert!(moved.same_item(&record));
+        moved.parent_id = "unfiled".into();
+        assert!(!moved.same_item(&record));
+
+        let record: BookmarkRecord = serde_json::from_value(json!({
+            "id": "BBBBBBBBBBBB",
+            "type": "folder",
+            "parentid": "toolbar",
+            "title": "Folder",
+            "children": ["AAAAAAAAAAAA"],
+        })).unwrap();
+        assert!(record.kind.is_folder());
+        assert_eq!(serde_json::to_value(&record).unwrap(), json!({
+            "id": "BBBBBBBBBBBB",
+            "type": "folder",
+            "parentid": "toolbar",
+            "title": "Folder",
+            "children": ["AAAAAAAAAAAA"],
+        }));
+
+        assert!(serde_json::from_value::<BookmarkRecord>(json!({
+            "id": "CCCCCCCCCCCC",
+            "type": "microsummary",
+        })).is_err());
+    }
+}
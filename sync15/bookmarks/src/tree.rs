@@ -0,0 +1,255 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use record::BookmarkRecord;
+
+/// The Places root. It holds the content roots, and is never synced.
+pub const ROOT_GUID: &str = "places";
+pub const MENU_GUID: &str = "menu";
+pub const TOOLBAR_GUID: &str = "toolbar";
+pub const UNFILED_GUID: &str = "unfiled";
+pub const MOBILE_GUID: &str = "mobile";
+
+/// The folders that hold everyone's bookmarks, in the order they appear in
+/// the Places root.
+pub const CONTENT_ROOTS: [&str; 4] = [MENU_GUID, TOOLBAR_GUID, UNFILED_GUID, MOBILE_GUID];
+
+pub fn is_root(guid: &str) -> bool {
+    guid == ROOT_GUID || CONTENT_ROOTS.contains(&guid)
+}
+
+/// A bookmark, folder, etc., as stored locally or on the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TreeItem {
+    pub record: BookmarkRecord,
+    /// When the item last changed, in milliseconds since the epoch. If both
+    /// sides changed an item, the most recent change wins.
+    pub modified: u64,
+    /// Whether the item changed since the last sync.
+    pub needs_merge: bool,
+}
+
+/// A bookmarks tree built from records, whose parents and children don't
+/// always agree. Every item ends up with exactly one parent.
+#[derive(Clone, Debug, Default)]
+pub struct Tree {
+    items: HashMap<String, TreeItem>,
+    parents: HashMap<String, String>,
+    children: HashMap<String, Vec<String>>,
+}
+
+impl Tree {
+    /// Builds a tree from `items`. Folders' `children` decide where items go;
+    /// if two folders claim an item, the first one we see keeps it. Items that
+    /// no folder claims go to the folder named by their `parentid`, or to
+    /// `unfiled` if that folder isn't in the tree.
+    pub fn from_items<I: IntoIterator<Item = TreeItem>>(items: I) -> Tree {
+        let mut tree = Tree::default();
+        for item in items {
+            tree.items.insert(item.record.id.clone(), item);
+        }
+        // The content roots always live in the Places root, whatever their
+        // records say.
+        for root in &CONTENT_ROOTS {
+            tree.attach(ROOT_GUID, root);
+        }
+        for root in &CONTENT_ROOTS {
+            tree.claim_children(root);
+        }
+
+        let mut orphans: Vec<String> = tree.items
+            .keys()
+            .filter(|guid| !is_root(guid) && !tree.parents.contains_key(*guid))
+            .cloned()
+            .collect();
+        orphans.sort();
+        // Orphans whose parent is in the tree can go there right away. The
+        // others wait, by `parentid`, until their parent gets placed.
+        let mut ready = VecDeque::new();
+        let mut waiting: HashMap<String, Vec<String>> = HashMap::new();
+        for guid in &orphans {
+            let parent_id = &tree.items[guid].record.parent_id;
+            if tree.can_hold(parent_id) {
+                ready.push_back(guid.clone());
+            } else {
+                waiting.entry(parent_id.clone()).or_insert_with(Vec::new).push(guid.clone());
+            }
+        }
+        let mut remaining: BTreeSet<String> = orphans.into_iter().collect();
+        loop {
+            let (guid, parent) = match ready.pop_front() {
+                Some(guid) => {
+                    if tree.contains(&guid) {
+                        continue;
+                    }
+                    let parent = tree.items[&guid].record.parent_id.clone();
+                    (guid, parent)
+                }
+                // None of the remaining orphans have a home, so we move the
+                // first one to `unfiled`, which can make a home for others.
+                None => match remaining.iter().next() {
+                    Some(guid) => (guid.clone(), UNFILED_GUID.to_string()),
+                    None => break,
+                },
+            };
+            debug!("Moving orphan {} to {}", guid, parent);
+            tree.attach(&parent, &guid);
+            tree.claim_children(&guid);
+            for placed in tree.descendants(&guid) {
+                remaining.remove(&placed);
+                if let Some(children) = waiting.remove(&placed) {
+                    if tree.can_hold(&placed) {
+                        ready.extend(children);
+                    }
+                }
+            }
+        }
+        tree
+    }
+
+    /// Returns the item for `guid`. The roots don't have an item if their
+    /// records are missing.
+    pub fn item(&self, guid: &str) -> Option<&TreeItem> {
+        self.items.get(guid)
+    }
+
+    pub fn items(&self) -> impl Iterator<Item = &TreeItem> {
+        self.items.values()
+    }
+
+    pub fn contains(&self, guid: &str) -> bool {
+        self.parents.contains_key(guid)
+    }
+
+    pub fn parent(&self, guid: &str) -> Option<&str> {
+        self.parents.get(guid).map(|parent| parent.as_str())
+    }
+
+    pub fn children(&self, guid: &str) -> &[String] {
+        self.children.get(guid).map(|children| &children[..]).unwrap_or(&[])
+    }
+
+    /// Returns `guid` and everything below it, parents first.
+    pub fn descendants(&self, guid: &str) -> Vec<String> {
+        let mut result = vec![guid.to_string()];
+        let mut i = 0;
+        while i < result.len() {
+            let children = self.children(&result[i]).to_vec();
+            result.extend(children);
+            i += 1;
+        }
+        result
+    }
+
+    pub fn needs_merge(&self, guid: &str) -> bool {
+        self.items.get(guid).map_or(false, |item| item.needs_merge)
+    }
+
+    pub fn modified(&self, guid: &str) -> u64 {
+        self.items.get(guid).map_or(0, |item| item.modified)
+    }
+
+    fn is_folder(&self, guid: &str) -> bool {
+        CONTENT_ROOTS.contains(&guid) ||
+            self.items.get(guid).map_or(false, |item| item.record.kind.is_folder())
+    }
+
+    // Whether a non-root item can go in `guid`.
+    fn can_hold(&self, guid: &str) -> bool {
+        guid != ROOT_GUID && self.is_folder(guid) && self.contains(guid)
+    }
+
+    fn attach(&mut self, parent: &str, child: &str) {
+        self.children.entry(parent.to_string()).or_insert_with(Vec::new).push(child.to_string());
+        self.parents.insert(child.to_string(), parent.to_string());
+    }
+
+    // Attaches the items that `guid` and its descendants list as children,
+    // unless they're already in the tree.
+    fn claim_children(&mut self, guid: &str) {
+        let mut folders = vec![guid.to_string()];
+        while let Some(folder) = folders.pop() {
+            let claimed = match self.items.get(&folder) {
+                Some(item) if item.record.kind.is_folder() => item.record.children.clone(),
+                _ => continue,
+            };
+            for child in claimed {
+                if self.items.contains_key(&child) && !is_root(&child) && !self.contains(&child) {
+                    self.attach(&folder, &child);
+                    folders.push(child);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use record::BookmarkKind;
+
+    pub fn item(guid: &str, kind: BookmarkKind, parent: &str, children: &[&str]) -> TreeItem {
+        let mut record = BookmarkRecord::new(guid.into(), kind);
+        record.parent_id = parent.into();
+        record.children = children.iter().map(|child| child.to_string()).collect();
+        TreeItem { record, modified: 0, needs_merge: false }
+    }
+
+    pub fn folder(guid: &str, parent: &str, children: &[&str]) -> TreeItem {
+        item(guid, BookmarkKind::Folder, parent, children)
+    }
+
+    pub fn bookmark(guid: &str, parent: &str) -> TreeItem {
+        item(guid, BookmarkKind::Bookmark, parent, &[])
+    }
+
+    #[test]
+    fn test_from_items() {
+        let tree = Tree::from_items(vec![
+            folder("menu", "places", &["folderAAAAAA", "bookmarkAAAA", "missingAAAAA"]),
+            folder("folderAAAAAA", "menu", &["bookmarkBBBB"]),
+            bookmark("bookmarkAAAA", "menu"),
+            // Claims `bookmarkAAAA` too, but `menu` got there first.
+            folder("folderBBBBBB", "toolbar", &["bookmarkAAAA"]),
+            bookmark("bookmarkBBBB", "folderAAAAAA"),
+            // Not in its parent's children.
+            bookmark("bookmarkCCCC", "folderAAAAAA"),
+            // Its parent doesn't exist.
+            bookmark("bookmarkDDDD", "missingBBBBB"),
+            // Bookmarks can't hold other items.
+            bookmark("bookmarkEEEE", "bookmarkAAAA"),
+        ]);
+
+        assert_eq!(tree.children(ROOT_GUID).to_vec(), vec!["menu", "toolbar", "unfiled", "mobile"]);
+        assert_eq!(tree.children("menu").to_vec(), vec!["folderAAAAAA", "bookmarkAAAA"]);
+        assert_eq!(tree.children("folderAAAAAA").to_vec(), vec!["bookmarkBBBB", "bookmarkCCCC"]);
+        // The toolbar has no record, so nothing claims `folderBBBBBB`, but
+        // its `parentid` still works.
+        assert_eq!(tree.parent("folderBBBBBB"), Some("toolbar"));
+        assert!(tree.children("folderBBBBBB").is_empty());
+        assert_eq!(tree.parent("bookmarkDDDD"), Some("unfiled"));
+        assert_eq!(tree.parent("bookmarkEEEE"), Some("unfiled"));
+        assert!(!tree.contains("missingAAAAA"));
+        assert!(tree.item("toolbar").is_none());
+        assert!(tree.contains("toolbar"));
+        assert_eq!(tree.descendants("menu").len(), 5);
+    }
+
+    #[test]
+    fn test_orphans_in_orphans() {
+        let tree = Tree::from_items(vec![
+            // Placed once its folder, another orphan, is in the tree.
+            bookmark("bookmarkAAAA", "folderBBBBBB"),
+            folder("folderBBBBBB", "folderAAAAAA", &[]),
+            // Its parent doesn't exist, so it goes to `unfiled`.
+            folder("folderAAAAAA", "missingAAAAA", &[]),
+        ]);
+
+        assert_eq!(tree.parent("folderAAAAAA"), Some("unfiled"));
+        assert_eq!(tree.parent("folderBBBBBB"), Some("folderAAAAAA"));
+        assert_eq!(tree.parent("bookmarkAAAA"), Some("folderBBBBBB"));
+    }
+}
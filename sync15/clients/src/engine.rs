@@ -0,0 +1,361 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+
+use sync15_adapter as sync;
+use self::sync::{IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp, SERVER_EPOCH};
+
+use record::{ClientRecord, Command};
+
+const COLLECTION_NAME: &str = "clients";
+
+/// The sync protocol version we support, advertised in our client record.
+const PROTOCOL_VERSION: &str = "1.5";
+
+/// Describes this device, for the record we upload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalClient {
+    /// Our record ID, which must stay the same across syncs.
+    pub id: String,
+    pub name: String,
+    /// `desktop`, `mobile`, `tablet` or `tv`.
+    pub typ: String,
+    pub os: Option<String>,
+    pub version: Option<String>,
+    pub fxa_device_id: Option<String>,
+}
+
+/// Implemented by the app to carry out commands sent by other clients.
+///
+/// We process commands as soon as we download our record, and remove them
+/// from the server by uploading our record again later in the same sync. If
+/// that upload fails, we see the same commands again on the next sync, so
+/// processing a command twice must be harmless.
+pub trait CommandProcessor {
+    /// Carries out `command`. If this fails, the command is dropped anyway,
+    /// so that a bad command doesn't get stuck in our record forever.
+    fn process_command(&mut self, command: &Command) -> sync::Result<()>;
+}
+
+pub struct ClientsEngine {
+    local: LocalClient,
+    processor: Box<CommandProcessor>,
+    remote_clients: HashMap<String, ClientRecord>,
+    // Commands we still need to send, by client ID.
+    outgoing_commands: HashMap<String, Vec<Command>>,
+    // Whether the server has our record as we currently describe it.
+    uploaded: bool,
+}
+
+impl ClientsEngine {
+    pub fn new(local: LocalClient, processor: Box<CommandProcessor>) -> ClientsEngine {
+        ClientsEngine {
+            local,
+            processor,
+            remote_clients: HashMap::new(),
+            outgoing_commands: HashMap::new(),
+            uploaded: false,
+        }
+    }
+
+    /// Updates the description of this device, which we'll upload on the next
+    /// sync.
+    pub fn set_local_client(&mut self, local: LocalClient) {
+        if local != self.local {
+            self.local = local;
+            self.uploaded = false;
+        }
+    }
+
+    /// The other clients connected to the account, as of the last sync.
+    pub fn remote_clients(&self) -> impl Iterator<Item = &ClientRecord> {
+        self.remote_clients.values()
+    }
+
+    /// Queues `command` for the client `client_id`, to be sent on the next
+    /// sync. Commands for clients we don't know about are dropped then.
+    pub fn send_command(&mut self, client_id: &str, command: Command) {
+        let commands = self.outgoing_commands
+            .entry(client_id.to_string())
+            .or_insert_with(Vec::new);
+        if !commands.contains(&command) {
+            commands.push(command);
+        }
+    }
+
+    fn local_record(&self) -> ClientRecord {
+        ClientRecord {
+            id: self.local.id.clone(),
+            name: self.local.name.clone(),
+            typ: self.local.typ.clone(),
+            commands: vec![],
+            fxa_device_id: self.local.fxa_device_id.clone(),
+            version: self.local.version.clone(),
+            protocols: vec![PROTOCOL_VERSION.into()],
+            os: self.local.os.clone(),
+            app_package: None,
+            application: None,
+            device: None,
+            extra: Default::default(),
+        }
+    }
+
+    fn process_commands(&mut self, record: &ClientRecord) {
+        let mut processed = Vec::with_capacity(record.commands.len());
+        for command_record in &record.commands {
+            match Command::from_record(command_record) {
+                // Other clients may send the same command more than once.
+                Some(ref command) if processed.contains(command) => {}
+                Some(command) => {
+                    info!("Processing command {:?}", command);
+                    if let Err(e) = self.processor.process_command(&command) {
+                        warn!("Failed to process command {:?}: {}", command, e);
+                    }
+                    processed.push(command);
+                }
+                None => warn!("Ignoring unknown command {:?}", command_record),
+            }
+        }
+    }
+}
+
+impl sync::Store for ClientsEngine {
+    type Error = sync::Error;
+
+    fn apply_incoming(&mut self, inbound: IncomingChangeset) -> sync::Result<()> {
+        for (payload, _) in inbound.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let record: ClientRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring malformed client record: {}", e);
+                    continue;
+                }
+            };
+            if record.id == self.local.id {
+                // Commands are removed from our record by uploading it again.
+                if !record.commands.is_empty() {
+                    self.process_commands(&record);
+                    self.uploaded = false;
+                }
+            } else {
+                self.remote_clients.insert(record.id.clone(), record);
+            }
+        }
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> sync::Result<OutgoingChangeset> {
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), SERVER_EPOCH);
+        if !self.uploaded {
+            outgoing.changes.push(Payload::from_record(self.local_record())?);
+        }
+        let remote_clients = &self.remote_clients;
+        self.outgoing_commands.retain(|client_id, commands| {
+            let known = remote_clients.contains_key(client_id);
+            if !known {
+                warn!("Dropping {} commands for unknown client {}", commands.len(), client_id);
+            }
+            known
+        });
+        for (client_id, commands) in &self.outgoing_commands {
+            let mut record = self.remote_clients[client_id].clone();
+            for command in commands {
+                let command_record = command.to_record();
+                if !record.commands.contains(&command_record) {
+                    record.commands.push(command_record);
+                }
+            }
+            outgoing.changes.push(Payload::from_record(record)?);
+        }
+        Ok(outgoing)
+    }
+
+    fn sync_finished(&mut self, _: ServerTimestamp, records_synced: &[String]) -> sync::Result<()> {
+        for id in records_synced {
+            if *id == self.local.id {
+                self.uploaded = true;
+            } else {
+                // The commands for this client were sent. Those for clients
+                // whose records failed to upload stay queued for the next sync.
+                self.outgoing_commands.remove(id);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl sync::SyncEngine for ClientsEngine {
+    fn collection_name(&self) -> &str {
+        COLLECTION_NAME
+    }
+
+    /// Syncs the clients collection. It's tiny, so we always download all of
+    /// it, which also means we forget about clients that went away.
+    fn sync(
+        &mut self,
+        client: &sync::Sync15StorageClient,
+        state: &sync::GlobalState,
+    ) -> sync::Result<()> {
+        self.remote_clients.clear();
+        sync::synchronize(client, state, self, COLLECTION_NAME.into(), SERVER_EPOCH, false)
+    }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        self.remote_clients.clear();
+        self.uploaded = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use sync15_adapter as sync;
+    use self::sync::Store;
+
+    struct RecordingProcessor(Rc<RefCell<Vec<Command>>>);
+
+    impl CommandProcessor for RecordingProcessor {
+        fn process_command(&mut self, command: &Command) -> sync::Result<()> {
+            self.0.borrow_mut().push(command.clone());
+            Ok(())
+        }
+    }
+
+    fn payload(value: ::serde_json::Value) -> (Payload, ServerTimestamp) {
+        (Payload::from_json(value).unwrap(), ServerTimestamp(10.0))
+    }
+
+    fn local_client() -> LocalClient {
+        LocalClient {
+            id: "AAAAAAAAAAAA".into(),
+            name: "Phone".into(),
+            typ: "mobile".into(),
+            os: Some("Android".into()),
+            version: Some("63.0".into()),
+            fxa_device_id: Some("device".into()),
+        }
+    }
+
+    fn new_engine(processed: &Rc<RefCell<Vec<Command>>>) -> ClientsEngine {
+        ClientsEngine::new(local_client(), Box::new(RecordingProcessor(processed.clone())))
+    }
+
+    fn apply(engine: &mut ClientsEngine, records: Vec<::serde_json::Value>) -> OutgoingChangeset {
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(10.0));
+        inbound.changes.extend(records.into_iter().map(payload));
+        engine.apply_incoming(inbound).unwrap();
+        engine.outgoing_changes().unwrap()
+    }
+
+    #[test]
+    fn test_process_commands() {
+        let processed = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = new_engine(&processed);
+
+        let outgoing = apply(&mut engine, vec![json!({
+            "id": "AAAAAAAAAAAA",
+            "name": "Phone",
+            "type": "mobile",
+            "commands": [
+                { "command": "resetEngine", "args": ["bookmarks"] },
+                { "command": "resetEngine", "args": ["bookmarks"] },
+                { "command": "unknown", "args": [] },
+            ],
+        })]);
+
+        // Each command runs once, and unknown ones are skipped.
+        assert_eq!(*processed.borrow(), vec![Command::ResetEngine("bookmarks".into())]);
+        // We don't list ourselves as a remote client.
+        assert_eq!(engine.remote_clients().count(), 0);
+
+        // Our record goes back up without the commands.
+        assert_eq!(outgoing.timestamp, SERVER_EPOCH);
+        assert_eq!(outgoing.changes.len(), 1);
+        let ours: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(ours, engine.local_record());
+        assert!(ours.commands.is_empty());
+        assert_eq!(ours.protocols, vec!["1.5".to_string()]);
+
+        // Once it's uploaded, we leave our record alone.
+        engine.sync_finished(ServerTimestamp(11.0), &["AAAAAAAAAAAA".to_string()]).unwrap();
+        let outgoing = apply(&mut engine, vec![json!({
+            "id": "AAAAAAAAAAAA",
+            "name": "Phone",
+            "type": "mobile",
+        })]);
+        assert!(outgoing.changes.is_empty());
+    }
+
+    #[test]
+    fn test_send_commands() {
+        let processed = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = new_engine(&processed);
+        engine.send_command("BBBBBBBBBBBB", Command::ResetEngine("history".into()));
+        engine.send_command("BBBBBBBBBBBB", Command::ResetEngine("history".into()));
+        engine.send_command("CCCCCCCCCCCC", Command::ResetAll);
+        engine.uploaded = true;
+
+        let laptop = json!({
+            "id": "BBBBBBBBBBBB",
+            "name": "Laptop",
+            "type": "desktop",
+            "commands": [{ "command": "wipeAll", "args": [] }],
+        });
+        let outgoing = apply(&mut engine, vec![laptop.clone()]);
+        assert_eq!(
+            engine.remote_clients().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["Laptop"]
+        );
+
+        // We add our command to the ones the client already has, once, and
+        // drop the command for the client we don't know about.
+        assert_eq!(outgoing.changes.len(), 1);
+        let theirs: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(theirs.id, "BBBBBBBBBBBB");
+        assert_eq!(
+            theirs.commands,
+            vec![
+                Command::WipeAll.to_record(),
+                Command::ResetEngine("history".into()).to_record(),
+            ]
+        );
+
+        // If the upload fails, we try again on the next sync.
+        engine.sync_finished(ServerTimestamp(11.0), &[]).unwrap();
+        assert_eq!(apply(&mut engine, vec![laptop.clone()]).changes.len(), 1);
+
+        engine.sync_finished(ServerTimestamp(12.0), &["BBBBBBBBBBBB".to_string()]).unwrap();
+        assert!(apply(&mut engine, vec![laptop]).changes.is_empty());
+        assert!(processed.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_send_command_keeps_unknown_fields() {
+        let processed = Rc::new(RefCell::new(Vec::new()));
+        let mut engine = new_engine(&processed);
+        engine.send_command("BBBBBBBBBBBB", Command::WipeAll);
+        engine.uploaded = true;
+
+        let outgoing = apply(&mut engine, vec![json!({
+            "id": "BBBBBBBBBBBB",
+            "name": "Laptop",
+            "type": "desktop",
+            "formfactor": "laptop",
+            "appInfo": { "channel": "nightly" },
+        })]);
+
+        assert_eq!(outgoing.changes.len(), 1);
+        let theirs = ::serde_json::to_value(&outgoing.changes[0]).unwrap();
+        assert_eq!(theirs["formfactor"], json!("laptop"));
+        assert_eq!(theirs["appInfo"], json!({ "channel": "nightly" }));
+        assert_eq!(theirs["commands"], json!([{ "command": "wipeAll", "args": [] }]));
+    }
+}
@@ -0,0 +1,22 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `clients` engine, which uploads a record describing this device,
+//! keeps track of the other devices connected to the account, and sends and
+//! processes commands like `wipeEngine` and `displayURI`.
+
+#![crate_name = "sync15_clients"]
+
+#[macro_use] extern crate log;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+extern crate sync15_adapter;
+
+pub mod engine;
+pub use engine::{ClientsEngine, CommandProcessor, LocalClient};
+pub mod record;
+pub use record::{ClientRecord, Command, CommandRecord};
@@ -0,0 +1,160 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde_json;
+
+/// A record in the `clients` collection, describing one device connected to
+/// the account.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRecord {
+    pub id: String,
+
+    pub name: String,
+
+    /// `desktop`, `mobile`, `tablet` or `tv`.
+    #[serde(rename = "type")]
+    pub typ: String,
+
+    /// Commands sent to this client by other clients.
+    #[serde(default)]
+    pub commands: Vec<CommandRecord>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fxa_device_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_package: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+
+    /// Fields we don't know about, kept so that we don't remove them when we
+    /// upload another client's record to send it commands.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+/// A command as it's stored in a client record.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CommandRecord {
+    pub command: String,
+
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    #[serde(rename = "flowID", default, skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+}
+
+/// The commands we know how to process and send.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// Delete all local data for all engines.
+    WipeAll,
+    /// Delete all local data for an engine.
+    WipeEngine(String),
+    /// Forget sync metadata for all engines, so that the next sync is a first
+    /// sync.
+    ResetAll,
+    /// Forget sync metadata for an engine.
+    ResetEngine(String),
+    /// Open a tab sent by another client.
+    DisplayUri {
+        uri: String,
+        sender_id: String,
+        title: String,
+    },
+}
+
+impl Command {
+    /// Parses a command record, returning `None` for unknown commands or
+    /// commands with the wrong number of arguments.
+    pub fn from_record(record: &CommandRecord) -> Option<Command> {
+        let args = &record.args;
+        match (record.command.as_str(), args.len()) {
+            ("wipeAll", 0) => Some(Command::WipeAll),
+            ("wipeEngine", 1) => Some(Command::WipeEngine(args[0].clone())),
+            ("resetAll", 0) => Some(Command::ResetAll),
+            ("resetEngine", 1) => Some(Command::ResetEngine(args[0].clone())),
+            ("displayURI", 3) => Some(Command::DisplayUri {
+                uri: args[0].clone(),
+                sender_id: args[1].clone(),
+                title: args[2].clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_record(&self) -> CommandRecord {
+        let (command, args) = match self {
+            Command::WipeAll => ("wipeAll", vec![]),
+            Command::WipeEngine(engine) => ("wipeEngine", vec![engine.clone()]),
+            Command::ResetAll => ("resetAll", vec![]),
+            Command::ResetEngine(engine) => ("resetEngine", vec![engine.clone()]),
+            Command::DisplayUri {
+                uri,
+                sender_id,
+                title,
+            } => ("displayURI", vec![uri.clone(), sender_id.clone(), title.clone()]),
+        };
+        CommandRecord {
+            command: command.into(),
+            args,
+            flow_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_client_record() {
+        let record: ClientRecord = serde_json::from_value(json!({
+            "id": "AAAAAAAAAAAA",
+            "name": "Laptop",
+            "type": "desktop",
+            "commands": [
+                { "command": "wipeEngine", "args": ["bookmarks"], "flowID": "flow" },
+                { "command": "displayURI", "args": ["https://example.com", "BBBBBBBBBBBB", "Example"] },
+                { "command": "logout", "args": [] },
+            ],
+            "fxaDeviceId": "device",
+            "os": "Darwin",
+        })).unwrap();
+        assert_eq!(record.typ, "desktop");
+        assert_eq!(record.fxa_device_id, Some("device".to_string()));
+        assert_eq!(record.commands[0].flow_id, Some("flow".to_string()));
+
+        let commands: Vec<Option<Command>> = record.commands.iter().map(Command::from_record).collect();
+        assert_eq!(commands, vec![
+            Some(Command::WipeEngine("bookmarks".into())),
+            Some(Command::DisplayUri {
+                uri: "https://example.com".into(),
+                sender_id: "BBBBBBBBBBBB".into(),
+                title: "Example".into(),
+            }),
+            None,
+        ]);
+        assert_eq!(commands[1].as_ref().unwrap().to_record(), CommandRecord {
+            flow_id: None,
+            ..record.commands[1].clone()
+        });
+    }
+}
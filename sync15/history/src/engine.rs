@@ -0,0 +1,352 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+
+use sync15_adapter as sync;
+use self::sync::{IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp};
+
+use record::{HistoryRecord, HistoryRecordVisit};
+
+const COLLECTION_NAME: &str = "history";
+
+/// The most visits we upload for a page. Like desktop, we only send the most
+/// recent ones.
+const MAX_OUTGOING_VISITS: usize = 20;
+
+/// The local history database, implemented by the app.
+pub trait HistoryStore {
+    /// Finds the page with the sync ID `guid`, or failing that, the page for
+    /// `url`, with all its local visits.
+    fn find_page(&self, guid: &str, url: &str) -> sync::Result<Option<HistoryRecord>>;
+
+    /// Stores a page from the server, replacing the page with the same ID or
+    /// URL. This must not mark the page as changed. If it replaces a page
+    /// with another ID that was changed or deleted locally, that state moves
+    /// to the new ID, so `changed_pages` never returns a page that's gone.
+    fn apply_remote_page(&mut self, page: HistoryRecord) -> sync::Result<()>;
+
+    /// Deletes a page that was deleted on another client.
+    fn delete_page(&mut self, guid: &str) -> sync::Result<()>;
+
+    /// Returns the pages that were visited locally since they were last
+    /// synced, with all their visits.
+    fn changed_pages(&self) -> sync::Result<Vec<HistoryRecord>>;
+
+    /// Returns the IDs of pages that were deleted locally since the last sync.
+    fn deleted_pages(&self) -> sync::Result<Vec<String>>;
+
+    /// Clears the changed and deleted flags of the uploaded pages.
+    fn mark_synced(&mut self, guids: &[String]) -> sync::Result<()>;
+
+    fn last_sync(&self) -> sync::Result<ServerTimestamp>;
+
+    fn set_last_sync(&mut self, last_sync: ServerTimestamp) -> sync::Result<()>;
+
+    /// Marks every page as changed and forgets the last sync time, so that
+    /// the next sync uploads all the local history.
+    fn reset(&mut self) -> sync::Result<()>;
+}
+
+/// Merges two lists of visits, dropping duplicates and visits we can't store.
+/// Two visits are the same if they have the same date and transition type.
+/// The result is sorted most recent first.
+pub fn merge_visits(
+    local: &[HistoryRecordVisit],
+    remote: &[HistoryRecordVisit],
+) -> Vec<HistoryRecordVisit> {
+    let mut seen = HashSet::with_capacity(local.len() + remote.len());
+    let mut merged: Vec<HistoryRecordVisit> = local
+        .iter()
+        .chain(remote.iter())
+        .filter(|visit| visit.is_valid() && seen.insert((visit.date, visit.transition)))
+        .cloned()
+        .collect();
+    merged.sort_by(|a, b| b.date.cmp(&a.date));
+    merged
+}
+
+pub struct HistoryEngine<S> {
+    store: S,
+}
+
+impl<S: HistoryStore> HistoryEngine<S> {
+    pub fn new(store: S) -> HistoryEngine<S> {
+        HistoryEngine { store }
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    fn apply_remote_record(&mut self, mut record: HistoryRecord) -> sync::Result<()> {
+        let local_visits = match self.store.find_page(&record.id, &record.hist_uri)? {
+            Some(local) => local.visits,
+            None => Vec::new(),
+        };
+        record.visits = merge_visits(&local_visits, &record.visits);
+        if record.visits.is_empty() {
+            // A page without visits isn't history.
+            debug!("Ignoring history record {} without visits", record.id);
+            return Ok(());
+        }
+        self.store.apply_remote_page(record)
+    }
+}
+
+impl<S: HistoryStore> sync::Store for HistoryEngine<S> {
+    type Error = sync::Error;
+
+    fn apply_incoming(&mut self, inbound: IncomingChangeset) -> sync::Result<()> {
+        info!("Applying {} incoming history records", inbound.changes.len());
+        for (payload, _) in inbound.changes {
+            if payload.is_tombstone() {
+                self.store.delete_page(payload.id())?;
+                continue;
+            }
+            match payload.into_record::<HistoryRecord>() {
+                Ok(record) => self.apply_remote_record(record)?,
+                Err(e) => warn!("Ignoring malformed history record: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> sync::Result<OutgoingChangeset> {
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), self.store.last_sync()?);
+        for mut page in self.store.changed_pages()? {
+            page.visits = merge_visits(&page.visits, &[]);
+            page.visits.truncate(MAX_OUTGOING_VISITS);
+            outgoing.changes.push(Payload::from_record(page)?);
+        }
+        for guid in self.store.deleted_pages()? {
+            outgoing.changes.push(Payload::new_tombstone(guid));
+        }
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &mut self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> sync::Result<()> {
+        self.store.mark_synced(records_synced)?;
+        self.store.set_last_sync(new_timestamp)
+    }
+}
+
+impl<S: HistoryStore> sync::SyncEngine for HistoryEngine<S> {
+    fn collection_name(&self) -> &str {
+        COLLECTION_NAME
+    }
+
+    /// Syncs the history collection. Uploads go through the adapter's
+    /// `PostQueue`, which splits them according to the server's limits, like
+    /// `max_post_records`, and drops records that are too large.
+    fn sync(
+        &mut self,
+        client: &sync::Sync15StorageClient,
+        state: &sync::GlobalState,
+    ) -> sync::Result<()> {
+        let last_sync = self.store.last_sync()?;
+        sync::synchronize(client, state, self, COLLECTION_NAME.into(), last_sync, false)
+    }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        self.store.reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use sync15_adapter as sync;
+    use self::sync::{Store, SERVER_EPOCH};
+
+    #[derive(Default)]
+    struct MemoryStore {
+        pages: HashMap<String, HistoryRecord>,
+        changed: HashSet<String>,
+        deleted: HashSet<String>,
+        last_sync: ServerTimestamp,
+    }
+
+    impl HistoryStore for MemoryStore {
+        fn find_page(&self, guid: &str, url: &str) -> sync::Result<Option<HistoryRecord>> {
+            Ok(self.pages.get(guid).cloned().or_else(|| {
+                self.pages.values().find(|page| page.hist_uri == url).cloned()
+            }))
+        }
+
+        fn apply_remote_page(&mut self, page: HistoryRecord) -> sync::Result<()> {
+            let replaced: Vec<String> = self.pages
+                .values()
+                .filter(|existing| existing.hist_uri == page.hist_uri && existing.id != page.id)
+                .map(|existing| existing.id.clone())
+                .collect();
+            for guid in replaced {
+                self.pages.remove(&guid);
+                if self.changed.remove(&guid) {
+                    self.changed.insert(page.id.clone());
+                }
+            }
+            self.pages.insert(page.id.clone(), page);
+            Ok(())
+        }
+
+        fn delete_page(&mut self, guid: &str) -> sync::Result<()> {
+            self.pages.remove(guid);
+            Ok(())
+        }
+
+        fn changed_pages(&self) -> sync::Result<Vec<HistoryRecord>> {
+            Ok(self.changed.iter().map(|guid| self.pages[guid].clone()).collect())
+        }
+
+        fn deleted_pages(&self) -> sync::Result<Vec<String>> {
+            Ok(self.deleted.iter().cloned().collect())
+        }
+
+        fn mark_synced(&mut self, guids: &[String]) -> sync::Result<()> {
+            for guid in guids {
+                self.changed.remove(guid);
+                self.deleted.remove(guid);
+            }
+            Ok(())
+        }
+
+        fn last_sync(&self) -> sync::Result<ServerTimestamp> {
+            Ok(self.last_sync)
+        }
+
+        fn set_last_sync(&mut self, last_sync: ServerTimestamp) -> sync::Result<()> {
+            self.last_sync = last_sync;
+            Ok(())
+        }
+
+        fn reset(&mut self) -> sync::Result<()> {
+            self.changed = self.pages.keys().cloned().collect();
+            self.last_sync = SERVER_EPOCH;
+            Ok(())
+        }
+    }
+
+    fn visit(date: u64, transition: i64) -> HistoryRecordVisit {
+        HistoryRecordVisit { date, transition }
+    }
+
+    fn page(id: &str, url: &str, visits: Vec<HistoryRecordVisit>) -> HistoryRecord {
+        HistoryRecord {
+            id: id.into(),
+            hist_uri: url.into(),
+            title: "Example".into(),
+            visits,
+        }
+    }
+
+    #[test]
+    fn test_merge_visits() {
+        let local = vec![visit(3, 1), visit(1, 1)];
+        let remote = vec![visit(2, 1), visit(1, 1), visit(1, 2), visit(4, 0)];
+        assert_eq!(
+            merge_visits(&local, &remote),
+            vec![visit(3, 1), visit(2, 1), visit(1, 1), visit(1, 2)]
+        );
+    }
+
+    #[test]
+    fn test_apply_remote_page() {
+        let mut store = MemoryStore::default();
+        // Same URL as the incoming record, but a different ID.
+        let local = page("AAAAAAAAAAAA", "https://example.com/", vec![visit(1, 1)]);
+        store.pages.insert(local.id.clone(), local);
+        let mut engine = HistoryEngine::new(store);
+
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(10.0));
+        let remote = page("CCCCCCCCCCCC", "https://example.com/", vec![visit(1, 1), visit(5, 2)]);
+        inbound.changes.push((Payload::from_record(remote).unwrap(), ServerTimestamp(10.0)));
+        // Pages without any visits we can store are skipped.
+        let invalid = page("DDDDDDDDDDDD", "https://example.net/", vec![visit(6, 0)]);
+        inbound.changes.push((Payload::from_record(invalid).unwrap(), ServerTimestamp(10.0)));
+        engine.apply_incoming(inbound).unwrap();
+
+        // The remote page replaces the local one, with the visits of both.
+        assert!(!engine.store().pages.contains_key("AAAAAAAAAAAA"));
+        assert_eq!(
+            engine.store().pages["CCCCCCCCCCCC"].visits,
+            vec![visit(5, 2), visit(1, 1)]
+        );
+        assert!(!engine.store().pages.contains_key("DDDDDDDDDDDD"));
+
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(11.0));
+        inbound.changes.push((Payload::new_tombstone("CCCCCCCCCCCC".into()), ServerTimestamp(11.0)));
+        engine.apply_incoming(inbound).unwrap();
+        assert!(engine.store().pages.is_empty());
+    }
+
+    #[test]
+    fn test_upload_changed_pages() {
+        let mut store = MemoryStore::default();
+        let changed = page("BBBBBBBBBBBB", "https://example.org/", vec![visit(2, 1)]);
+        store.pages.insert(changed.id.clone(), changed);
+        store.changed.insert("BBBBBBBBBBBB".into());
+        store.deleted.insert("DDDDDDDDDDDD".into());
+        let mut engine = HistoryEngine::new(store);
+
+        let outgoing = engine.outgoing_changes().unwrap();
+        assert_eq!(outgoing.timestamp, SERVER_EPOCH);
+        assert_eq!(outgoing.changes.len(), 2);
+        let uploaded: HistoryRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.id, "BBBBBBBBBBBB");
+        assert!(outgoing.changes[1].is_tombstone());
+        assert_eq!(outgoing.changes[1].id(), "DDDDDDDDDDDD");
+
+        let synced = vec!["BBBBBBBBBBBB".to_string(), "DDDDDDDDDDDD".to_string()];
+        engine.sync_finished(ServerTimestamp(11.0), &synced).unwrap();
+        assert!(engine.store().changed.is_empty());
+        assert!(engine.store().deleted.is_empty());
+        assert_eq!(engine.store().last_sync, ServerTimestamp(11.0));
+    }
+
+    #[test]
+    fn test_changed_page_replaced() {
+        let mut store = MemoryStore::default();
+        let local = page("AAAAAAAAAAAA", "https://example.com/", vec![visit(2, 1)]);
+        store.pages.insert(local.id.clone(), local);
+        store.changed.insert("AAAAAAAAAAAA".into());
+        let mut engine = HistoryEngine::new(store);
+
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(10.0));
+        let remote = page("CCCCCCCCCCCC", "https://example.com/", vec![visit(1, 1)]);
+        inbound.changes.push((Payload::from_record(remote).unwrap(), ServerTimestamp(10.0)));
+        engine.apply_incoming(inbound).unwrap();
+
+        // Our local visit still goes up, under the server's ID.
+        let outgoing = engine.outgoing_changes().unwrap();
+        assert_eq!(outgoing.changes.len(), 1);
+        let uploaded: HistoryRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.id, "CCCCCCCCCCCC");
+        assert_eq!(uploaded.visits, vec![visit(2, 1), visit(1, 1)]);
+    }
+
+    #[test]
+    fn test_outgoing_visits_limit() {
+        let mut store = MemoryStore::default();
+        let visits = (0..30).map(|date| visit(date, 1)).collect();
+        let local = page("AAAAAAAAAAAA", "https://example.com/", visits);
+        store.pages.insert(local.id.clone(), local);
+        store.changed.insert("AAAAAAAAAAAA".into());
+        let mut engine = HistoryEngine::new(store);
+
+        let outgoing = engine.outgoing_changes().unwrap();
+        let uploaded: HistoryRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(uploaded.visits.len(), MAX_OUTGOING_VISITS);
+        assert_eq!(uploaded.visits[0], visit(29, 1));
+    }
+}
@@ -0,0 +1,21 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `history` engine, which syncs pages and their visits between the
+//! server and a `HistoryStore` implemented by the app.
+
+#![crate_name = "sync15_history"]
+
+#[macro_use] extern crate log;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+extern crate sync15_adapter;
+
+pub mod engine;
+pub use engine::{merge_visits, HistoryEngine, HistoryStore};
+pub mod record;
+pub use record::{HistoryRecord, HistoryRecordVisit};
@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// A record in the `history` collection: a page and its most recent visits.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryRecord {
+    pub id: String,
+    pub hist_uri: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default)]
+    pub visits: Vec<HistoryRecordVisit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HistoryRecordVisit {
+    /// When the visit happened, in microseconds since the epoch.
+    pub date: u64,
+    /// How we got to the page, as one of Places' transition types. Other
+    /// clients can send anything here, so we keep it as is, and filter out
+    /// the types we can't store with `is_valid`.
+    #[serde(rename = "type")]
+    pub transition: i64,
+}
+
+impl HistoryRecordVisit {
+    /// Places only knows about transition types 1 to 9. Other clients
+    /// sometimes upload visits with types we can't store.
+    pub fn is_valid(&self) -> bool {
+        self.transition >= 1 && self.transition <= 9
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_history_record() {
+        let record: HistoryRecord = serde_json::from_value(json!({
+            "id": "AAAAAAAAAAAA",
+            "histUri": "https://example.com/",
+            "title": "Example",
+            "visits": [
+                { "date": 1536000000000000u64, "type": 1 },
+                { "date": 1535000000000000u64, "type": 0 },
+                { "date": 1534000000000000u64, "type": -1 },
+                { "date": 1533000000000000u64, "type": 1000 },
+            ],
+        })).unwrap();
+        assert_eq!(record.hist_uri, "https://example.com/");
+        assert!(record.visits[0].is_valid());
+        assert!(!record.visits[1].is_valid());
+        assert!(!record.visits[2].is_valid());
+        assert!(!record.visits[3].is_valid());
+
+        let record: HistoryRecord = serde_json::from_value(json!({
+            "id": "BBBBBBBBBBBB",
+            "histUri": "https://example.org/",
+        })).unwrap();
+        assert_eq!(record.title, "");
+        assert!(record.visits.is_empty());
+    }
+}
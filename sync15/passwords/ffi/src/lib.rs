@@ -187,6 +187,8 @@ pub unsafe extern "C" fn sync15_passwords_sync(
             sync_info.last_client_init = requested_init;
         }
 
+        sync_info.client.check_backoff()?;
+
         { // Scope borrow of `sync_info.client`
             let mut state_machine =
                 sync::SetupStateMachine::for_readonly_sync(&sync_info.client, &root_sync_key);
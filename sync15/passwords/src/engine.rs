@@ -157,7 +157,7 @@ impl sync::Store for PasswordEngine {
     fn apply_incoming(
         &mut self,
         inbound: sync::IncomingChangeset
-    ) -> Result<OutgoingChangeset> {
+    ) -> Result<()> {
         debug!("Remote collection has {} changes timestamped at {}",
                inbound.changes.len(), inbound.timestamp);
 
@@ -189,12 +189,17 @@ impl sync::Store for PasswordEngine {
             Some(current_tx_id)
         };
 
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> Result<OutgoingChangeset> {
         let (outbound_changes, last_server_timestamp) = self.get_unsynced_changes()?;
 
         let outbound = OutgoingChangeset {
             changes: outbound_changes,
             timestamp: last_server_timestamp,
-            collection: "passwords".into()
+            collection: "passwords".into(),
+            ttl: None,
         };
 
         debug!("After applying incoming changes, local collection has {} outgoing changes timestamped at {}",
@@ -232,3 +237,17 @@ impl sync::Store for PasswordEngine {
         Ok(())
     }
 }
+
+impl sync::SyncEngine for PasswordEngine {
+    fn collection_name(&self) -> &str {
+        "passwords"
+    }
+
+    fn sync(&mut self, client: &sync::Sync15StorageClient, state: &sync::GlobalState) -> sync::Result<()> {
+        PasswordEngine::sync(self, client, state).map_err(|e| sync::ErrorKind::StoreError(e.into()).into())
+    }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        PasswordEngine::reset(self).map_err(|e| sync::ErrorKind::StoreError(e.into()).into())
+    }
+}
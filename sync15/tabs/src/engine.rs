@@ -0,0 +1,190 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+
+use sync15_adapter as sync;
+use self::sync::{IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp, SERVER_EPOCH};
+
+use record::{TabsRecord, TabsRecordTab};
+
+const COLLECTION_NAME: &str = "tabs";
+
+/// How long the server keeps our tabs record, in seconds. Clients that stop
+/// syncing eventually stop showing up in everyone's list of remote tabs.
+const TABS_TTL: u32 = 21 * 24 * 60 * 60;
+
+/// The open tabs of another client, as of the last sync.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteTabs {
+    pub client_id: String,
+    pub client_name: String,
+    pub tabs: Vec<TabsRecordTab>,
+    /// When the client last uploaded its tabs.
+    pub last_modified: ServerTimestamp,
+}
+
+pub struct TabsEngine {
+    // The ID of our record in the `clients` collection.
+    local_id: String,
+    local_name: String,
+    local_tabs: Vec<TabsRecordTab>,
+    remote_tabs: HashMap<String, RemoteTabs>,
+}
+
+impl TabsEngine {
+    /// `local_id` must be the ID of this device's record in the `clients`
+    /// collection, so that other clients can match our tabs to it.
+    pub fn new(local_id: String, local_name: String) -> TabsEngine {
+        TabsEngine {
+            local_id,
+            local_name,
+            local_tabs: Vec::new(),
+            remote_tabs: HashMap::new(),
+        }
+    }
+
+    /// Replaces the local open tabs, which we'll upload on the next sync.
+    pub fn set_local_tabs(&mut self, tabs: Vec<TabsRecordTab>) {
+        self.local_tabs = tabs;
+    }
+
+    /// The tabs of every other client, as of the last sync.
+    pub fn remote_tabs(&self) -> impl Iterator<Item = &RemoteTabs> {
+        self.remote_tabs.values()
+    }
+
+    pub fn remote_tabs_for_client(&self, client_id: &str) -> Option<&RemoteTabs> {
+        self.remote_tabs.get(client_id)
+    }
+
+    fn local_record(&self) -> TabsRecord {
+        TabsRecord {
+            id: self.local_id.clone(),
+            client_name: self.local_name.clone(),
+            tabs: self.local_tabs.clone(),
+        }
+    }
+}
+
+impl sync::Store for TabsEngine {
+    type Error = sync::Error;
+
+    fn apply_incoming(&mut self, inbound: IncomingChangeset) -> sync::Result<()> {
+        for (payload, last_modified) in inbound.changes {
+            if payload.id() == self.local_id || payload.is_tombstone() {
+                continue;
+            }
+            let record: TabsRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring malformed tabs record: {}", e);
+                    continue;
+                }
+            };
+            self.remote_tabs.insert(record.id.clone(), RemoteTabs {
+                client_id: record.id,
+                client_name: record.client_name,
+                tabs: record.tabs,
+                last_modified,
+            });
+        }
+        Ok(())
+    }
+
+    fn outgoing_changes(&mut self) -> sync::Result<OutgoingChangeset> {
+        // We upload our tabs every sync, even if they didn't change, so that
+        // our record doesn't expire while we're still around.
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), SERVER_EPOCH);
+        outgoing.ttl = Some(TABS_TTL);
+        outgoing.changes.push(Payload::from_record(self.local_record())?);
+        Ok(outgoing)
+    }
+
+    fn sync_finished(&mut self, _: ServerTimestamp, _: &[String]) -> sync::Result<()> {
+        Ok(())
+    }
+}
+
+impl sync::SyncEngine for TabsEngine {
+    fn collection_name(&self) -> &str {
+        COLLECTION_NAME
+    }
+
+    /// Syncs the tabs collection. Like the clients collection, it has one
+    /// record per client, so we always download all of it, and forget about
+    /// clients whose records expired.
+    fn sync(
+        &mut self,
+        client: &sync::Sync15StorageClient,
+        state: &sync::GlobalState,
+    ) -> sync::Result<()> {
+        self.remote_tabs.clear();
+        sync::synchronize(client, state, self, COLLECTION_NAME.into(), SERVER_EPOCH, false)
+    }
+
+    fn reset(&mut self) -> sync::Result<()> {
+        self.remote_tabs.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sync15_adapter as sync;
+    use self::sync::Store;
+
+    fn tab(title: &str, url: &str) -> TabsRecordTab {
+        TabsRecordTab {
+            title: title.into(),
+            url_history: vec![url.into()],
+            icon: None,
+            last_used: 1536000000,
+        }
+    }
+
+    fn remote_record(id: &str, name: &str) -> (Payload, ServerTimestamp) {
+        let record = TabsRecord {
+            id: id.to_string(),
+            client_name: name.to_string(),
+            tabs: vec![tab("Mozilla", "https://mozilla.org")],
+        };
+        (Payload::from_record(record).unwrap(), ServerTimestamp(10.0))
+    }
+
+    #[test]
+    fn test_remote_tabs() {
+        let mut engine = TabsEngine::new("AAAAAAAAAAAA".into(), "Phone".into());
+
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(10.0));
+        inbound.changes.push(remote_record("AAAAAAAAAAAA", "Phone"));
+        inbound.changes.push(remote_record("BBBBBBBBBBBB", "Laptop"));
+        inbound.changes.push((Payload::new_tombstone("CCCCCCCCCCCC".into()), ServerTimestamp(10.0)));
+        engine.apply_incoming(inbound).unwrap();
+
+        // We skip our own record, and tombstones.
+        assert_eq!(engine.remote_tabs().count(), 1);
+        let laptop = engine.remote_tabs_for_client("BBBBBBBBBBBB").unwrap();
+        assert_eq!(laptop.client_name, "Laptop");
+        assert_eq!(laptop.tabs[0].url_history, vec!["https://mozilla.org".to_string()]);
+        assert_eq!(laptop.last_modified, ServerTimestamp(10.0));
+    }
+
+    #[test]
+    fn test_upload_every_sync() {
+        let mut engine = TabsEngine::new("AAAAAAAAAAAA".into(), "Phone".into());
+        engine.set_local_tabs(vec![tab("Example", "https://example.com")]);
+
+        for &synced_at in &[11.0, 12.0] {
+            let outgoing = engine.outgoing_changes().unwrap();
+            assert_eq!(outgoing.timestamp, SERVER_EPOCH);
+            assert_eq!(outgoing.ttl, Some(TABS_TTL));
+            assert_eq!(outgoing.changes.len(), 1);
+            let ours: TabsRecord = outgoing.changes[0].clone().into_record().unwrap();
+            assert_eq!(ours, engine.local_record());
+            engine.sync_finished(ServerTimestamp(synced_at), &["AAAAAAAAAAAA".to_string()]).unwrap();
+        }
+    }
+}
@@ -0,0 +1,21 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `tabs` engine, which uploads this device's open tabs and keeps the
+//! tabs of the other devices around, for "tabs from other devices" UI.
+
+#![crate_name = "sync15_tabs"]
+
+#[macro_use] extern crate log;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+#[cfg_attr(test, macro_use)]
+extern crate serde_json;
+
+extern crate sync15_adapter;
+
+pub mod engine;
+pub use engine::{RemoteTabs, TabsEngine};
+pub mod record;
+pub use record::{TabsRecord, TabsRecordTab};
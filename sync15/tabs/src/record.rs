@@ -0,0 +1,58 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+/// A record in the `tabs` collection, holding the open tabs of one client.
+/// Its ID is the ID of the client's record in the `clients` collection.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecord {
+    pub id: String,
+    pub client_name: String,
+    pub tabs: Vec<TabsRecordTab>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TabsRecordTab {
+    pub title: String,
+    /// The URLs in the tab's back history, most recent first. The first one
+    /// is the page the tab is showing.
+    pub url_history: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+    /// When the tab was last used, in seconds since the epoch.
+    #[serde(default)]
+    pub last_used: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    #[test]
+    fn test_tabs_record() {
+        let record: TabsRecord = serde_json::from_value(json!({
+            "id": "AAAAAAAAAAAA",
+            "clientName": "Laptop",
+            "tabs": [{
+                "title": "Example",
+                "urlHistory": ["https://example.com/b", "https://example.com/a"],
+                "icon": "https://example.com/favicon.ico",
+                "lastUsed": 1536000000,
+            }, {
+                "title": "Blank",
+                "urlHistory": ["about:blank"],
+            }],
+        })).unwrap();
+        assert_eq!(record.client_name, "Laptop");
+        assert_eq!(record.tabs[0].last_used, 1536000000);
+        assert_eq!(record.tabs[1].icon, None);
+        assert_eq!(serde_json::to_value(&record.tabs[1]).unwrap(), json!({
+            "title": "Blank",
+            "urlHistory": ["about:blank"],
+            "lastUsed": 0,
+        }));
+    }
+}